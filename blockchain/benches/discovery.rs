@@ -0,0 +1,86 @@
+//! Canonical chain discovery over a synthetic blocks dir
+//!
+//! Generates `SYNTHETIC_BLOCKS` (default 500k) block files in a temp dir,
+//! then runs discovery on one thread and on all available threads. Every
+//! header is held in memory, so expect a few hundred MB for 500k blocks
+//!
+//! ```sh
+//! cargo bench --bench discovery
+//! SYNTHETIC_BLOCKS=10000 cargo bench --bench discovery
+//! ```
+
+use blockchain::canonical_chain_discovery::{block_paths, discover_canonical_chain};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rayon::ThreadPoolBuilder;
+use std::{fs::File, io::Write, path::Path, time::Instant};
+
+const DEFAULT_NUM_BLOCKS: u32 = 500_000;
+
+/// every `FORK_FREQ` lengths there is a competing block
+const FORK_FREQ: u32 = 50;
+
+fn state_hash(length: u32, fork: bool) -> String {
+    format!("3N{}{length:049}", if fork { 'f' } else { 'K' })
+}
+
+/// A chain of `num_blocks` lengths starting at 2, with a short-lived fork
+/// every `FORK_FREQ` lengths
+fn generate_blocks_dir(dir: &Path, num_blocks: u32) {
+    let padding = "0".repeat(1024);
+    let write_block = |length: u32, fork: bool| {
+        let hash = state_hash(length, fork);
+        let parent_hash = state_hash(length - 1, false);
+        let mut file = File::create(dir.join(format!("mainnet-{length}-{hash}.json"))).unwrap();
+        write!(
+            file,
+            r#"{{"scheduled_time":"0","protocol_state":{{"previous_state_hash":"{parent_hash}","body":{{"consensus_state":{{"blockchain_length":"{length}","global_slot_since_genesis":"{length}","block_creator":"B62q","last_vrf_output":"{hash}"}}}}}},"staged_ledger_diff":"{padding}"}}"#
+        )
+        .unwrap();
+    };
+
+    for length in 2..num_blocks + 2 {
+        write_block(length, false);
+        if length % FORK_FREQ == 0 {
+            write_block(length, true);
+        }
+    }
+}
+
+fn discovery(c: &mut Criterion) {
+    let num_blocks = std::env::var("SYNTHETIC_BLOCKS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_NUM_BLOCKS);
+
+    let dir = tempfile::tempdir().unwrap();
+    let time = Instant::now();
+    generate_blocks_dir(dir.path(), num_blocks);
+    println!(
+        "Generated {num_blocks} lengths in {} in {:?}",
+        dir.path().display(),
+        time.elapsed()
+    );
+
+    let paths = block_paths(dir.path());
+    let mut group = c.benchmark_group("discovery");
+    group.sample_size(10);
+
+    let mut thread_counts = vec![1, rayon::current_num_threads()];
+    thread_counts.dedup();
+
+    for num_threads in thread_counts {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("threads", num_threads),
+            &paths,
+            |b, paths| b.iter(|| pool.install(|| discover_canonical_chain(paths).unwrap())),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, discovery);
+criterion_main!(benches);
@@ -0,0 +1,523 @@
+//! Packed block archives
+//!
+//! A segment packs the blocks of `segment_size` consecutive lengths into one
+//! file, `blocks-{first}-{last}.segment` in the blocks dir:
+//!
+//! ```text
+//! MINASEG\x01 | header length (u64 LE) | header JSON | zstd frames
+//! ```
+//!
+//! Each block is its own zstd frame, so any block can be read on its own. The
+//! header maps each state hash to the block's file name, the offset and size
+//! of its frame and its parsed header, so discovery never decompresses a frame.
+
+use crate::{
+    canonical_chain_discovery::{block_paths, BlockFile},
+    precomputed_block::{BlockParseError, PrecomputedBlock},
+};
+use fs::layout::BlockName;
+use glob::glob;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{remove_file, rename, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// Number of lengths per segment, unless given
+pub const DEFAULT_SEGMENT_SIZE: u32 = 1000;
+
+pub const SEGMENT_EXTENSION: &str = "segment";
+
+const MAGIC: &[u8; 8] = b"MINASEG\x01";
+
+/// An open segment file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    path: PathBuf,
+    /// file offset of the first frame
+    data_start: u64,
+    pub header: SegmentHeader,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentHeader {
+    pub first_length: u32,
+    pub last_length: u32,
+    /// packed blocks, keyed by state hash
+    pub entries: BTreeMap<String, SegmentEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentEntry {
+    /// name of the block file which was packed
+    pub name: String,
+    /// offset of the block's frame, from the end of the header
+    pub offset: u64,
+    /// compressed size of the block's frame
+    pub size: u64,
+    pub block: PrecomputedBlock,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackConfig {
+    pub segment_size: u32,
+    /// zstd compression level
+    pub level: i32,
+    /// also pack the segment of the highest loose block, which may still grow
+    pub all: bool,
+    /// keep the block files which were packed
+    pub keep_files: bool,
+}
+
+/// What a pack or unpack did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveReport {
+    pub segments: usize,
+    pub blocks: usize,
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Parse(PathBuf, BlockParseError),
+    /// the file doesn't start with the segment magic
+    NotASegment(PathBuf),
+}
+
+/// A block to write into a segment
+enum Source<'a> {
+    File(PathBuf, PrecomputedBlock),
+    Packed(&'a Segment, &'a SegmentEntry),
+}
+
+impl Segment {
+    /// Read the header of the segment at `path`
+    pub fn open(path: &Path) -> Result<Self, ArchiveError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        let mut header_len = [0; 8];
+        if reader.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Err(ArchiveError::NotASegment(path.into()));
+        }
+        reader.read_exact(&mut header_len)?;
+        let header_len = u64::from_le_bytes(header_len);
+        let header = serde_json::from_reader(reader.by_ref().take(header_len))?;
+        Ok(Self {
+            path: path.into(),
+            data_start: (MAGIC.len() + 8) as u64 + header_len,
+            header,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Segment file name of the lengths `first..=last`
+    pub fn file_name(first: u32, last: u32) -> String {
+        format!("blocks-{first}-{last}.{SEGMENT_EXTENSION}")
+    }
+
+    pub fn get(&self, state_hash: &str) -> Option<&SegmentEntry> {
+        self.header.entries.get(state_hash)
+    }
+
+    /// Entries ordered by length, then state hash
+    pub fn entries(&self) -> Vec<&SegmentEntry> {
+        let mut entries: Vec<&SegmentEntry> = self.header.entries.values().collect();
+        entries.sort_by_key(|e| e.block.blockchain_length);
+        entries
+    }
+
+    /// Decompress the block of `entry` into `writer`
+    pub fn copy_block(&self, entry: &SegmentEntry, writer: impl Write) -> Result<(), ArchiveError> {
+        zstd::stream::copy_decode(self.frame(entry)?, writer)?;
+        Ok(())
+    }
+
+    /// Path of a packed block, as given to discovery
+    pub fn block_path(&self, entry: &SegmentEntry) -> PathBuf {
+        self.path.join(&entry.name)
+    }
+
+    /// The compressed frame of `entry`
+    fn frame(&self, entry: &SegmentEntry) -> io::Result<impl Read> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.data_start + entry.offset))?;
+        Ok(BufReader::new(file).take(entry.size))
+    }
+
+    /// Write the segment of lengths `first..=last` atomically
+    ///
+    /// Frames are compressed into a temporary data file first, as the header
+    /// (which precedes them) isn't known until all are written.
+    fn write(
+        path: &Path,
+        first_length: u32,
+        last_length: u32,
+        sources: Vec<Source>,
+        level: i32,
+    ) -> Result<(), ArchiveError> {
+        let data_path = path.with_extension("data.tmp");
+        let tmp_path = path.with_extension("tmp");
+
+        let mut entries = BTreeMap::new();
+        let mut data = BufWriter::new(File::create(&data_path)?);
+        let mut offset = 0;
+        for source in sources {
+            let (name, block, size) = match source {
+                Source::File(path, block) => {
+                    let mut counter = CountingWriter::new(&mut data);
+                    zstd::stream::copy_encode(
+                        BufReader::new(File::open(&path)?),
+                        &mut counter,
+                        level,
+                    )?;
+                    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                    (name, block, counter.count)
+                }
+                Source::Packed(segment, entry) => {
+                    io::copy(&mut segment.frame(entry)?, &mut data)?;
+                    (entry.name.clone(), entry.block.clone(), entry.size)
+                }
+            };
+            entries.insert(
+                block.state_hash.clone(),
+                SegmentEntry {
+                    name,
+                    offset,
+                    size,
+                    block,
+                },
+            );
+            offset += size;
+        }
+        data.flush()?;
+        drop(data);
+
+        let header = serde_json::to_vec(&SegmentHeader {
+            first_length,
+            last_length,
+            entries,
+        })?;
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(header.len() as u64).to_le_bytes())?;
+        writer.write_all(&header)?;
+        io::copy(&mut File::open(&data_path)?, &mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        remove_file(data_path)?;
+        rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Segments in `blocks_dir`, ordered by length
+pub fn segments(blocks_dir: &Path) -> Result<Vec<Segment>, ArchiveError> {
+    let mut segments = glob(&format!("{}/*.{SEGMENT_EXTENSION}", blocks_dir.display()))
+        .expect("Failed to read glob pattern")
+        .filter_map(|x| x.ok())
+        .map(|path| Segment::open(&path))
+        .collect::<Result<Vec<_>, _>>()?;
+    segments.sort_by_key(|s| s.header.first_length);
+    Ok(segments)
+}
+
+/// The segment containing the block with `state_hash`, if it's packed
+pub fn find(blocks_dir: &Path, state_hash: &str) -> Result<Option<Segment>, ArchiveError> {
+    Ok(segments(blocks_dir)?
+        .into_iter()
+        .find(|s| s.get(state_hash).is_some()))
+}
+
+/// Headers of all packed blocks in `blocks_dir`, read from the segment headers
+pub fn block_files(blocks_dir: &Path) -> Result<Vec<BlockFile>, ArchiveError> {
+    Ok(segments(blocks_dir)?
+        .iter()
+        .flat_map(|segment| {
+            segment.header.entries.values().map(|entry| BlockFile {
+                path: segment.block_path(entry),
+                block: entry.block.clone(),
+            })
+        })
+        .collect())
+}
+
+/// Pack the block files of `blocks_dir` into segments
+///
+/// Blocks are added to existing segments of their lengths, replacing packed
+/// blocks of the same state hash. The segment of the highest block is left
+/// loose, unless `config.all`, so downloads can keep finding the tip.
+pub fn pack(blocks_dir: &Path, config: &PackConfig) -> Result<ArchiveReport, ArchiveError> {
+    let time = Instant::now();
+    let segment_size = config.segment_size.max(1);
+    let mut groups: BTreeMap<u32, Vec<PathBuf>> = BTreeMap::new();
+    for path in block_paths(blocks_dir) {
+        match BlockName::from_path(&path) {
+            Some(name) => groups
+                .entry(name.length / segment_size)
+                .or_default()
+                .push(path),
+            None => warn!("Not packing {}: invalid block file name", path.display()),
+        }
+    }
+    if !config.all {
+        groups.pop_last();
+    }
+
+    let existing: HashMap<PathBuf, Segment> = segments(blocks_dir)?
+        .into_iter()
+        .map(|s| (s.path.clone(), s))
+        .collect();
+    let mut report = ArchiveReport::default();
+    for (segment_index, files) in groups {
+        let first = segment_index * segment_size;
+        let last = first + (segment_size - 1);
+        let path = blocks_dir.join(Segment::file_name(first, last));
+
+        let mut sources = vec![];
+        for file in &files {
+            let block = PrecomputedBlock::from_path(file)
+                .map_err(|err| ArchiveError::Parse(file.clone(), err))?;
+            sources.push(Source::File(file.clone(), block));
+        }
+        if let Some(segment) = existing.get(&path) {
+            let loose: Vec<String> = sources
+                .iter()
+                .filter_map(|s| match s {
+                    Source::File(_, block) => Some(block.state_hash.clone()),
+                    Source::Packed(..) => None,
+                })
+                .collect();
+            for (hash, entry) in &segment.header.entries {
+                if !loose.contains(hash) {
+                    sources.push(Source::Packed(segment, entry));
+                }
+            }
+        }
+        sources.sort_by_key(|s| match s {
+            Source::File(_, block) | Source::Packed(_, SegmentEntry { block, .. }) => {
+                block.blockchain_length
+            }
+        });
+
+        Segment::write(&path, first, last, sources, config.level)?;
+        if !config.keep_files {
+            for file in &files {
+                remove_file(file)?;
+            }
+        }
+        info!(
+            "Packed {} blocks of lengths {first} to {last} into {}",
+            files.len(),
+            path.display()
+        );
+        report.segments += 1;
+        report.blocks += files.len();
+    }
+
+    info!(
+        "Packed {} blocks into {} segments in {:?}",
+        report.blocks,
+        report.segments,
+        time.elapsed()
+    );
+    Ok(report)
+}
+
+/// Extract the blocks of all segments of `blocks_dir` into its top level
+///
+/// Existing block files are not overwritten. Segments are removed once
+/// extracted, unless `keep_segments`.
+pub fn unpack(blocks_dir: &Path, keep_segments: bool) -> Result<ArchiveReport, ArchiveError> {
+    let mut report = ArchiveReport::default();
+    for segment in segments(blocks_dir)? {
+        for entry in segment.entries() {
+            let path = blocks_dir.join(&entry.name);
+            if path.exists() {
+                continue;
+            }
+            let tmp_path = blocks_dir.join(format!(".{}.part", entry.name));
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            segment.copy_block(entry, &mut writer)?;
+            writer.flush()?;
+            rename(tmp_path, path)?;
+            report.blocks += 1;
+        }
+        if !keep_segments {
+            remove_file(segment.path())?;
+        }
+        report.segments += 1;
+    }
+    info!(
+        "Unpacked {} blocks from {} segments",
+        report.blocks, report.segments
+    );
+    Ok(report)
+}
+
+/// Counts the bytes written through it
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Default for PackConfig {
+    fn default() -> Self {
+        Self {
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            all: false,
+            keep_files: false,
+        }
+    }
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "archive io error: {err}"),
+            Self::Json(err) => write!(f, "malformed segment header: {err}"),
+            Self::Parse(path, err) => write!(f, "{}: {err}", path.display()),
+            Self::NotASegment(path) => write!(f, "{} is not a block segment", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<io::Error> for ArchiveError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ArchiveError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonical_chain_discovery::tests::{state_hash, write_block};
+    use std::fs::read;
+
+    #[test]
+    fn pack_read_unpack() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut originals = BTreeMap::new();
+        for length in 2..=12 {
+            let path = write_block(
+                dir.path(),
+                length,
+                &state_hash(length),
+                &state_hash(length - 1),
+            );
+            originals.insert(path.file_name().unwrap().to_owned(), read(path).unwrap());
+        }
+        let config = PackConfig {
+            segment_size: 5,
+            ..PackConfig::default()
+        };
+
+        // lengths 10 to 12 are the open segment
+        assert_eq!(
+            pack(dir.path(), &config).unwrap(),
+            ArchiveReport {
+                segments: 2,
+                blocks: 8,
+            }
+        );
+        assert_eq!(block_paths(dir.path()).len(), 3);
+        let packed = segments(dir.path()).unwrap();
+        assert_eq!(
+            packed
+                .iter()
+                .map(|s| s.header.entries.len())
+                .collect::<Vec<_>>(),
+            vec![3, 5]
+        );
+        assert!(packed[1].path().ends_with("blocks-5-9.segment"));
+
+        let mut blocks = block_files(dir.path()).unwrap();
+        blocks.sort_by_key(|b| b.block.blockchain_length);
+        assert_eq!(blocks.len(), 8);
+        assert_eq!(
+            blocks[0].block,
+            PrecomputedBlock::from_reader(
+                state_hash(2),
+                originals[blocks[0].path.file_name().unwrap()].as_slice(),
+            )
+            .unwrap()
+        );
+
+        let segment = find(dir.path(), &state_hash(7)).unwrap().unwrap();
+        let mut contents = vec![];
+        segment
+            .copy_block(segment.get(&state_hash(7)).unwrap(), &mut contents)
+            .unwrap();
+        assert_eq!(
+            contents,
+            originals[std::ffi::OsStr::new(&segment.get(&state_hash(7)).unwrap().name)]
+        );
+
+        // a late block and a replaced block are merged into their segment
+        write_block(dir.path(), 6, &state_hash(106), &state_hash(5));
+        write_block(dir.path(), 7, &state_hash(7), &state_hash(106));
+        let report = pack(
+            dir.path(),
+            &PackConfig {
+                all: true,
+                ..config
+            },
+        )
+        .unwrap();
+        assert_eq!(report.blocks, 5);
+        let segment = find(dir.path(), &state_hash(7)).unwrap().unwrap();
+        assert_eq!(segment.header.entries.len(), 6);
+        assert_eq!(
+            segment.get(&state_hash(7)).unwrap().block.parent_hash,
+            state_hash(106)
+        );
+        assert!(block_paths(dir.path()).is_empty());
+
+        assert_eq!(unpack(dir.path(), false).unwrap().blocks, 12);
+        assert!(segments(dir.path()).unwrap().is_empty());
+        assert_eq!(block_paths(dir.path()).len(), 12);
+        for (name, contents) in originals {
+            if name.to_str().unwrap().contains(&state_hash(7)) {
+                continue;
+            }
+            assert_eq!(read(dir.path().join(name)).unwrap(), contents);
+        }
+    }
+}
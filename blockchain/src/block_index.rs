@@ -0,0 +1,577 @@
+use crate::{
+    archive,
+    canonical_chain_discovery::{block_paths, read_blocks, BlockFile},
+    precomputed_block::{BlockParseError, PrecomputedBlock},
+};
+use fs::layout::BlockName;
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    fs::{rename, File},
+    io::{BufReader, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Instant, SystemTime},
+};
+
+/// Name of the index file, kept in the blocks dir
+///
+/// It has no `.json` extension so it is never mistaken for a block
+pub const INDEX_FILE_NAME: &str = ".block-index";
+const INDEX_VERSION: u32 = 1;
+
+/// Header index of a blocks dir, keyed by state hash
+///
+/// The index is updated incrementally, only new or modified block files are
+/// read. Entries of deleted files are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockIndex {
+    blocks_dir: PathBuf,
+    entries: HashMap<String, IndexEntry>,
+    /// state hashes of the blocks of each length
+    by_length: BTreeMap<u32, BTreeSet<String>>,
+    /// modification times of block files which could not be parsed, so they
+    /// aren't reread until they change
+    unparsable: HashMap<PathBuf, SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// block file path, relative to the blocks dir
+    pub path: PathBuf,
+    /// modification time of the block file when it was indexed
+    pub modified: SystemTime,
+    pub block: PrecomputedBlock,
+}
+
+/// What changed in an index update
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IndexUpdate {
+    pub added: usize,
+    /// indexed or unparsable block files which were deleted or changed
+    pub removed: usize,
+    /// new or changed block files which could not be parsed
+    pub skipped: usize,
+}
+
+#[derive(Debug)]
+pub enum IndexError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    UnsupportedVersion(u32),
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexFile {
+    version: u32,
+    entries: Vec<IndexEntry>,
+    #[serde(default)]
+    unparsable: Vec<UnparsableFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UnparsableFile {
+    /// relative to the blocks dir
+    path: PathBuf,
+    modified: SystemTime,
+}
+
+impl BlockIndex {
+    /// An empty index of `blocks_dir`
+    pub fn new(blocks_dir: &Path) -> Self {
+        Self {
+            blocks_dir: blocks_dir.into(),
+            entries: HashMap::new(),
+            by_length: BTreeMap::new(),
+            unparsable: HashMap::new(),
+        }
+    }
+
+    /// Path of the index file of `blocks_dir`
+    pub fn index_path(blocks_dir: &Path) -> PathBuf {
+        blocks_dir.join(INDEX_FILE_NAME)
+    }
+
+    /// Load the index of `blocks_dir`, if there is one
+    pub fn open(blocks_dir: &Path) -> Result<Option<Self>, IndexError> {
+        let index_path = Self::index_path(blocks_dir);
+        if !index_path.exists() {
+            return Ok(None);
+        }
+
+        let file: IndexFile = serde_json::from_reader(BufReader::new(File::open(index_path)?))?;
+        if file.version != INDEX_VERSION {
+            return Err(IndexError::UnsupportedVersion(file.version));
+        }
+
+        let mut index = Self::new(blocks_dir);
+        for entry in file.entries {
+            index.insert(entry);
+        }
+        index.unparsable = file
+            .unparsable
+            .into_iter()
+            .map(|file| (file.path, file.modified))
+            .collect();
+        Ok(Some(index))
+    }
+
+    /// Load the index of `blocks_dir` and bring it up to date, if there is one
+    pub fn open_updated(blocks_dir: &Path) -> Result<Option<Self>, IndexError> {
+        match Self::open(blocks_dir)? {
+            Some(mut index) => {
+                if index.update()? != IndexUpdate::default() {
+                    index.save()?;
+                }
+                Ok(Some(index))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Write the index file atomically
+    ///
+    /// Each save writes its own temp file, so indexes of a shared blocks dir
+    /// can be saved concurrently, e.g. by the `loop` targets of two networks
+    pub fn save(&self) -> Result<(), IndexError> {
+        static SAVES: AtomicUsize = AtomicUsize::new(0);
+        let index_path = Self::index_path(&self.blocks_dir);
+        let tmp_path = self.blocks_dir.join(format!(
+            "{INDEX_FILE_NAME}.{}.{}.tmp",
+            process::id(),
+            SAVES.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut entries: Vec<&IndexEntry> = self.entries.values().collect();
+        entries.sort_by(|a, b| {
+            (a.block.blockchain_length, &a.block.state_hash)
+                .cmp(&(b.block.blockchain_length, &b.block.state_hash))
+        });
+
+        let mut unparsable: Vec<UnparsableFile> = self
+            .unparsable
+            .iter()
+            .map(|(path, modified)| UnparsableFile {
+                path: path.clone(),
+                modified: *modified,
+            })
+            .collect();
+        unparsable.sort_by(|a, b| a.path.cmp(&b.path));
+
+        #[derive(Serialize)]
+        struct IndexFileRef<'a> {
+            version: u32,
+            entries: Vec<&'a IndexEntry>,
+            unparsable: Vec<UnparsableFile>,
+        }
+
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(
+            &mut writer,
+            &IndexFileRef {
+                version: INDEX_VERSION,
+                entries,
+                unparsable,
+            },
+        )?;
+        writer.flush()?;
+        rename(tmp_path, index_path)?;
+        Ok(())
+    }
+
+    /// Index new and modified block files, forget deleted ones
+    ///
+    /// Files which disappear while updating, e.g. renamed by a download or
+    /// packed, are forgotten too.
+    pub fn update(&mut self) -> Result<IndexUpdate, IndexError> {
+        info!("Updating block index of {}...", self.blocks_dir.display());
+        let time = Instant::now();
+        let mut update = IndexUpdate::default();
+
+        // indexed files which are unchanged are kept, all others are (re)read
+        let indexed: HashMap<&Path, (&str, SystemTime)> = self
+            .entries
+            .iter()
+            .map(|(hash, e)| (e.path.as_path(), (hash.as_str(), e.modified)))
+            .collect();
+        let mut kept = HashSet::new();
+        let mut unparsable = HashMap::new();
+        let mut to_read = vec![];
+        for path in block_paths(&self.blocks_dir) {
+            let modified = match path.metadata().and_then(|m| m.modified()) {
+                Ok(modified) => modified,
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    debug!("{} disappeared, not indexing it", path.display());
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let rel_path = path.strip_prefix(&self.blocks_dir).unwrap_or(&path);
+            match indexed.get(rel_path) {
+                Some((hash, indexed_modified)) if *indexed_modified == modified => {
+                    kept.insert(hash.to_string());
+                }
+                _ if self.unparsable.get(rel_path) == Some(&modified) => {
+                    unparsable.insert(rel_path.to_path_buf(), modified);
+                }
+                _ => to_read.push((path, modified)),
+            }
+        }
+        update.removed += self.unparsable.len() - unparsable.len();
+        self.unparsable = unparsable;
+
+        let stale: Vec<String> = self
+            .entries
+            .keys()
+            .filter(|hash| !kept.contains(*hash))
+            .cloned()
+            .collect();
+        for hash in stale {
+            self.remove(&hash);
+            update.removed += 1;
+        }
+
+        let read: Vec<(
+            PathBuf,
+            SystemTime,
+            Result<PrecomputedBlock, BlockParseError>,
+        )> = to_read
+            .into_par_iter()
+            .map(|(path, modified)| {
+                let block = PrecomputedBlock::from_path(&path);
+                (path, modified, block)
+            })
+            .collect();
+        for (path, modified, block) in read {
+            let rel_path = path
+                .strip_prefix(&self.blocks_dir)
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|_| path.clone());
+            match block {
+                Ok(block) => {
+                    self.insert(IndexEntry {
+                        path: rel_path,
+                        modified,
+                        block,
+                    });
+                    update.added += 1;
+                }
+                Err(BlockParseError::Io(err)) if err.kind() == ErrorKind::NotFound => {
+                    debug!("{} disappeared, not indexing it", path.display());
+                }
+                Err(err) => {
+                    warn!("Not indexing {}: {err}", path.display());
+                    self.unparsable.insert(rel_path, modified);
+                    update.skipped += 1;
+                }
+            }
+        }
+
+        info!(
+            "Indexed {} blocks ({} added, {} removed, {} skipped) in {:?}",
+            self.entries.len(),
+            update.added,
+            update.removed,
+            update.skipped,
+            time.elapsed()
+        );
+        Ok(update)
+    }
+
+    fn insert(&mut self, entry: IndexEntry) {
+        let hash = entry.block.state_hash.clone();
+        self.remove(&hash);
+        self.by_length
+            .entry(entry.block.blockchain_length)
+            .or_default()
+            .insert(hash.clone());
+        self.entries.insert(hash, entry);
+    }
+
+    fn remove(&mut self, state_hash: &str) -> Option<IndexEntry> {
+        let entry = self.entries.remove(state_hash)?;
+        let length = entry.block.blockchain_length;
+        if let Some(hashes) = self.by_length.get_mut(&length) {
+            hashes.remove(state_hash);
+            if hashes.is_empty() {
+                self.by_length.remove(&length);
+            }
+        }
+        Some(entry)
+    }
+
+    pub fn blocks_dir(&self) -> &Path {
+        &self.blocks_dir
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, state_hash: &str) -> Option<&IndexEntry> {
+        self.entries.get(state_hash)
+    }
+
+    /// Full path of an indexed block file
+    pub fn path(&self, entry: &IndexEntry) -> PathBuf {
+        self.blocks_dir.join(&entry.path)
+    }
+
+    pub fn max_length(&self) -> Option<u32> {
+        self.by_length.keys().next_back().copied()
+    }
+
+    /// Highest block of the network, other networks may share the blocks dir
+    pub fn highest_block(&self, network: &str) -> Option<&IndexEntry> {
+        self.by_length.keys().rev().find_map(|length| {
+            self.by_length(*length).find(|entry| {
+                BlockName::from_path(&entry.path).is_some_and(|name| name.network == network)
+            })
+        })
+    }
+
+    /// Max length of the network's blocks
+    pub fn max_network_length(&self, network: &str) -> Option<u32> {
+        self.highest_block(network)
+            .map(|entry| entry.block.blockchain_length)
+    }
+
+    pub fn min_length(&self) -> Option<u32> {
+        self.by_length.keys().next().copied()
+    }
+
+    /// Blocks of the given length, ordered by state hash
+    pub fn by_length(&self, length: u32) -> impl Iterator<Item = &IndexEntry> {
+        self.by_length
+            .get(&length)
+            .into_iter()
+            .flatten()
+            .map(|hash| &self.entries[hash])
+    }
+
+    /// Indexed ancestors of a block, starting from its parent, until one is missing
+    pub fn ancestors(&self, state_hash: &str) -> impl Iterator<Item = &IndexEntry> {
+        let block = self.get(state_hash);
+        std::iter::successors(block.and_then(|e| self.get(&e.block.parent_hash)), |e| {
+            self.get(&e.block.parent_hash)
+        })
+    }
+
+    /// Lengths missing between the lowest and highest indexed blocks
+    pub fn gaps(&self) -> Vec<u32> {
+        let lengths: Vec<u32> = self.by_length.keys().copied().collect();
+        lengths
+            .windows(2)
+            .flat_map(|pair| pair[0] + 1..pair[1])
+            .collect()
+    }
+
+    /// All indexed blocks, ready for canonical chain discovery
+    pub fn block_files(&self) -> Vec<BlockFile> {
+        self.entries
+            .values()
+            .map(|e| BlockFile {
+                path: self.path(e),
+                block: e.block.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Headers of all blocks in `blocks_dir`, from its index when there is one
+///
+/// Blocks packed into `archive` segments are included, unless there is also a
+/// block file of the same state hash.
+pub fn read_block_files(blocks_dir: &Path) -> anyhow::Result<Vec<BlockFile>> {
+    let mut blocks = match BlockIndex::open_updated(blocks_dir)? {
+        Some(index) => index.block_files(),
+        None => {
+            let time = Instant::now();
+            info!("Collecting block paths...");
+            let paths = block_paths(blocks_dir);
+            info!("Collection took {:?}", time.elapsed());
+            read_blocks(&paths)?
+        }
+    };
+
+    let loose: HashSet<String> = blocks.iter().map(|b| b.block.state_hash.clone()).collect();
+    blocks.extend(
+        archive::block_files(blocks_dir)?
+            .into_iter()
+            .filter(|b| !loose.contains(&b.block.state_hash)),
+    );
+    Ok(blocks)
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "block index io error: {err}"),
+            Self::Json(err) => write!(f, "malformed block index: {err}"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported block index version {version}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for IndexError {}
+
+impl From<std::io::Error> for IndexError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for IndexError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonical_chain_discovery::tests::{state_hash, write_block};
+    use std::{fs::remove_file, time::Duration};
+
+    fn hashes<'a>(entries: impl Iterator<Item = &'a IndexEntry>) -> Vec<String> {
+        entries.map(|e| e.block.state_hash.clone()).collect()
+    }
+
+    #[test]
+    fn queries() {
+        let dir = tempfile::tempdir().unwrap();
+        for length in [2, 3, 4, 7, 8] {
+            write_block(
+                dir.path(),
+                length,
+                &state_hash(length),
+                &state_hash(length - 1),
+            );
+        }
+        write_block(dir.path(), 4, &state_hash(104), &state_hash(3));
+        let devnet = write_block(dir.path(), 9, &state_hash(9), &state_hash(8));
+        std::fs::rename(
+            &devnet,
+            dir.path().join(format!("devnet-9-{}.json", state_hash(9))),
+        )
+        .unwrap();
+
+        let mut index = BlockIndex::new(dir.path());
+        index.update().unwrap();
+        assert_eq!(index.len(), 7);
+        assert_eq!(index.max_length(), Some(9));
+        assert_eq!(index.max_network_length("mainnet"), Some(8));
+        assert_eq!(index.max_network_length("devnet"), Some(9));
+        assert_eq!(index.max_network_length("testnet"), None);
+        assert_eq!(index.gaps(), vec![5, 6]);
+        assert_eq!(
+            hashes(index.by_length(4)),
+            vec![state_hash(4), state_hash(104)]
+        );
+        assert_eq!(
+            hashes(index.ancestors(&state_hash(104))),
+            vec![state_hash(3), state_hash(2)]
+        );
+        assert_eq!(hashes(index.ancestors(&state_hash(8))), vec![state_hash(7)]);
+        assert_eq!(index.ancestors("3Nunknown").count(), 0);
+
+        // not indexed until saved
+        assert_eq!(BlockIndex::open(dir.path()).unwrap(), None);
+        index.save().unwrap();
+        assert_eq!(BlockIndex::open(dir.path()).unwrap(), Some(index.clone()));
+
+        // concurrent saves don't share a temp file
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..10 {
+                        index.save().unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(BlockIndex::open(dir.path()).unwrap(), Some(index));
+        assert!(std::fs::read_dir(dir.path()).unwrap().all(|entry| entry
+            .unwrap()
+            .path()
+            .extension()
+            != Some("tmp".as_ref())));
+    }
+
+    #[test]
+    fn incremental_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = (2..=5)
+            .map(|length| {
+                write_block(
+                    dir.path(),
+                    length,
+                    &state_hash(length),
+                    &state_hash(length - 1),
+                )
+            })
+            .collect();
+
+        let mut index = BlockIndex::new(dir.path());
+        assert_eq!(index.update().unwrap().added, 4);
+        assert_eq!(index.update().unwrap(), IndexUpdate::default());
+
+        // a new block, a deleted block, a changed block and an unparsable block
+        write_block(dir.path(), 6, &state_hash(6), &state_hash(5));
+        remove_file(&paths[0]).unwrap();
+        write_block(dir.path(), 5, &state_hash(5), &state_hash(104));
+        File::options()
+            .write(true)
+            .open(&paths[3])
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        std::fs::write(dir.path().join("mainnet-7-3Nbad.json"), "{").unwrap();
+
+        assert_eq!(
+            index.update().unwrap(),
+            IndexUpdate {
+                added: 2,
+                removed: 2,
+                skipped: 1,
+            }
+        );
+        assert_eq!(index.min_length(), Some(3));
+        assert_eq!(index.max_length(), Some(6));
+
+        // unparsable files aren't reread until they change
+        assert_eq!(index.update().unwrap(), IndexUpdate::default());
+        index.save().unwrap();
+        let mut reopened = BlockIndex::open(dir.path()).unwrap().unwrap();
+        assert_eq!(reopened.update().unwrap(), IndexUpdate::default());
+        remove_file(dir.path().join("mainnet-7-3Nbad.json")).unwrap();
+        assert_eq!(reopened.update().unwrap().removed, 1);
+        assert_eq!(
+            index.get(&state_hash(5)).unwrap().block.parent_hash,
+            state_hash(104)
+        );
+
+        // a file deleted between listing and reading it is skipped, e.g. a
+        // dangling link
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(
+                dir.path().join("gone"),
+                dir.path().join(format!("mainnet-8-{}.json", state_hash(8))),
+            )
+            .unwrap();
+            assert_eq!(reopened.update().unwrap(), IndexUpdate::default());
+            assert_eq!(reopened.max_length(), Some(6));
+        }
+    }
+}
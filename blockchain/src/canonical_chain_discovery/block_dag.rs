@@ -0,0 +1,256 @@
+use super::{BlockFile, CanonicalChain, DiscoveryConfig, DiscoveryError, OrphanedBranch, TieBreak};
+use log::{info, warn};
+use rayon::prelude::*;
+use std::{cmp::Ordering, collections::BTreeMap, ops::Range};
+
+/// All blocks linked by parent hash
+///
+/// Blocks whose parent is absent are roots, so gaps split the blocks into
+/// several trees. Every node knows the best tip built on top of it.
+///
+/// All headers are held until they're split into a [CanonicalChain], which
+/// returns every block, so memory is linear in the number of blocks. Nothing
+/// below the finality depth is dropped.
+pub(crate) struct BlockDag<'a> {
+    pub blocks: Vec<BlockFile>,
+    /// candidates of each length, as a range of `blocks`
+    pub by_length: BTreeMap<u32, Range<usize>>,
+    pub parents: Vec<Option<usize>>,
+    pub children: Vec<Vec<usize>>,
+    /// index of the best tip in each block's subtree
+    pub best_tips: Vec<usize>,
+    tie_break: &'a TieBreak,
+}
+
+impl<'a> BlockDag<'a> {
+    pub fn new(
+        mut blocks: Vec<BlockFile>,
+        tie_break: &'a TieBreak,
+    ) -> Result<Self, DiscoveryError> {
+        if blocks.is_empty() {
+            return Err(DiscoveryError::NoBlocks);
+        }
+
+        blocks.par_sort_unstable_by(|a, b| {
+            a.block
+                .blockchain_length
+                .cmp(&b.block.blockchain_length)
+                .then_with(|| a.block.state_hash.cmp(&b.block.state_hash))
+        });
+
+        let mut by_length: BTreeMap<u32, Range<usize>> = BTreeMap::new();
+        for (idx, b) in blocks.iter().enumerate() {
+            by_length
+                .entry(b.block.blockchain_length)
+                .or_insert(idx..idx)
+                .end = idx + 1;
+        }
+
+        // a parent can only be among the candidates one length below
+        let mut parents = vec![None; blocks.len()];
+        let mut children = vec![vec![]; blocks.len()];
+        for (idx, b) in blocks.iter().enumerate() {
+            let candidates = b
+                .block
+                .blockchain_length
+                .checked_sub(1)
+                .and_then(|length| by_length.get(&length))
+                .cloned()
+                .unwrap_or_default();
+            if let Some(parent) = candidates
+                .into_iter()
+                .find(|p| blocks[*p].block.state_hash == b.block.parent_hash)
+            {
+                parents[idx] = Some(parent);
+                children[parent].push(idx);
+            }
+        }
+
+        let len = blocks.len();
+        let mut dag = Self {
+            blocks,
+            by_length,
+            parents,
+            children,
+            best_tips: (0..len).collect(),
+            tie_break,
+        };
+
+        // children are longer than their parents, so visit the blocks from the top down
+        for idx in (0..len).rev() {
+            for c in 0..dag.children[idx].len() {
+                let candidate = dag.best_tips[dag.children[idx][c]];
+                if dag.compare(candidate, dag.best_tips[idx]).is_gt() {
+                    dag.best_tips[idx] = candidate;
+                }
+            }
+        }
+        Ok(dag)
+    }
+
+    /// Longest chain wins, ties are broken by the configured rule
+    pub fn compare(&self, a: usize, b: usize) -> Ordering {
+        let (a, b) = (&self.blocks[a].block, &self.blocks[b].block);
+        a.blockchain_length
+            .cmp(&b.blockchain_length)
+            .then_with(|| self.tie_break.compare(a, b))
+    }
+
+    pub fn roots(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.blocks.len()).filter(|idx| self.parents[*idx].is_none())
+    }
+
+    /// The best tip among all blocks
+    pub fn best_tip(&self) -> usize {
+        self.roots()
+            .map(|root| self.best_tips[root])
+            .max_by(|a, b| self.compare(*a, *b))
+            .expect("blocks are nonempty")
+    }
+
+    /// The ancestor `depth` blocks below `idx`
+    pub fn ancestor(&self, mut idx: usize, depth: u32) -> Option<usize> {
+        for _ in 0..depth {
+            idx = self.parents[idx]?;
+        }
+        Some(idx)
+    }
+
+    /// Lengths missing between the lowest and highest blocks
+    pub fn gaps(&self) -> Vec<u32> {
+        info!("Checking for gaps...");
+        let mut gaps = vec![];
+        let lengths: Vec<u32> = self.by_length.keys().copied().collect();
+        for pair in lengths.windows(2) {
+            for missing in pair[0] + 1..pair[1] {
+                warn!("Skipped: {missing}");
+                gaps.push(missing);
+            }
+        }
+
+        if gaps.is_empty() {
+            info!(
+                "No gaps found for blocks between lengths {} and {}",
+                lengths.first().unwrap_or(&0),
+                lengths.last().unwrap_or(&0),
+            );
+        }
+        gaps
+    }
+
+    /// Split the blocks into canonical, successive and orphaned, given the
+    /// best `tip` and the `canonical` chain from its root to the canonical tip
+    pub fn canonical_chain(
+        self,
+        canonical: Vec<usize>,
+        tip: usize,
+        gaps: Vec<u32>,
+    ) -> CanonicalChain {
+        let canonical_tip = *canonical.last().expect("canonical chain is nonempty");
+        let root = canonical[0];
+        if self.blocks[root].block.blockchain_length > self.blocks[0].block.blockchain_length {
+            warn!(
+                "Canonical chain stops at length {}, parent {} is missing",
+                self.blocks[root].block.blockchain_length, self.blocks[root].block.parent_hash
+            );
+        }
+
+        // blocks are either canonical, successive to the canonical tip, or orphaned
+        let mut status = vec![Status::Orphaned; self.blocks.len()];
+        for idx in &canonical {
+            status[*idx] = Status::Canonical;
+        }
+        let mut stack = self.children[canonical_tip].clone();
+        while let Some(idx) = stack.pop() {
+            status[idx] = Status::Successive;
+            stack.extend_from_slice(&self.children[idx]);
+        }
+
+        // an orphaned branch starts at an orphaned block without an orphaned parent
+        let branches: Vec<(usize, Option<String>, u32)> = (0..self.blocks.len())
+            .filter(|idx| {
+                status[*idx] == Status::Orphaned
+                    && self.parents[*idx].map(|p| status[p]) != Some(Status::Orphaned)
+            })
+            .map(|root| {
+                let fork_point =
+                    self.parents[root].map(|p| self.blocks[p].block.state_hash.clone());
+                let length = self.blocks[self.best_tips[root]].block.blockchain_length
+                    - self.blocks[root].block.blockchain_length
+                    + 1;
+                (root, fork_point, length)
+            })
+            .collect();
+        let tip = self.blocks[tip].clone();
+
+        // move each block to its place instead of copying
+        let mut blocks: Vec<Option<BlockFile>> = self.blocks.into_iter().map(Some).collect();
+        let orphaned: Vec<OrphanedBranch> = branches
+            .into_iter()
+            .map(|(root, fork_point, length)| {
+                let mut branch = vec![];
+                let mut stack = vec![root];
+                while let Some(idx) = stack.pop() {
+                    branch.push(idx);
+                    stack.extend_from_slice(&self.children[idx]);
+                }
+                branch.sort_unstable();
+
+                OrphanedBranch {
+                    fork_point,
+                    length,
+                    blocks: branch
+                        .into_iter()
+                        .map(|idx| blocks[idx].take().unwrap())
+                        .collect(),
+                }
+            })
+            .collect();
+        let canonical: Vec<BlockFile> = canonical
+            .into_iter()
+            .map(|idx| blocks[idx].take().unwrap())
+            .collect();
+        let successive: Vec<BlockFile> = blocks.into_iter().flatten().collect();
+
+        info!(
+            "Found {} canonical, {} successive blocks and {} orphaned branches",
+            canonical.len(),
+            successive.len(),
+            orphaned.len(),
+        );
+
+        CanonicalChain {
+            canonical,
+            successive,
+            orphaned,
+            gaps,
+            tip,
+        }
+    }
+}
+
+/// Finality depth check shared by both discovery directions
+pub(crate) fn check_finality(
+    dag: &BlockDag,
+    tip: usize,
+    config: &DiscoveryConfig,
+) -> Result<usize, DiscoveryError> {
+    dag.ancestor(tip, config.finality_depth).ok_or_else(|| {
+        let mut root = tip;
+        while let Some(parent) = dag.parents[root] {
+            root = parent;
+        }
+        DiscoveryError::InsufficientBlocks {
+            required: config.finality_depth,
+            found: dag.blocks[tip].block.blockchain_length
+                - dag.blocks[root].block.blockchain_length,
+        }
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Canonical,
+    Successive,
+    Orphaned,
+}
@@ -1,3 +1,311 @@
 pub mod backward_discovery;
 pub mod common;
 pub mod forward_discovery;
+pub mod output;
+
+mod block_dag;
+
+use crate::precomputed_block::{BlockParseError, PrecomputedBlock};
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use common::MAINNET_CANONICAL_THRESHOLD;
+use log::info;
+use rayon::prelude::*;
+use std::{
+    cmp::Ordering,
+    fmt,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// A parsed block header along with the file it was read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockFile {
+    pub path: PathBuf,
+    pub block: PrecomputedBlock,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanonicalChain {
+    /// canonical blocks in increasing length order
+    pub canonical: Vec<BlockFile>,
+    /// descendants of the canonical tip, not (yet) known to be canonical
+    pub successive: Vec<BlockFile>,
+    /// branches which fork off below the canonical tip, or are disconnected
+    pub orphaned: Vec<OrphanedBranch>,
+    /// lengths missing between the lowest and highest blocks
+    pub gaps: Vec<u32>,
+    /// the best tip the canonical chain was confirmed from
+    pub tip: BlockFile,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrphanedBranch {
+    /// state hash of the canonical block the branch forks from, if present
+    pub fork_point: Option<String>,
+    /// number of lengths the branch spans
+    pub length: u32,
+    /// blocks of the branch in increasing length order
+    pub blocks: Vec<BlockFile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// number of blocks on top of a block before it is considered canonical
+    pub finality_depth: u32,
+    /// how to choose between best tips of the same length
+    pub tie_break: TieBreak,
+}
+
+/// Orders blocks of the same length, the greater block is preferred
+#[derive(Debug, Clone)]
+pub enum TieBreak {
+    /// prefer the lowest `last_vrf_output`
+    LowestVrf,
+    /// prefer the greatest blake2b hash of the `last_vrf_output`
+    VrfHash,
+    /// prefer the lowest state hash
+    StateHash,
+    Custom(fn(&PrecomputedBlock, &PrecomputedBlock) -> Ordering),
+}
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    NoBlocks,
+    Parse(PathBuf, BlockParseError),
+    /// fewer than `required` blocks are built on top of the highest candidate
+    InsufficientBlocks {
+        required: u32,
+        found: u32,
+    },
+}
+
+/// Discover the canonical chain among the given block files
+pub fn discover_canonical_chain(paths: &[PathBuf]) -> Result<CanonicalChain, DiscoveryError> {
+    backward_discovery::discover(paths, &DiscoveryConfig::default())
+}
+
+/// All block file paths in `blocks_dir`, in any `fs::layout`
+pub fn block_paths(blocks_dir: &Path) -> Vec<PathBuf> {
+    fs::layout::block_paths(blocks_dir)
+}
+
+/// Parse the header of each block in parallel
+///
+/// Each file is opened once and only read up to the end of its header. Only
+/// the block bodies are skipped, every header is kept, so memory grows with
+/// the number of blocks, i.e. a few hundred bytes per block
+pub fn read_blocks(paths: &[PathBuf]) -> Result<Vec<BlockFile>, DiscoveryError> {
+    info!(
+        "Reading {} block headers on {} threads...",
+        paths.len(),
+        rayon::current_num_threads()
+    );
+    let time = Instant::now();
+    let blocks = paths
+        .par_iter()
+        .map(|path| match PrecomputedBlock::from_path(path) {
+            Ok(block) => Ok(BlockFile {
+                path: path.clone(),
+                block,
+            }),
+            Err(err) => Err(DiscoveryError::Parse(path.clone(), err)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    info!("Read {} blocks in {:?}", blocks.len(), time.elapsed());
+    Ok(blocks)
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            finality_depth: MAINNET_CANONICAL_THRESHOLD,
+            tie_break: TieBreak::VrfHash,
+        }
+    }
+}
+
+impl TieBreak {
+    pub fn compare(&self, a: &PrecomputedBlock, b: &PrecomputedBlock) -> Ordering {
+        match self {
+            Self::LowestVrf => b.last_vrf_output.cmp(&a.last_vrf_output),
+            Self::VrfHash => {
+                let hash = |b: &PrecomputedBlock| Blake2b::<U32>::digest(&b.last_vrf_output);
+                hash(a).cmp(&hash(b))
+            }
+            Self::StateHash => b.state_hash.cmp(&a.state_hash),
+            Self::Custom(compare) => compare(a, b),
+        }
+    }
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoBlocks => write!(f, "no blocks found"),
+            Self::Parse(path, err) => write!(f, "{}: {err}", path.display()),
+            Self::InsufficientBlocks { required, found } => write!(
+                f,
+                "no canonical blocks can be confidently found, {found} of {required} blocks needed on top"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{fs::write, path::Path};
+
+    /// A fake state hash, unique per `id`
+    pub fn state_hash(id: u32) -> String {
+        format!("3N{id:050}")
+    }
+
+    /// Write a minimal block file, returning its path
+    pub fn write_block(dir: &Path, length: u32, hash: &str, parent_hash: &str) -> PathBuf {
+        let path = dir.join(format!("mainnet-{length}-{hash}.json"));
+        write(
+            &path,
+            format!(
+                r#"{{"scheduled_time":"0","protocol_state":{{"previous_state_hash":"{parent_hash}","body":{{"consensus_state":{{"blockchain_length":"{length}","global_slot_since_genesis":"{length}","block_creator":"B62q"}}}}}}}}"#
+            ),
+        )
+        .unwrap();
+        path
+    }
+
+    /// Chain of lengths 2..=20 with branches of lengths 6, 7 forking from 5
+    /// and 16, 17 forking from 15
+    fn forked_chain(dir: &Path) -> Vec<PathBuf> {
+        let mut paths = vec![];
+        for length in 2..=20 {
+            paths.push(write_block(
+                dir,
+                length,
+                &state_hash(length),
+                &state_hash(length - 1),
+            ));
+        }
+        for fork in [5, 15] {
+            paths.push(write_block(
+                dir,
+                fork + 1,
+                &state_hash(fork + 101),
+                &state_hash(fork),
+            ));
+            paths.push(write_block(
+                dir,
+                fork + 2,
+                &state_hash(fork + 102),
+                &state_hash(fork + 101),
+            ));
+        }
+        paths
+    }
+
+    fn lengths(blocks: &[BlockFile]) -> Vec<u32> {
+        blocks.iter().map(|b| b.block.blockchain_length).collect()
+    }
+
+    #[test]
+    fn backward_and_forward_agree() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = forked_chain(dir.path());
+        let config = DiscoveryConfig::default();
+
+        let backward = backward_discovery::discover(&paths, &config).unwrap();
+        let forward = forward_discovery::discover(&paths, &config).unwrap();
+
+        assert_eq!(lengths(&backward.canonical), (2..=10).collect::<Vec<_>>());
+        assert_eq!(backward.tip.block.state_hash, state_hash(20));
+        assert_eq!(backward.successive.len(), 12);
+        assert_eq!(backward.orphaned.len(), 1);
+        assert_eq!(backward.orphaned[0].fork_point, Some(state_hash(5)));
+        assert_eq!(backward.orphaned[0].length, 2);
+        assert_eq!(lengths(&backward.orphaned[0].blocks), vec![6, 7]);
+        assert!(backward.gaps.is_empty());
+        assert_eq!(backward, forward);
+    }
+
+    #[test]
+    fn finality_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = forked_chain(dir.path());
+        let config = DiscoveryConfig {
+            finality_depth: 3,
+            ..Default::default()
+        };
+
+        let chain = backward_discovery::discover(&paths, &config).unwrap();
+        assert_eq!(lengths(&chain.canonical), (2..=17).collect::<Vec<_>>());
+        assert_eq!(lengths(&chain.successive), vec![18, 19, 20]);
+        assert_eq!(chain.orphaned.len(), 2);
+        assert_eq!(chain, forward_discovery::discover(&paths, &config).unwrap());
+    }
+
+    #[test]
+    fn tie_break() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = forked_chain(dir.path());
+        paths.push(write_block(
+            dir.path(),
+            20,
+            &state_hash(120),
+            &state_hash(19),
+        ));
+
+        for (tie_break, tip) in [
+            (TieBreak::StateHash, state_hash(20)),
+            (
+                TieBreak::Custom(|a, b| a.state_hash.cmp(&b.state_hash)),
+                state_hash(120),
+            ),
+        ] {
+            let config = DiscoveryConfig {
+                tie_break,
+                ..Default::default()
+            };
+            let backward = backward_discovery::discover(&paths, &config).unwrap();
+            let forward = forward_discovery::discover(&paths, &config).unwrap();
+
+            assert_eq!(backward.tip.block.state_hash, tip);
+            assert_eq!(backward, forward);
+        }
+    }
+
+    #[test]
+    fn gaps_and_insufficient_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = vec![
+            write_block(dir.path(), 2, &state_hash(2), &state_hash(1)),
+            write_block(dir.path(), 5, &state_hash(5), &state_hash(4)),
+        ];
+
+        let dag =
+            block_dag::BlockDag::new(read_blocks(&paths).unwrap(), &TieBreak::VrfHash).unwrap();
+        assert_eq!(dag.gaps(), vec![3, 4]);
+        assert!(matches!(
+            discover_canonical_chain(&paths),
+            Err(DiscoveryError::InsufficientBlocks { found: 0, .. })
+        ));
+        assert!(matches!(
+            discover_canonical_chain(&[]),
+            Err(DiscoveryError::NoBlocks)
+        ));
+    }
+
+    #[test]
+    fn malformed_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(format!("mainnet-2-{}.json", state_hash(2)));
+        write(&path, r#"{"protocol_state":"#).unwrap();
+
+        assert!(matches!(
+            discover_canonical_chain(&[path]),
+            Err(DiscoveryError::Parse(_, _))
+        ));
+    }
+}
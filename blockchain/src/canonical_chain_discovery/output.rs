@@ -0,0 +1,242 @@
+//! Canonical chain output, with the provenance of the discovery run
+
+use super::{BlockFile, CanonicalChain};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+/// How a canonical chain was discovered
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// discovery algorithm, e.g. `backward`
+    pub mode: String,
+    /// number of blocks on top of a block before it is canonical
+    pub finality_depth: u32,
+    pub tie_break: String,
+    pub blocks_dir: PathBuf,
+    /// unix time (in ms) of the discovery
+    pub generated_at: u64,
+}
+
+/// The provenance of a canonical chain and what was found
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputHeader {
+    #[serde(flatten)]
+    pub provenance: Provenance,
+    /// state hash of the best tip the canonical chain was confirmed from
+    pub tip: String,
+    pub canonical: usize,
+    pub pending: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputEntry {
+    pub length: u32,
+    pub state_hash: String,
+    pub parent_hash: String,
+    pub status: BlockStatus,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockStatus {
+    Canonical,
+    /// a descendant of the canonical tip, not (yet) known to be canonical
+    Pending,
+}
+
+/// A discovered chain, ready to be written
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainOutput {
+    pub header: OutputHeader,
+    /// canonical, then pending blocks, in increasing length order
+    pub blocks: Vec<OutputEntry>,
+}
+
+const CSV_COLUMNS: &str = "length,state_hash,parent_hash,status,path";
+
+impl ChainOutput {
+    pub fn new(chain: &CanonicalChain, provenance: Provenance) -> Self {
+        let entry = |block: &BlockFile, status| OutputEntry {
+            length: block.block.blockchain_length,
+            state_hash: block.block.state_hash.clone(),
+            parent_hash: block.block.parent_hash.clone(),
+            status,
+            path: block.path.clone(),
+        };
+        Self {
+            header: OutputHeader {
+                provenance,
+                tip: chain.tip.block.state_hash.clone(),
+                canonical: chain.canonical.len(),
+                pending: chain.successive.len(),
+            },
+            blocks: chain
+                .canonical
+                .iter()
+                .map(|b| entry(b, BlockStatus::Canonical))
+                .chain(
+                    chain
+                        .successive
+                        .iter()
+                        .map(|b| entry(b, BlockStatus::Pending)),
+                )
+                .collect(),
+        }
+    }
+
+    /// `#` comment lines of the header fields, then one block file name per
+    /// line
+    pub fn write_text(&self, mut writer: impl Write) -> io::Result<()> {
+        self.write_comment_header(&mut writer)?;
+        for entry in &self.blocks {
+            let name = entry.path.file_name().unwrap_or(entry.path.as_os_str());
+            writeln!(writer, "{}", name.to_string_lossy())?;
+        }
+        Ok(())
+    }
+
+    /// One JSON document per line, so appended runs stay readable as JSON
+    /// lines
+    pub fn write_json(&self, mut writer: impl Write) -> io::Result<()> {
+        serde_json::to_writer(&mut writer, self)?;
+        writeln!(writer)
+    }
+
+    /// `#` comment lines of the header fields, then the CSV rows, preceded by
+    /// the column names unless `columns` is false (e.g. when appending)
+    pub fn write_csv(&self, mut writer: impl Write, columns: bool) -> io::Result<()> {
+        self.write_comment_header(&mut writer)?;
+        if columns {
+            writeln!(writer, "{CSV_COLUMNS}")?;
+        }
+        for entry in &self.blocks {
+            writeln!(
+                writer,
+                "{},{},{},{},{}",
+                entry.length,
+                entry.state_hash,
+                entry.parent_hash,
+                entry.status.as_str(),
+                csv_field(&entry.path.to_string_lossy())
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_comment_header(&self, mut writer: impl Write) -> io::Result<()> {
+        let h = &self.header;
+        let p = &h.provenance;
+        writeln!(writer, "# mode: {}", p.mode)?;
+        writeln!(writer, "# finality_depth: {}", p.finality_depth)?;
+        writeln!(writer, "# tie_break: {}", p.tie_break)?;
+        writeln!(writer, "# blocks_dir: {}", p.blocks_dir.display())?;
+        writeln!(writer, "# generated_at: {}", p.generated_at)?;
+        writeln!(writer, "# tip: {}", h.tip)?;
+        writeln!(writer, "# canonical: {}", h.canonical)?;
+        writeln!(writer, "# pending: {}", h.pending)
+    }
+}
+
+impl BlockStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Canonical => "canonical",
+            Self::Pending => "pending",
+        }
+    }
+}
+
+/// Quote a CSV field if needed
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonical_chain_discovery::{
+        backward_discovery,
+        tests::{state_hash, write_block},
+        DiscoveryConfig,
+    };
+
+    #[test]
+    fn formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = vec![];
+        for length in 2..=6 {
+            paths.push(write_block(
+                dir.path(),
+                length,
+                &state_hash(length),
+                &state_hash(length - 1),
+            ));
+        }
+        paths.push(dir.path().join("a,b"));
+        std::fs::create_dir(&paths[5]).unwrap();
+        paths[5] = write_block(&paths[5], 7, &state_hash(7), &state_hash(6));
+
+        let config = DiscoveryConfig {
+            finality_depth: 2,
+            ..DiscoveryConfig::default()
+        };
+        let chain = backward_discovery::discover(&paths, &config).unwrap();
+        let output = ChainOutput::new(
+            &chain,
+            Provenance {
+                mode: "backward".into(),
+                finality_depth: 2,
+                tie_break: "vrf-hash".into(),
+                blocks_dir: dir.path().into(),
+                generated_at: 1,
+            },
+        );
+        assert_eq!(output.header.tip, state_hash(7));
+        assert_eq!((output.header.canonical, output.header.pending), (4, 2));
+        assert_eq!(output.blocks[4].status, BlockStatus::Pending);
+
+        let mut text = vec![];
+        output.write_text(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let lines: Vec<&str> = text.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], format!("mainnet-2-{}.json", state_hash(2)));
+        assert!(text.starts_with("# mode: backward\n# finality_depth: 2\n"));
+
+        let mut json = vec![];
+        output.write_json(&mut json).unwrap();
+        output.write_json(&mut json).unwrap();
+        let runs: Vec<ChainOutput> = serde_json::Deserializer::from_slice(&json)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(runs, vec![output.clone(), output.clone()]);
+        // the provenance is flattened into the header
+        let value = serde_json::to_value(&output).unwrap();
+        assert_eq!(value["header"]["mode"], "backward");
+
+        let mut csv = vec![];
+        output.write_csv(&mut csv, true).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let rows: Vec<&str> = csv.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(rows[0], CSV_COLUMNS);
+        assert_eq!(
+            rows[1],
+            format!(
+                "2,{},{},canonical,{}",
+                state_hash(2),
+                state_hash(1),
+                paths[0].display()
+            )
+        );
+        assert!(rows[6].ends_with(&format!("pending,\"{}\"", paths[5].display())));
+    }
+}
@@ -0,0 +1,279 @@
+use crate::precomputed_block::PrecomputedBlock;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::Read,
+    str::FromStr,
+};
+
+/// Blocks missing from a collection, enough to fetch exactly those
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GapReport {
+    /// lowest block length
+    pub min_length: u32,
+    /// highest block length
+    pub max_length: u32,
+    /// lengths without any blocks between the lowest and highest
+    pub missing_lengths: Vec<u32>,
+    /// missing parents of blocks, at lengths which do have other blocks
+    pub dangling_parents: Vec<DanglingParent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DanglingParent {
+    /// length of the missing parent
+    pub length: u32,
+    /// state hash of the missing parent
+    pub state_hash: String,
+    /// state hashes of the blocks built on it
+    pub children: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum GapReportError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// a line of a text report which could not be parsed
+    Malformed(String),
+}
+
+impl GapReport {
+    pub fn new<'a>(blocks: impl IntoIterator<Item = &'a PrecomputedBlock>) -> Self {
+        let mut by_length: BTreeMap<u32, BTreeSet<&str>> = BTreeMap::new();
+        let blocks: Vec<&PrecomputedBlock> = blocks.into_iter().collect();
+        for block in &blocks {
+            by_length
+                .entry(block.blockchain_length)
+                .or_default()
+                .insert(&block.state_hash);
+        }
+
+        let lengths: Vec<u32> = by_length.keys().copied().collect();
+        let missing_lengths = lengths
+            .windows(2)
+            .flat_map(|pair| pair[0] + 1..pair[1])
+            .collect();
+
+        // parents absent from a length which isn't missing altogether
+        let mut dangling: BTreeMap<(u32, &str), Vec<String>> = BTreeMap::new();
+        for block in &blocks {
+            let parent_length = block.blockchain_length.saturating_sub(1);
+            if let Some(candidates) = by_length.get(&parent_length) {
+                if !candidates.contains(block.parent_hash.as_str()) {
+                    dangling
+                        .entry((parent_length, &block.parent_hash))
+                        .or_default()
+                        .push(block.state_hash.clone());
+                }
+            }
+        }
+
+        Self {
+            min_length: lengths.first().copied().unwrap_or_default(),
+            max_length: lengths.last().copied().unwrap_or_default(),
+            missing_lengths,
+            dangling_parents: dangling
+                .into_iter()
+                .map(|((length, state_hash), mut children)| {
+                    children.sort();
+                    DanglingParent {
+                        length,
+                        state_hash: state_hash.to_string(),
+                        children,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.missing_lengths.is_empty() && self.dangling_parents.is_empty()
+    }
+
+    /// Read a JSON or text report
+    pub fn read(mut reader: impl Read) -> Result<Self, GapReportError> {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents)?;
+        if contents.trim_start().starts_with('{') {
+            Ok(serde_json::from_str(&contents)?)
+        } else {
+            contents.parse()
+        }
+    }
+
+    /// gsutil URIs of the block files filling the gaps, in length order
+    ///
+    /// Every block of a missing length is queried, while only the parent
+    /// itself is queried for a dangling block
+    pub fn queries(&self, bucket: &str, network: &str) -> Vec<String> {
+        let mut queries: Vec<(u32, String)> = self
+            .missing_lengths
+            .iter()
+            .map(|length| (*length, format!("gs://{bucket}/{network}-{length}-*.json")))
+            .chain(self.dangling_parents.iter().map(|parent| {
+                (
+                    parent.length,
+                    format!(
+                        "gs://{bucket}/{network}-{}-{}.json",
+                        parent.length, parent.state_hash
+                    ),
+                )
+            }))
+            .collect();
+        queries.sort();
+        queries.into_iter().map(|(_, query)| query).collect()
+    }
+}
+
+/// One fact per line
+///
+/// ```text
+/// lengths 2 1000
+/// missing 5
+/// dangling 9 3N...parent 3N...child
+/// ```
+impl fmt::Display for GapReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "lengths {} {}", self.min_length, self.max_length)?;
+        for length in &self.missing_lengths {
+            writeln!(f, "missing {length}")?;
+        }
+        for parent in &self.dangling_parents {
+            write!(f, "dangling {} {}", parent.length, parent.state_hash)?;
+            for child in &parent.children {
+                write!(f, " {child}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for GapReport {
+    type Err = GapReportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut report = Self::default();
+        for line in s.lines().filter(|line| !line.trim().is_empty()) {
+            let malformed = || GapReportError::Malformed(line.to_string());
+            let mut words = line.split_whitespace();
+            let kind = words.next();
+            let mut length = || -> Result<u32, GapReportError> {
+                words
+                    .next()
+                    .and_then(|w| w.parse().ok())
+                    .ok_or_else(malformed)
+            };
+
+            match kind {
+                Some("lengths") => {
+                    report.min_length = length()?;
+                    report.max_length = length()?;
+                }
+                Some("missing") => report.missing_lengths.push(length()?),
+                Some("dangling") => {
+                    let length = length()?;
+                    let state_hash = words.next().ok_or_else(malformed)?.to_string();
+                    report.dangling_parents.push(DanglingParent {
+                        length,
+                        state_hash,
+                        children: words.map(str::to_string).collect(),
+                    });
+                }
+                _ => return Err(malformed()),
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl fmt::Display for GapReportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "failed to read gap report: {err}"),
+            Self::Json(err) => write!(f, "malformed gap report: {err}"),
+            Self::Malformed(line) => write!(f, "malformed gap report line: {line}"),
+        }
+    }
+}
+
+impl std::error::Error for GapReportError {}
+
+impl From<std::io::Error> for GapReportError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for GapReportError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonical_chain_discovery::tests::state_hash;
+
+    fn block(length: u32, id: u32, parent_id: u32) -> PrecomputedBlock {
+        PrecomputedBlock {
+            state_hash: state_hash(id),
+            parent_hash: state_hash(parent_id),
+            blockchain_length: length,
+            global_slot: length,
+            creator: "B62q".to_string(),
+            last_vrf_output: String::new(),
+            scheduled_time: None,
+        }
+    }
+
+    /// Lengths 2..=10 without 5, 6, and with the parent of 8' missing
+    fn report() -> GapReport {
+        let mut blocks: Vec<PrecomputedBlock> = [2, 3, 4, 7, 8, 9, 10]
+            .into_iter()
+            .map(|length| block(length, length, length - 1))
+            .collect();
+        blocks.push(block(8, 108, 107));
+        GapReport::new(&blocks)
+    }
+
+    #[test]
+    fn missing_lengths_and_dangling_parents() {
+        let report = report();
+        assert_eq!((report.min_length, report.max_length), (2, 10));
+        assert_eq!(report.missing_lengths, vec![5, 6]);
+        assert_eq!(
+            report.dangling_parents,
+            vec![DanglingParent {
+                length: 7,
+                state_hash: state_hash(107),
+                children: vec![state_hash(108)],
+            }]
+        );
+        assert_eq!(
+            report.queries("bucket", "mainnet"),
+            vec![
+                "gs://bucket/mainnet-5-*.json".to_string(),
+                "gs://bucket/mainnet-6-*.json".to_string(),
+                format!("gs://bucket/mainnet-7-{}.json", state_hash(107)),
+            ]
+        );
+        assert!(GapReport::new(&[block(2, 2, 1), block(3, 3, 2)]).is_empty());
+    }
+
+    #[test]
+    fn text_and_json_round_trip() {
+        let report = report();
+        let json = serde_json::to_string(&report).unwrap();
+        let text = report.to_string();
+
+        assert_eq!(GapReport::read(json.as_bytes()).unwrap(), report);
+        assert_eq!(GapReport::read(text.as_bytes()).unwrap(), report);
+        assert!(matches!(
+            GapReport::read("missing five".as_bytes()),
+            Err(GapReportError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::precomputed_block::PrecomputedBlock;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Timing constants of a network, relating its slots to wall clock time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenesisConstants {
+    /// unix time (in ms) of the start of slot 0
+    pub genesis_timestamp: u64,
+    /// length (in ms) of a slot
+    pub slot_duration: u64,
+}
+
+impl GenesisConstants {
+    /// Genesis 2021-03-17T00:00:00Z, 3 min slots
+    pub const MAINNET: Self = Self {
+        genesis_timestamp: 1_615_939_200_000,
+        slot_duration: 180_000,
+    };
+
+    /// Built-in constants of the named network
+    pub fn for_network(network: &str) -> Option<Self> {
+        match network {
+            "mainnet" => Some(Self::MAINNET),
+            _ => None,
+        }
+    }
+
+    /// Slot in progress at unix time `time` (in ms)
+    pub fn slot_at(&self, time: u64) -> u32 {
+        (time.saturating_sub(self.genesis_timestamp) / self.slot_duration) as u32
+    }
+}
+
+/// Where the network's tip should be now, extrapolated from one of its blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TipEstimate {
+    /// slot in progress now
+    pub current_slot: u32,
+    /// slots since the block's
+    pub elapsed_slots: u32,
+    /// length if blocks keep filling the same fraction of slots as before
+    pub expected_length: u32,
+    /// length if every slot since the block has one, an upper bound
+    pub max_length: u32,
+}
+
+impl TipEstimate {
+    /// Estimate the tip at unix time `now` (in ms) from `block`
+    ///
+    /// The elapsed slots are counted from the block's global slot. If the
+    /// block's `scheduled_time` doesn't fall in that slot, the genesis
+    /// constants don't fit the network and the elapsed slots are counted from
+    /// the scheduled time instead.
+    pub fn new(block: &PrecomputedBlock, genesis: &GenesisConstants, now: u64) -> Self {
+        let current_slot = genesis.slot_at(now);
+        let elapsed_slots = match block.scheduled_time {
+            Some(scheduled_time) if genesis.slot_at(scheduled_time) != block.global_slot => {
+                warn!(
+                    "Block {} is in slot {}, but was scheduled for slot {} according to the genesis constants",
+                    block.state_hash,
+                    block.global_slot,
+                    genesis.slot_at(scheduled_time)
+                );
+                (now.saturating_sub(scheduled_time) / genesis.slot_duration) as u32
+            }
+            _ => current_slot.saturating_sub(block.global_slot),
+        };
+
+        let density = if block.global_slot == 0 {
+            1.0
+        } else {
+            (block.blockchain_length as f64 / block.global_slot as f64).min(1.0)
+        };
+        Self {
+            current_slot,
+            elapsed_slots,
+            expected_length: block.blockchain_length
+                + (elapsed_slots as f64 * density).round() as u32,
+            max_length: block.blockchain_length + elapsed_slots,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLOT: u64 = GenesisConstants::MAINNET.slot_duration;
+    const GENESIS: u64 = GenesisConstants::MAINNET.genesis_timestamp;
+
+    fn block(length: u32, global_slot: u32, scheduled_time: Option<u64>) -> PrecomputedBlock {
+        PrecomputedBlock {
+            state_hash: "3N".to_string(),
+            parent_hash: "3N".to_string(),
+            blockchain_length: length,
+            global_slot,
+            creator: "B62q".to_string(),
+            last_vrf_output: String::new(),
+            scheduled_time,
+        }
+    }
+
+    #[test]
+    fn estimate_from_slots() {
+        let genesis = GenesisConstants::for_network("mainnet").unwrap();
+        assert_eq!(genesis.slot_at(GENESIS - 1), 0);
+        assert_eq!(genesis.slot_at(GENESIS + 10 * SLOT + 1), 10);
+        assert_eq!(GenesisConstants::for_network("testworld"), None);
+
+        // 3 blocks every 4 slots, 100 slots ago
+        let now = GENESIS + 500 * SLOT + SLOT / 2;
+        let estimate =
+            TipEstimate::new(&block(300, 400, Some(GENESIS + 400 * SLOT)), &genesis, now);
+        assert_eq!(
+            estimate,
+            TipEstimate {
+                current_slot: 500,
+                elapsed_slots: 100,
+                expected_length: 375,
+                max_length: 400,
+            }
+        );
+        assert_eq!(
+            TipEstimate::new(&block(300, 400, None), &genesis, now),
+            estimate
+        );
+
+        // with the wrong genesis, the scheduled time still counts
+        let wrong = GenesisConstants {
+            genesis_timestamp: GENESIS - 1000 * SLOT,
+            ..genesis
+        };
+        let estimate = TipEstimate::new(&block(300, 400, Some(GENESIS + 400 * SLOT)), &wrong, now);
+        assert_eq!((estimate.elapsed_slots, estimate.max_length), (100, 400));
+    }
+}
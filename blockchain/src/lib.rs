@@ -1,12 +1,17 @@
-use std::{
-    ffi::OsStr,
-    fs::File,
-    io::{prelude::*, SeekFrom},
-    path::Path,
-};
+pub mod archive;
+pub mod block_index;
+pub mod canonical_chain_discovery;
+pub mod gap_report;
+pub mod genesis;
+pub mod precomputed_block;
+pub mod watch;
 
+use precomputed_block::PrecomputedBlock;
+use std::{ffi::OsStr, path::Path};
+
+/// extract a blockchain length from a block file path in any `fs::layout`
 pub fn length_from_path(path: &Path) -> Option<u32> {
-    get_blockchain_length(path.file_name().unwrap())
+    fs::layout::length_from_path(path)
 }
 
 pub fn hash_from_path(path: &Path) -> Option<String> {
@@ -14,31 +19,25 @@ pub fn hash_from_path(path: &Path) -> Option<String> {
 }
 
 pub fn extract_parent_hash_from_path(path: &Path) -> anyhow::Result<String> {
-    let parent_hash_offset = 75;
-    let parent_hash_length = 52;
-
-    let mut f = File::open(path)?;
-    f.seek(SeekFrom::Start(parent_hash_offset))?;
-    let mut buf = vec![0; parent_hash_length];
-    f.read_exact(&mut buf)?;
-    let parent_hash = String::from_utf8(buf)?;
-    Ok(parent_hash)
+    Ok(PrecomputedBlock::from_path(path)?.parent_hash)
 }
 
+/// Length of a base58check encoded state hash
+///
+/// Networks differ in their hashes' leading characters, e.g. `3N` on mainnet,
+/// but not in their length
+pub const STATE_HASH_LEN: usize = 52;
+
 /// extract a state hash from an OS file name
 pub fn get_state_hash(file_name: &OsStr) -> Option<String> {
-    let last_part = file_name.to_str()?.split('-').last()?.to_string();
-    if last_part.starts_with('.') {
-        return None;
-    }
-    if !last_part.starts_with("3N") {
-        return None;
-    }
-    let state_hash = last_part.split('.').next()?;
-    if state_hash.contains('.') {
-        return None;
-    }
-    Some(state_hash.to_string())
+    let last_part = file_name.to_str()?.split('-').next_back()?;
+    let state_hash = last_part.strip_suffix(".json").unwrap_or(last_part);
+    is_state_hash(state_hash).then(|| state_hash.to_string())
+}
+
+/// whether `s` looks like a state hash of any network
+pub fn is_state_hash(s: &str) -> bool {
+    s.len() == STATE_HASH_LEN && s.chars().all(|c| c.is_ascii_alphanumeric())
 }
 
 /// extract a blockchain length from an OS file name
@@ -51,3 +50,30 @@ pub fn get_blockchain_length(file_name: &OsStr) -> Option<u32> {
             Ok(x) => Some(x),
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_hashes_of_any_network() {
+        let hash = |name: &str| get_state_hash(OsStr::new(name));
+        let mainnet = "3NKeMoncuHab5ScarV5ViyF16cJPT4taWNSaTLS64Dp67wuXigPZ";
+        let other = "4NKeMoncuHab5ScarV5ViyF16cJPT4taWNSaTLS64Dp67wuXigPZ";
+
+        assert_eq!(hash(&format!("mainnet-2-{mainnet}.json")).unwrap(), mainnet);
+        assert_eq!(hash(&format!("berkeley-2-{other}.json")).unwrap(), other);
+        assert_eq!(
+            hash(&format!("testworld-2-0-5-{other}.json")).unwrap(),
+            other
+        );
+        assert_eq!(
+            get_blockchain_length(OsStr::new(&format!("testworld-2-0-5-{other}.json"))),
+            Some(5)
+        );
+        assert_eq!(hash(&format!("mainnet-2-{mainnet}")).unwrap(), mainnet);
+        assert_eq!(hash(".block-index"), None);
+        assert_eq!(hash(&format!(".mainnet-2-{mainnet}.json.part")), None);
+        assert_eq!(hash(&format!("mainnet-2-{}.json", &mainnet[1..])), None);
+    }
+}
@@ -1,8 +1,24 @@
-use canonical_chain_discovery::{backward_discovery, forward_discovery};
-use clap::{Parser, Subcommand};
-
-mod canonical_chain_discovery;
-use canonical_chain_discovery::common;
+use blockchain::{
+    archive::{self, PackConfig, DEFAULT_SEGMENT_SIZE},
+    block_index::{read_block_files, BlockIndex},
+    canonical_chain_discovery::{
+        backward_discovery,
+        common::MAINNET_CANONICAL_THRESHOLD,
+        forward_discovery,
+        output::{ChainOutput, Provenance},
+        BlockFile, CanonicalChain, DiscoveryConfig, DiscoveryError, TieBreak,
+    },
+    gap_report::GapReport,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use fs::{check_dir, check_file};
+use log::info;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 
 #[derive(Parser, Debug)]
 #[command(name = "chain-discovery", author, version, about, long_about = Some("Mina canonical chain discovery"))]
@@ -14,15 +30,427 @@ struct Cli {
 #[derive(Subcommand, Debug)]
 enum CliSubcommand {
     /// Use the backward canonical chain discovery algorithm (fast)
-    Backward(common::SubcommandArgs),
+    Backward(SubcommandArgs),
     /// Use the forward canonical chain discovery algorithm (slow)
-    Forward(common::SubcommandArgs),
+    Forward(SubcommandArgs),
+    /// Build or update the block header index, then query it
+    Index(IndexArgs),
+    /// Report missing lengths and dangling parents
+    Gaps(GapsArgs),
+    /// Pack block files into zstd compressed segments of consecutive lengths
+    Pack(PackArgs),
+    /// Extract the blocks of all segments
+    Unpack(UnpackArgs),
+    /// List packed blocks
+    Ls(LsArgs),
+    /// Write a packed block to stdout
+    Cat(CatArgs),
+    /// Track the canonical chain as block files appear, emitting JSON line
+    /// events
+    #[cfg(target_os = "linux")]
+    Watch(WatchArgs),
+}
+
+#[derive(Parser, Debug, Clone)]
+struct SubcommandArgs {
+    /// Path to blocks directory
+    #[arg(short, long, default_value = concat!(env!("HOME"), "/.blocks"))]
+    blocks_dir: PathBuf,
+    /// File to output the list of canonical blocks
+    #[arg(short, long, default_value = concat!(env!("HOME"), "/.output"))]
+    output_file: PathBuf,
+    /// Format of the output file
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+    /// Append to the output file instead of overwriting it
+    #[arg(long)]
+    append: bool,
+    /// Number of blocks on top of a block before it is canonical
+    #[arg(short = 'k', long, default_value_t = MAINNET_CANONICAL_THRESHOLD)]
+    finality_depth: u32,
+    /// How to choose between best tips of the same length
+    #[arg(long, value_enum, default_value_t = TieBreakArg::VrfHash)]
+    tie_break: TieBreakArg,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum TieBreakArg {
+    /// Prefer the lowest last VRF output
+    LowestVrf,
+    /// Prefer the greatest hash of the last VRF output
+    VrfHash,
+    /// Prefer the lowest state hash
+    StateHash,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum OutputFormat {
+    /// Block file names, after `#` header lines
+    Text,
+    /// One JSON document of the header and blocks per run
+    Json,
+    /// Block rows, after `#` header lines and a row of column names
+    Csv,
+}
+
+impl From<TieBreakArg> for TieBreak {
+    fn from(arg: TieBreakArg) -> Self {
+        match arg {
+            TieBreakArg::LowestVrf => Self::LowestVrf,
+            TieBreakArg::VrfHash => Self::VrfHash,
+            TieBreakArg::StateHash => Self::StateHash,
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+struct IndexArgs {
+    /// Path to blocks directory
+    #[arg(short, long, default_value = concat!(env!("HOME"), "/.blocks"))]
+    blocks_dir: PathBuf,
+    #[command(subcommand)]
+    query: Option<IndexQuery>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum IndexQuery {
+    /// Highest indexed block length
+    MaxLength,
+    /// Block files of the given length
+    ByLength { length: u32 },
+    /// Block files of the indexed ancestors of a block, from its parent down
+    Ancestors { state_hash: String },
+    /// Lengths missing between the lowest and highest indexed blocks
+    Gaps,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct GapsArgs {
+    /// Path to blocks directory
+    #[arg(short, long, default_value = concat!(env!("HOME"), "/.blocks"))]
+    blocks_dir: PathBuf,
+    /// File to write the report to, instead of stdout
+    #[arg(short, long)]
+    output_file: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+    format: ReportFormat,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct PackArgs {
+    /// Path to blocks directory
+    #[arg(short, long, default_value = concat!(env!("HOME"), "/.blocks"))]
+    blocks_dir: PathBuf,
+    /// Number of consecutive lengths per segment
+    #[arg(short = 'n', long, default_value_t = DEFAULT_SEGMENT_SIZE)]
+    segment_size: u32,
+    /// zstd compression level
+    #[arg(short, long, default_value_t = zstd::DEFAULT_COMPRESSION_LEVEL)]
+    level: i32,
+    /// Also pack the segment of the highest block, which may still grow
+    #[arg(long)]
+    all: bool,
+    /// Keep the packed block files
+    #[arg(long)]
+    keep_files: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct UnpackArgs {
+    /// Path to blocks directory
+    #[arg(short, long, default_value = concat!(env!("HOME"), "/.blocks"))]
+    blocks_dir: PathBuf,
+    /// Keep the segments once extracted
+    #[arg(long)]
+    keep_segments: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct LsArgs {
+    /// Path to blocks directory
+    #[arg(short, long, default_value = concat!(env!("HOME"), "/.blocks"))]
+    blocks_dir: PathBuf,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct CatArgs {
+    /// Path to blocks directory
+    #[arg(short, long, default_value = concat!(env!("HOME"), "/.blocks"))]
+    blocks_dir: PathBuf,
+    state_hash: String,
+}
+
+#[derive(Parser, Debug, Clone)]
+struct WatchArgs {
+    /// Path to the watched blocks directory
+    #[arg(short, long, default_value = concat!(env!("HOME"), "/.mina-indexer/watch-blocks"))]
+    blocks_dir: PathBuf,
+    /// Number of blocks on top of a block before it is finalized
+    #[arg(short = 'k', long, default_value_t = MAINNET_CANONICAL_THRESHOLD)]
+    finality_depth: u32,
+    /// How to choose between best tips of the same length
+    #[arg(long, value_enum, default_value_t = TieBreakArg::VrfHash)]
+    tie_break: TieBreakArg,
+    /// Send events to the clients of a Unix socket at this path, instead of
+    /// stdout
+    #[arg(long)]
+    socket: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ReportFormat {
+    Text,
+    Json,
 }
 
 fn main() -> anyhow::Result<()> {
     env_logger::init();
     match Cli::parse().command {
-        CliSubcommand::Backward(args) => backward_discovery::main(&args),
-        CliSubcommand::Forward(args) => forward_discovery::main(&args),
+        CliSubcommand::Backward(args) => {
+            discover(&args, "backward", backward_discovery::discover_blocks)
+        }
+        CliSubcommand::Forward(args) => {
+            discover(&args, "forward", forward_discovery::discover_blocks)
+        }
+        CliSubcommand::Index(args) => index(&args),
+        CliSubcommand::Gaps(args) => gaps(&args),
+        CliSubcommand::Pack(args) => pack(&args),
+        CliSubcommand::Unpack(args) => {
+            check_dir(&args.blocks_dir)?;
+            archive::unpack(&args.blocks_dir, args.keep_segments)?;
+            Ok(())
+        }
+        CliSubcommand::Ls(args) => ls(&args),
+        CliSubcommand::Cat(args) => cat(&args),
+        #[cfg(target_os = "linux")]
+        CliSubcommand::Watch(args) => watch(&args),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn watch(args: &WatchArgs) -> anyhow::Result<()> {
+    use blockchain::{
+        precomputed_block::PrecomputedBlock,
+        watch::{BlockWatcher, ChainEvent, ChainTracker, SocketSink},
+    };
+    use log::warn;
+
+    let blocks_dir = &args.blocks_dir;
+    check_dir(blocks_dir)?;
+    let sink = args.socket.as_deref().map(SocketSink::bind).transpose()?;
+    let emit = |events: Vec<ChainEvent>| -> anyhow::Result<()> {
+        for event in events {
+            match &sink {
+                Some(sink) => sink.send(&event)?,
+                None => {
+                    let mut stdout = std::io::stdout().lock();
+                    serde_json::to_writer(&mut stdout, &event)?;
+                    writeln!(stdout)?;
+                    stdout.flush()?;
+                }
+            }
+        }
+        Ok(())
+    };
+
+    // watch before reading, so no block falls in between
+    let mut watcher = BlockWatcher::new(blocks_dir)?;
+    let mut tracker = ChainTracker::new(DiscoveryConfig {
+        finality_depth: args.finality_depth,
+        tie_break: args.tie_break.into(),
+    });
+    let mut blocks = read_block_files(blocks_dir)?;
+    blocks.sort_by_key(|b| b.block.blockchain_length);
+    for block in blocks {
+        tracker.insert(block.block);
+    }
+    if let Some(tip) = tracker.best_tip() {
+        info!(
+            "Tracking {} blocks, best tip {} of length {}",
+            tracker.len(),
+            tip.state_hash,
+            tip.blockchain_length
+        );
+        emit(vec![ChainEvent::NewBestTip {
+            state_hash: tip.state_hash.clone(),
+            parent_hash: tip.parent_hash.clone(),
+            length: tip.blockchain_length,
+        }])?;
+    }
+
+    loop {
+        for path in watcher.next_blocks()? {
+            match PrecomputedBlock::from_path(&path) {
+                Ok(block) => emit(tracker.insert(block))?,
+                Err(err) => warn!("Skipping {}: {err}", path.display()),
+            }
+        }
+    }
+}
+
+fn pack(args: &PackArgs) -> anyhow::Result<()> {
+    check_dir(&args.blocks_dir)?;
+    let config = PackConfig {
+        segment_size: args.segment_size,
+        level: args.level,
+        all: args.all,
+        keep_files: args.keep_files,
+    };
+    let report = archive::pack(&args.blocks_dir, &config)?;
+    println!(
+        "{} blocks packed into {} segments",
+        report.blocks, report.segments
+    );
+    Ok(())
+}
+
+fn ls(args: &LsArgs) -> anyhow::Result<()> {
+    check_dir(&args.blocks_dir)?;
+    let mut stdout = std::io::stdout().lock();
+    for segment in archive::segments(&args.blocks_dir)? {
+        let segment_name = segment.path().file_name().unwrap().to_string_lossy();
+        for entry in segment.entries() {
+            writeln!(stdout, "{}\t{segment_name}\t{}", entry.name, entry.size)?;
+        }
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+fn cat(args: &CatArgs) -> anyhow::Result<()> {
+    check_dir(&args.blocks_dir)?;
+    let Some(segment) = archive::find(&args.blocks_dir, &args.state_hash)? else {
+        anyhow::bail!("{} is not packed", args.state_hash);
+    };
+    let mut stdout = std::io::stdout().lock();
+    segment.copy_block(segment.get(&args.state_hash).unwrap(), &mut stdout)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+fn gaps(args: &GapsArgs) -> anyhow::Result<()> {
+    check_dir(&args.blocks_dir)?;
+    let blocks = read_block_files(&args.blocks_dir)?;
+    let report = GapReport::new(blocks.iter().map(|b| &b.block));
+    info!(
+        "{} missing lengths and {} dangling parents between lengths {} and {}",
+        report.missing_lengths.len(),
+        report.dangling_parents.len(),
+        report.min_length,
+        report.max_length,
+    );
+
+    let mut output: Box<dyn Write> = match &args.output_file {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    };
+    match args.format {
+        ReportFormat::Text => write!(output, "{report}")?,
+        ReportFormat::Json => writeln!(output, "{}", serde_json::to_string_pretty(&report)?)?,
+    }
+    output.flush()?;
+    Ok(())
+}
+
+fn index(args: &IndexArgs) -> anyhow::Result<()> {
+    let blocks_dir = &args.blocks_dir;
+    check_dir(blocks_dir)?;
+
+    let mut index = BlockIndex::open(blocks_dir)?.unwrap_or_else(|| BlockIndex::new(blocks_dir));
+    index.update()?;
+    index.save()?;
+
+    match &args.query {
+        None => println!("{} blocks indexed", index.len()),
+        Some(IndexQuery::MaxLength) => {
+            if let Some(max_length) = index.max_length() {
+                println!("{max_length}");
+            }
+        }
+        Some(IndexQuery::ByLength { length }) => {
+            for entry in index.by_length(*length) {
+                println!("{}", index.path(entry).display());
+            }
+        }
+        Some(IndexQuery::Ancestors { state_hash }) => {
+            if index.get(state_hash).is_none() {
+                anyhow::bail!("{state_hash} is not indexed");
+            }
+            for entry in index.ancestors(state_hash) {
+                println!("{}", index.path(entry).display());
+            }
+        }
+        Some(IndexQuery::Gaps) => {
+            for length in index.gaps() {
+                println!("{length}");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn discover(
+    args: &SubcommandArgs,
+    mode: &str,
+    discovery: fn(Vec<BlockFile>, &DiscoveryConfig) -> Result<CanonicalChain, DiscoveryError>,
+) -> anyhow::Result<()> {
+    let blocks_dir = &args.blocks_dir;
+    let output_file_path = &args.output_file;
+
+    check_dir(blocks_dir)?;
+    check_file(output_file_path)?;
+
+    let total = Instant::now();
+    let blocks = read_block_files(blocks_dir)?;
+
+    let config = DiscoveryConfig {
+        finality_depth: args.finality_depth,
+        tie_break: args.tie_break.into(),
+    };
+    let chain = discovery(blocks, &config)?;
+
+    for branch in &chain.orphaned {
+        info!(
+            "Orphaned branch of length {} from {}",
+            branch.length,
+            branch.fork_point.as_deref().unwrap_or("an unknown parent"),
+        );
+    }
+    let time = Instant::now();
+    let output = ChainOutput::new(
+        &chain,
+        Provenance {
+            mode: mode.into(),
+            finality_depth: args.finality_depth,
+            tie_break: args
+                .tie_break
+                .to_possible_value()
+                .unwrap()
+                .get_name()
+                .into(),
+            blocks_dir: blocks_dir.clone(),
+            generated_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+        },
+    );
+    let mut output_file = OpenOptions::new()
+        .write(true)
+        .append(args.append)
+        .truncate(!args.append)
+        .open(output_file_path)?;
+    let columns = !args.append || output_file.metadata()?.len() == 0;
+    let mut writer = BufWriter::new(&mut output_file);
+    match args.format {
+        OutputFormat::Text => output.write_text(&mut writer)?,
+        OutputFormat::Json => output.write_json(&mut writer)?,
+        OutputFormat::Csv => output.write_csv(&mut writer, columns)?,
     }
+    writer.flush()?;
+    info!(
+        "{} written to {} in {:?}",
+        output.blocks.len(),
+        output_file_path.display(),
+        time.elapsed()
+    );
+    info!("Total time: {:?}", total.elapsed());
+    Ok(())
 }
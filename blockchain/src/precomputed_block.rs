@@ -0,0 +1,317 @@
+use crate::hash_from_path;
+use serde::{
+    de::{IgnoredAny, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// The header fields of a Mina precomputed block
+///
+/// The state hash is not part of the block JSON, it comes from the file name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrecomputedBlock {
+    pub state_hash: String,
+    pub parent_hash: String,
+    pub blockchain_length: u32,
+    pub global_slot: u32,
+    pub creator: String,
+    pub last_vrf_output: String,
+    /// unix time (in ms) the block was scheduled for, absent from older blocks
+    #[serde(default)]
+    pub scheduled_time: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum BlockParseError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    InvalidFileName(PathBuf),
+    MissingProtocolState,
+}
+
+impl PrecomputedBlock {
+    /// Parse the header of the block file at `path`
+    pub fn from_path(path: &Path) -> Result<Self, BlockParseError> {
+        let state_hash =
+            hash_from_path(path).ok_or_else(|| BlockParseError::InvalidFileName(path.into()))?;
+        let file = File::open(path)?;
+        Self::from_reader(state_hash, BufReader::new(file))
+    }
+
+    /// Parse the header of a block from its JSON contents
+    pub fn from_reader(state_hash: String, reader: impl Read) -> Result<Self, BlockParseError> {
+        let (
+            ProtocolState {
+                previous_state_hash,
+                body,
+            },
+            scheduled_time,
+        ) = read_header(reader)?;
+        let ConsensusState {
+            blockchain_length,
+            global_slot_since_genesis,
+            block_creator,
+            last_vrf_output,
+        } = body.consensus_state;
+
+        Ok(Self {
+            state_hash,
+            parent_hash: previous_state_hash,
+            blockchain_length: blockchain_length.0,
+            global_slot: global_slot_since_genesis.0,
+            creator: block_creator,
+            last_vrf_output,
+            scheduled_time,
+        })
+    }
+}
+
+/// Streams the block JSON until `protocol_state` has been read
+///
+/// Everything before it is skipped without being built and everything after
+/// it is never read. Both the original and the versioned (`{"data": ...}`)
+/// block formats are supported, as is any key order or whitespace. Blocks
+/// start with `scheduled_time`, it's only read if it comes before
+/// `protocol_state`.
+fn read_header(reader: impl Read) -> Result<(ProtocolState, Option<u64>), BlockParseError> {
+    let mut header = Header::default();
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let res = de.deserialize_map(HeaderVisitor(&mut header));
+
+    // once the header is found we stop consuming the map,
+    // so the parser complains about the unread remainder
+    match (header.protocol_state, res) {
+        (Some(protocol_state), _) => Ok((protocol_state, header.scheduled_time.map(|t| t.0))),
+        (None, Err(err)) => Err(err.into()),
+        (None, Ok(())) => Err(BlockParseError::MissingProtocolState),
+    }
+}
+
+#[derive(Default)]
+struct Header {
+    protocol_state: Option<ProtocolState>,
+    scheduled_time: Option<Numeric<u64>>,
+}
+
+struct HeaderVisitor<'a>(&'a mut Header);
+
+impl<'de, 'a> Visitor<'de> for HeaderVisitor<'a> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a precomputed block object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "protocol_state" => self.0.protocol_state = Some(map.next_value()?),
+                "scheduled_time" => self.0.scheduled_time = Some(map.next_value()?),
+                "data" => map.next_value_seed(HeaderVisitor(&mut *self.0))?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+            if self.0.protocol_state.is_some() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'de, 'a> serde::de::DeserializeSeed<'de> for HeaderVisitor<'a> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+#[derive(Deserialize)]
+struct ProtocolState {
+    previous_state_hash: String,
+    body: ProtocolStateBody,
+}
+
+#[derive(Deserialize)]
+struct ProtocolStateBody {
+    consensus_state: ConsensusState,
+}
+
+#[derive(Deserialize)]
+struct ConsensusState {
+    blockchain_length: Numeric<u32>,
+    global_slot_since_genesis: Numeric<u32>,
+    block_creator: String,
+    #[serde(default)]
+    last_vrf_output: String,
+}
+
+/// Mina serializes integers as strings, newer versions may not
+struct Numeric<T>(T);
+
+impl<'de, T> Deserialize<'de> for Numeric<T>
+where
+    T: Deserialize<'de> + FromStr,
+    T::Err: fmt::Display,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum StrOrNum<T> {
+            Str(String),
+            Num(T),
+        }
+
+        match StrOrNum::deserialize(deserializer)? {
+            StrOrNum::Num(n) => Ok(Numeric(n)),
+            StrOrNum::Str(s) => s.parse().map(Numeric).map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl fmt::Display for BlockParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "failed to read block: {err}"),
+            Self::Json(err) => write!(f, "malformed block: {err}"),
+            Self::InvalidFileName(path) => {
+                write!(f, "no state hash in block file name {}", path.display())
+            }
+            Self::MissingProtocolState => write!(f, "block has no protocol_state"),
+        }
+    }
+}
+
+impl std::error::Error for BlockParseError {}
+
+impl From<std::io::Error> for BlockParseError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for BlockParseError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATE_HASH: &str = "3NKeMoncuHab5ScarV5ViyF16cJPT4taWNSaTLS64Dp67wuXigPZ";
+    const PARENT_HASH: &str = "3NLoKn22eMnyQ7rxh5pxB6vBA3XhSAhhrf7akdqS6HbAKD14Dh1d";
+
+    fn parse(json: &str) -> Result<PrecomputedBlock, BlockParseError> {
+        PrecomputedBlock::from_reader(STATE_HASH.to_string(), json.as_bytes())
+    }
+
+    fn expected() -> PrecomputedBlock {
+        PrecomputedBlock {
+            state_hash: STATE_HASH.to_string(),
+            parent_hash: PARENT_HASH.to_string(),
+            blockchain_length: 2,
+            global_slot: 3,
+            creator: "B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg".to_string(),
+            last_vrf_output: "kNGs_Br8hMTTrJbdAUq7x2dfRWVdPFbt5aRmjLoEPQA=".to_string(),
+            scheduled_time: Some(1615940848214),
+        }
+    }
+
+    #[test]
+    fn compact() {
+        let json = format!(
+            r#"{{"scheduled_time":"1615940848214","protocol_state":{{"previous_state_hash":"{PARENT_HASH}","body":{{"consensus_state":{{"blockchain_length":"2","global_slot_since_genesis":"3","block_creator":"B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg","last_vrf_output":"kNGs_Br8hMTTrJbdAUq7x2dfRWVdPFbt5aRmjLoEPQA="}}}}}},"staged_ledger_diff":{{}}}}"#
+        );
+        assert_eq!(parse(&json).unwrap(), expected());
+    }
+
+    #[test]
+    fn pretty_printed_and_reordered() {
+        let json = format!(
+            r#"{{
+  "staged_ledger_diff": {{ "diff": [1, 2, 3] }},
+  "scheduled_time": "1615940848214",
+  "protocol_state": {{
+    "body": {{
+      "consensus_state": {{
+        "block_creator": "B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg",
+        "last_vrf_output": "kNGs_Br8hMTTrJbdAUq7x2dfRWVdPFbt5aRmjLoEPQA=",
+        "global_slot_since_genesis": 3,
+        "blockchain_length": 2
+      }}
+    }},
+    "previous_state_hash": "{PARENT_HASH}"
+  }}
+}}"#
+        );
+        assert_eq!(parse(&json).unwrap(), expected());
+    }
+
+    /// Fails any read, i.e. the rest of a block which must not be read
+    struct Unread;
+
+    impl Read for Unread {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            panic!("read past the block header")
+        }
+    }
+
+    #[test]
+    fn stops_after_protocol_state() {
+        // the parser peeks one byte past the protocol state
+        let header = |data: &str| {
+            format!(
+                r#"{data}{{"protocol_state":{{"previous_state_hash":"{PARENT_HASH}","body":{{"consensus_state":{{"blockchain_length":"2","global_slot_since_genesis":"3","block_creator":"B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg","last_vrf_output":"kNGs_Br8hMTTrJbdAUq7x2dfRWVdPFbt5aRmjLoEPQA="}}}}}},"#
+            )
+        };
+        let no_scheduled_time = PrecomputedBlock {
+            scheduled_time: None,
+            ..expected()
+        };
+        for header in [header(""), header(r#"{"version":1,"data":"#)] {
+            let block =
+                PrecomputedBlock::from_reader(STATE_HASH.into(), header.as_bytes().chain(Unread));
+            assert_eq!(block.unwrap(), no_scheduled_time);
+        }
+    }
+
+    #[test]
+    fn versioned() {
+        let json = format!(
+            r#"{{"version":1,"data":{{"protocol_state":{{"previous_state_hash":"{PARENT_HASH}","body":{{"consensus_state":{{"blockchain_length":"2","global_slot_since_genesis":"3","block_creator":"B62qiy32p8kAKnny8ZFwoMhYpBppM1DWVCqAPBYNcXnsAHhnfAAuXgg","last_vrf_output":"kNGs_Br8hMTTrJbdAUq7x2dfRWVdPFbt5aRmjLoEPQA="}}}}}}}}}}"#
+        );
+        assert_eq!(
+            parse(&json).unwrap(),
+            PrecomputedBlock {
+                scheduled_time: None,
+                ..expected()
+            }
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(matches!(
+            parse("{}"),
+            Err(BlockParseError::MissingProtocolState)
+        ));
+        assert!(matches!(
+            parse(r#"{"scheduled_time":"16159"#),
+            Err(BlockParseError::Json(_))
+        ));
+        assert!(matches!(
+            parse(r#"{"protocol_state":{"previous_state_hash":"3N"}}"#),
+            Err(BlockParseError::Json(_))
+        ));
+    }
+}
@@ -0,0 +1,628 @@
+//! Canonical chain tracking over a live blocks dir
+//!
+//! A [ChainTracker] keeps the tree of blocks above the last finalized block
+//! and reports how the chain changes as blocks are inserted, a
+//! [BlockWatcher] reports block files as they appear in a blocks dir.
+
+use crate::{canonical_chain_discovery::DiscoveryConfig, precomputed_block::PrecomputedBlock};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+};
+
+/// In-memory block tree, pruned below the last finalized block
+#[derive(Debug, Clone)]
+pub struct ChainTracker {
+    config: DiscoveryConfig,
+    blocks: HashMap<String, PrecomputedBlock>,
+    /// state hashes of the blocks built on each state hash
+    children: HashMap<String, Vec<String>>,
+    by_length: BTreeMap<u32, Vec<String>>,
+    best_tip: Option<String>,
+    finalized: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ChainEvent {
+    NewBestTip {
+        state_hash: String,
+        parent_hash: String,
+        length: u32,
+    },
+    /// the best tip moved to a branch which doesn't extend the previous one
+    Reorg {
+        /// number of blocks of the old branch which are no longer on the chain
+        depth: u32,
+        /// last block common to both branches, if known
+        fork_point: Option<String>,
+        /// state hashes above the fork point, in increasing length order
+        old_branch: Vec<String>,
+        new_branch: Vec<String>,
+    },
+    /// a block `finality_depth` blocks below the best tip, or one of its
+    /// ancestors which wasn't finalized yet
+    Finalized { state_hash: String, length: u32 },
+}
+
+impl ChainTracker {
+    pub fn new(config: DiscoveryConfig) -> Self {
+        Self {
+            config,
+            blocks: HashMap::new(),
+            children: HashMap::new(),
+            by_length: BTreeMap::new(),
+            best_tip: None,
+            finalized: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn best_tip(&self) -> Option<&PrecomputedBlock> {
+        self.best_tip.as_ref().map(|hash| &self.blocks[hash])
+    }
+
+    pub fn finalized(&self) -> Option<&PrecomputedBlock> {
+        self.finalized.as_ref().map(|hash| &self.blocks[hash])
+    }
+
+    /// Add a block to the tree, returning how the chain changed
+    ///
+    /// Known blocks and blocks no higher than the last finalized block are
+    /// ignored. A block may arrive before its parent, it only counts once its
+    /// ancestors reach the last finalized block.
+    pub fn insert(&mut self, block: PrecomputedBlock) -> Vec<ChainEvent> {
+        let finalized_length = self.finalized().map(|b| b.blockchain_length);
+        if self.blocks.contains_key(&block.state_hash)
+            || finalized_length.is_some_and(|length| block.blockchain_length <= length)
+        {
+            debug!("Ignoring block {}", block.state_hash);
+            return vec![];
+        }
+
+        let hash = block.state_hash.clone();
+        self.children
+            .entry(block.parent_hash.clone())
+            .or_default()
+            .push(hash.clone());
+        self.by_length
+            .entry(block.blockchain_length)
+            .or_default()
+            .push(hash.clone());
+        self.blocks.insert(hash.clone(), block);
+
+        if !self.is_connected(&hash) {
+            debug!("Block {hash} is not connected to the finalized block yet");
+            return vec![];
+        }
+
+        // the block may link blocks which arrived before it
+        let candidate = self.best_in_subtree(&hash);
+        let old_tip = match &self.best_tip {
+            Some(best_tip) if self.compare(&candidate, best_tip).is_le() => return vec![],
+            best_tip => best_tip.clone(),
+        };
+
+        let mut events = vec![];
+        if let Some(old_tip) = old_tip {
+            let (fork_point, old_branch, new_branch) = self.branches(&old_tip, &candidate);
+            if !old_branch.is_empty() {
+                events.push(ChainEvent::Reorg {
+                    depth: old_branch.len() as u32,
+                    fork_point,
+                    old_branch,
+                    new_branch,
+                });
+            }
+        }
+        let tip = &self.blocks[&candidate];
+        events.push(ChainEvent::NewBestTip {
+            state_hash: tip.state_hash.clone(),
+            parent_hash: tip.parent_hash.clone(),
+            length: tip.blockchain_length,
+        });
+        self.best_tip = Some(candidate);
+        self.finalize(&mut events);
+        events
+    }
+
+    /// Longest chain wins, ties are broken by the configured rule
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        let (a, b) = (&self.blocks[a], &self.blocks[b]);
+        a.blockchain_length
+            .cmp(&b.blockchain_length)
+            .then_with(|| self.config.tie_break.compare(a, b))
+    }
+
+    /// Whether the block's ancestors reach the last finalized block, if any
+    fn is_connected(&self, hash: &str) -> bool {
+        let Some(finalized) = &self.finalized else {
+            return true;
+        };
+        let mut block = self.blocks.get(hash);
+        while let Some(b) = block {
+            if &b.state_hash == finalized {
+                return true;
+            }
+            block = self.blocks.get(&b.parent_hash);
+        }
+        false
+    }
+
+    fn best_in_subtree(&self, root: &str) -> String {
+        let mut best = root;
+        let mut stack = vec![root];
+        while let Some(hash) = stack.pop() {
+            if self.compare(hash, best).is_gt() {
+                best = hash;
+            }
+            if let Some(children) = self.children.get(hash) {
+                stack.extend(children.iter().map(String::as_str));
+            }
+        }
+        best.to_string()
+    }
+
+    /// The fork point of two tips and the branches above it, in increasing
+    /// length order. If the tips aren't connected, the branches go down to
+    /// the lowest known ancestors.
+    fn branches(&self, old: &str, new: &str) -> (Option<String>, Vec<String>, Vec<String>) {
+        let mut old_branch = vec![];
+        let mut new_branch = vec![];
+        let mut a = self.blocks.get(old);
+        let mut b = self.blocks.get(new);
+        let mut fork_point = None;
+        loop {
+            match (a, b) {
+                (Some(x), Some(y)) if x.state_hash == y.state_hash => {
+                    fork_point = Some(x.state_hash.clone());
+                    break;
+                }
+                (Some(x), Some(y)) if x.blockchain_length < y.blockchain_length => {
+                    new_branch.push(y.state_hash.clone());
+                    b = self.blocks.get(&y.parent_hash);
+                }
+                (Some(x), _) => {
+                    old_branch.push(x.state_hash.clone());
+                    a = self.blocks.get(&x.parent_hash);
+                }
+                (None, Some(y)) => {
+                    new_branch.push(y.state_hash.clone());
+                    b = self.blocks.get(&y.parent_hash);
+                }
+                (None, None) => break,
+            }
+        }
+        old_branch.reverse();
+        new_branch.reverse();
+        (fork_point, old_branch, new_branch)
+    }
+
+    /// Finalize the ancestors of the best tip `finality_depth` blocks down,
+    /// then prune the tree below them
+    fn finalize(&mut self, events: &mut Vec<ChainEvent>) {
+        let mut target = self.best_tip.as_deref().and_then(|h| self.blocks.get(h));
+        for _ in 0..self.config.finality_depth {
+            target = target.and_then(|b| self.blocks.get(&b.parent_hash));
+        }
+        let Some(target) = target else {
+            return;
+        };
+
+        let finalized = self.finalized();
+        let finalized_length = finalized.map_or(0, |b| b.blockchain_length);
+        let mut newly_finalized = vec![];
+        let mut block = Some(target);
+        while let Some(b) = block.filter(|b| b.blockchain_length > finalized_length) {
+            newly_finalized.push(b);
+            block = self.blocks.get(&b.parent_hash);
+        }
+        if let (Some(finalized), Some(lowest)) = (finalized, newly_finalized.last()) {
+            if lowest.parent_hash != finalized.state_hash {
+                warn!(
+                    "Finalized block {} does not extend the previously finalized block {}",
+                    lowest.state_hash, finalized.state_hash
+                );
+            }
+        }
+        events.extend(newly_finalized.iter().rev().map(|b| ChainEvent::Finalized {
+            state_hash: b.state_hash.clone(),
+            length: b.blockchain_length,
+        }));
+
+        let (hash, length) = (target.state_hash.clone(), target.blockchain_length);
+        self.finalized = Some(hash);
+        self.prune(length);
+    }
+
+    /// Forget the blocks below `length`
+    fn prune(&mut self, length: u32) {
+        let kept = self.by_length.split_off(&length);
+        for hash in std::mem::replace(&mut self.by_length, kept)
+            .into_values()
+            .flatten()
+        {
+            self.children.remove(&hash);
+            if let Some(block) = self.blocks.remove(&hash) {
+                self.children.remove(&block.parent_hash);
+            }
+        }
+        for hash in self.by_length.get(&length).into_iter().flatten() {
+            self.children.remove(&self.blocks[hash].parent_hash);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use watcher::BlockWatcher;
+
+#[cfg(target_os = "linux")]
+mod watcher {
+    use fs::layout::{block_paths, BlockName};
+    use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+    use log::{debug, warn};
+    use std::{
+        collections::HashMap,
+        io,
+        path::{Path, PathBuf},
+    };
+
+    /// Reports block files as they're written or moved into a blocks dir, or
+    /// into its numeric shard subdirectories
+    pub struct BlockWatcher {
+        blocks_dir: PathBuf,
+        inotify: Inotify,
+        dirs: HashMap<WatchDescriptor, PathBuf>,
+        buffer: Vec<u8>,
+    }
+
+    impl BlockWatcher {
+        pub fn new(blocks_dir: &Path) -> io::Result<Self> {
+            let mut watcher = Self {
+                blocks_dir: blocks_dir.into(),
+                inotify: Inotify::init()?,
+                dirs: HashMap::new(),
+                buffer: vec![0; 4096],
+            };
+            watcher.add(blocks_dir)?;
+            watcher.add_shards()?;
+            Ok(watcher)
+        }
+
+        /// Watch the shard dirs which aren't watched yet, returning them
+        fn add_shards(&mut self) -> io::Result<Vec<PathBuf>> {
+            let mut added = vec![];
+            for entry in std::fs::read_dir(&self.blocks_dir)? {
+                let path = entry?.path();
+                if is_shard(&path) && !self.dirs.values().any(|dir| dir == &path) {
+                    self.add(&path)?;
+                    added.push(path);
+                }
+            }
+            Ok(added)
+        }
+
+        fn add(&mut self, dir: &Path) -> io::Result<()> {
+            let mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE;
+            let wd = self.inotify.watches().add(dir, mask)?;
+            self.dirs.insert(wd, dir.into());
+            Ok(())
+        }
+
+        /// Wait for new block files
+        ///
+        /// If the event queue overflowed, every block file in the blocks dir
+        /// is reported again, as any of them may have been missed.
+        pub fn next_blocks(&mut self) -> io::Result<Vec<PathBuf>> {
+            let mut paths = vec![];
+            let mut new_dirs = vec![];
+            let mut overflowed = false;
+            for event in self.inotify.read_events_blocking(&mut self.buffer)? {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    overflowed = true;
+                    continue;
+                }
+                let (Some(dir), Some(name)) = (self.dirs.get(&event.wd), event.name) else {
+                    continue;
+                };
+                let path = dir.join(name);
+                if event.mask.contains(EventMask::ISDIR) {
+                    if is_shard(&path) {
+                        new_dirs.push(path);
+                    }
+                } else if event
+                    .mask
+                    .intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO)
+                    && BlockName::from_path(&path).is_some()
+                {
+                    paths.push(path);
+                }
+            }
+            if overflowed {
+                warn!(
+                    "Missed block file events, rescanning {}",
+                    self.blocks_dir.display()
+                );
+                for dir in self.add_shards()? {
+                    debug!("Watching new shard {}", dir.display());
+                }
+                return Ok(block_paths(&self.blocks_dir));
+            }
+            for dir in new_dirs {
+                debug!("Watching new shard {}", dir.display());
+                // blocks may have been moved in before the watch was added
+                for entry in std::fs::read_dir(&dir)? {
+                    let path = entry?.path();
+                    if BlockName::from_path(&path).is_some() {
+                        paths.push(path);
+                    }
+                }
+                self.add(&dir)?;
+            }
+            Ok(paths)
+        }
+    }
+
+    fn is_shard(path: &Path) -> bool {
+        path.is_dir()
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.parse::<u32>().is_ok())
+    }
+}
+
+#[cfg(unix)]
+pub use socket::SocketSink;
+
+#[cfg(unix)]
+mod socket {
+    use super::ChainEvent;
+    use log::{debug, info, warn};
+    use std::{
+        fs::remove_file,
+        io::{self, Write},
+        os::unix::net::{UnixListener, UnixStream},
+        path::Path,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    /// Time a client has to take an event before it's dropped, so a client
+    /// which stopped reading can't hold up the others
+    const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Sends events as JSON lines to every client of a Unix socket
+    pub struct SocketSink {
+        clients: Arc<Mutex<Vec<UnixStream>>>,
+    }
+
+    impl SocketSink {
+        /// Listen on `path`, replacing a stale socket file
+        pub fn bind(path: &Path) -> io::Result<Self> {
+            if path.exists() {
+                remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            info!("Listening on {}", path.display());
+
+            let clients = Arc::new(Mutex::new(vec![]));
+            let accepted = clients.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    if let Err(err) = stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+                        warn!("Dropping client: {err}");
+                        continue;
+                    }
+                    debug!("Client connected");
+                    accepted.lock().unwrap().push(stream);
+                }
+            });
+            Ok(Self { clients })
+        }
+
+        /// Number of connected clients
+        pub fn clients(&self) -> usize {
+            self.clients.lock().unwrap().len()
+        }
+
+        /// Send the event to all clients, dropping those which disconnected
+        /// or didn't take it within [WRITE_TIMEOUT]
+        pub fn send(&self, event: &ChainEvent) -> io::Result<()> {
+            let mut line = serde_json::to_vec(event)?;
+            line.push(b'\n');
+            self.clients.lock().unwrap().retain_mut(|client| {
+                client
+                    .write_all(&line)
+                    .inspect_err(|err| debug!("Dropping client: {err}"))
+                    .is_ok()
+            });
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canonical_chain_discovery::tests::state_hash;
+
+    fn block(length: u32, id: u32, parent: u32) -> PrecomputedBlock {
+        PrecomputedBlock {
+            state_hash: state_hash(id),
+            parent_hash: state_hash(parent),
+            blockchain_length: length,
+            global_slot: length,
+            creator: "B62q".into(),
+            last_vrf_output: String::new(),
+            scheduled_time: None,
+        }
+    }
+
+    fn hashes(ids: &[u32]) -> Vec<String> {
+        ids.iter().map(|id| state_hash(*id)).collect()
+    }
+
+    #[test]
+    fn tip_reorg_finality() {
+        let mut tracker = ChainTracker::new(DiscoveryConfig {
+            finality_depth: 3,
+            ..DiscoveryConfig::default()
+        });
+        for length in 1..=3 {
+            let events = tracker.insert(block(length, length, length - 1));
+            assert!(matches!(
+                events.as_slice(),
+                [ChainEvent::NewBestTip { length: l, .. }] if *l == length
+            ));
+        }
+        assert_eq!(
+            tracker.insert(block(4, 4, 3))[1],
+            ChainEvent::Finalized {
+                state_hash: state_hash(1),
+                length: 1,
+            }
+        );
+
+        // finalizes length 2, a lower block is ignored
+        assert_eq!(
+            tracker.insert(block(5, 5, 4)),
+            vec![
+                ChainEvent::NewBestTip {
+                    state_hash: state_hash(5),
+                    parent_hash: state_hash(4),
+                    length: 5,
+                },
+                ChainEvent::Finalized {
+                    state_hash: state_hash(2),
+                    length: 2,
+                },
+            ]
+        );
+        assert!(tracker.insert(block(2, 102, 1)).is_empty());
+        assert_eq!(tracker.len(), 4);
+
+        // a branch from 3 arrives out of order, it's linked once its root arrives
+        assert!(tracker.insert(block(6, 106, 105)).is_empty());
+        assert!(tracker.insert(block(5, 105, 104)).is_empty());
+        let events = tracker.insert(block(4, 104, 3));
+        assert_eq!(
+            events[0],
+            ChainEvent::Reorg {
+                depth: 2,
+                fork_point: Some(state_hash(3)),
+                old_branch: hashes(&[4, 5]),
+                new_branch: hashes(&[104, 105, 106]),
+            }
+        );
+        assert_eq!(
+            &events[1..],
+            &[
+                ChainEvent::NewBestTip {
+                    state_hash: state_hash(106),
+                    parent_hash: state_hash(105),
+                    length: 6,
+                },
+                ChainEvent::Finalized {
+                    state_hash: state_hash(3),
+                    length: 3,
+                },
+            ]
+        );
+        assert_eq!(tracker.finalized().unwrap().state_hash, state_hash(3));
+        assert_eq!(tracker.len(), 6);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watch_dir_and_socket() {
+        use crate::canonical_chain_discovery::tests::write_block;
+        use std::{
+            io::{BufRead, BufReader},
+            os::unix::net::UnixStream,
+            time::Duration,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("0")).unwrap();
+        let mut watcher = BlockWatcher::new(dir.path()).unwrap();
+        let path = write_block(&dir.path().join("0"), 2, &state_hash(2), &state_hash(1));
+        assert_eq!(watcher.next_blocks().unwrap(), vec![path]);
+
+        let socket = dir.path().join("events.sock");
+        let sink = SocketSink::bind(&socket).unwrap();
+        let client = UnixStream::connect(&socket).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let event = ChainEvent::Finalized {
+            state_hash: state_hash(2),
+            length: 2,
+        };
+        // the client is accepted in the background
+        while sink.clients() == 0 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        sink.send(&event).unwrap();
+        let line = BufReader::new(client).lines().next().unwrap().unwrap();
+        assert_eq!(serde_json::from_str::<ChainEvent>(&line).unwrap(), event);
+
+        // a client which stops reading is dropped once its buffer fills
+        let _stalled = UnixStream::connect(&socket).unwrap();
+        while sink.clients() < 2 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        while sink.clients() == 2 {
+            sink.send(&event).unwrap();
+        }
+        assert_eq!(sink.clients(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watch_overflow() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = BlockWatcher::new(dir.path()).unwrap();
+
+        // more block files than events fit in the queue
+        let max_events: usize = std::fs::read_to_string("/proc/sys/fs/inotify/max_queued_events")
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let mut written: Vec<_> = (0..max_events / 2 + 1)
+            .map(|length| {
+                let path = dir
+                    .path()
+                    .join(format!("mainnet-{length}-{}.json", state_hash(1)));
+                std::fs::write(&path, "{}").unwrap();
+                path
+            })
+            .collect();
+        std::fs::create_dir(dir.path().join("0")).unwrap();
+        let sharded = dir
+            .path()
+            .join(format!("0/mainnet-1-{}.json", state_hash(2)));
+        std::fs::write(&sharded, "{}").unwrap();
+        written.push(sharded);
+
+        // the events before the overflow come first, then the rescan
+        let mut paths = vec![];
+        while paths.len() < written.len() {
+            paths = watcher.next_blocks().unwrap();
+        }
+        paths.sort();
+        written.sort();
+        assert_eq!(paths, written);
+    }
+}
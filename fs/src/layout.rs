@@ -0,0 +1,539 @@
+//! Block directory layouts and migration between them
+
+use glob::glob;
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt,
+    fs::{create_dir_all, remove_dir, remove_file, rename, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Zero-padded width of lengths, unless given
+pub const DEFAULT_PAD_WIDTH: usize = 6;
+
+/// Number of lengths per shard directory, unless given
+pub const DEFAULT_SHARD_SIZE: u32 = 1000;
+
+/// Name of the journal of a migration in progress, kept in the blocks dir
+pub const JOURNAL_FILE_NAME: &str = ".layout-journal";
+
+/// How block files are named and arranged in a blocks dir
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// `{network}-{length}-{state_hash}.json`
+    Flat,
+    /// lengths zero-padded to `width` digits, so names sort by length
+    Padded { width: usize },
+    /// flat names in `{length / shard_size}/` subdirectories
+    Sharded { shard_size: u32 },
+}
+
+/// The parts of a block file name, in any layout
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BlockName {
+    pub network: String,
+    pub length: u32,
+    pub state_hash: String,
+}
+
+/// Renames moving a blocks dir into a layout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    blocks_dir: PathBuf,
+    pub moves: Vec<Move>,
+}
+
+/// A block file rename, relative to the blocks dir
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Move {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum LayoutError {
+    Io(io::Error),
+    /// not a layout name, e.g. `padded:x`
+    InvalidLayout(String),
+    /// two blocks would end up at this path, or a file is already there
+    Conflict(PathBuf),
+    /// a previous migration didn't finish, it must be rolled back first
+    Interrupted(PathBuf),
+    /// a journal line which could not be parsed
+    MalformedJournal(String),
+}
+
+impl BlockName {
+    /// Parse `{network}-{length}-{state_hash}.json`, where the length may be
+    /// zero-padded and the network may contain dashes
+    pub fn parse(file_name: &str) -> Option<Self> {
+        let stem = file_name.strip_suffix(".json")?;
+        let mut parts = stem.rsplitn(3, '-');
+        let state_hash = parts.next().filter(|h| !h.is_empty())?;
+        let length = parts.next()?.parse().ok()?;
+        let network = parts.next().filter(|n| !n.is_empty())?;
+        Some(Self {
+            network: network.to_string(),
+            length,
+            state_hash: state_hash.to_string(),
+        })
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        Self::parse(path.file_name()?.to_str()?)
+    }
+}
+
+/// Length of the block file at `path`, in any layout
+pub fn length_from_path(path: &Path) -> Option<u32> {
+    BlockName::from_path(path).map(|name| name.length)
+}
+
+/// Width of the block file's length, if it's zero-padded
+fn padded_width(path: &Path) -> Option<usize> {
+    let stem = path.file_name()?.to_str()?.strip_suffix(".json")?;
+    let length = stem.rsplit('-').nth(1)?;
+    (length.len() > 1 && length.starts_with('0')).then_some(length.len())
+}
+
+/// Block files in `blocks_dir`, in any layout
+pub fn block_paths(blocks_dir: &Path) -> Vec<PathBuf> {
+    let top_level = glob(&format!("{}/*.json", blocks_dir.display()))
+        .expect("Failed to read glob pattern")
+        .filter_map(|x| x.ok());
+    let sharded = glob(&format!("{}/*/*.json", blocks_dir.display()))
+        .expect("Failed to read glob pattern")
+        .filter_map(|x| x.ok())
+        .filter(|path| {
+            path.parent()
+                .and_then(Path::file_name)
+                .and_then(|shard| shard.to_str())
+                .is_some_and(|shard| shard.parse::<u32>().is_ok())
+        });
+    top_level.chain(sharded).collect()
+}
+
+impl Layout {
+    /// The layout of the block files already in `blocks_dir`, flat if there
+    /// are none
+    pub fn detect(blocks_dir: &Path) -> Self {
+        Self::of_paths(blocks_dir, &block_paths(blocks_dir))
+    }
+
+    /// The layout of the block files at `paths` in `blocks_dir`
+    ///
+    /// A shard size is inferred from the lengths in each shard, preferring
+    /// [DEFAULT_SHARD_SIZE] when it fits.
+    pub fn of_paths(blocks_dir: &Path, paths: &[PathBuf]) -> Self {
+        let mut sharded = false;
+        let (mut min_size, mut max_size) = (1, u32::MAX);
+        let mut width = None;
+        for path in paths {
+            let Some(length) = length_from_path(path) else {
+                continue;
+            };
+            let shard = path
+                .strip_prefix(blocks_dir)
+                .ok()
+                .filter(|relative| relative.components().count() == 2)
+                .and_then(Path::parent)
+                .and_then(|shard| shard.to_str()?.parse::<u32>().ok());
+            match shard {
+                Some(shard) => {
+                    sharded = true;
+                    min_size = min_size.max(length / (shard + 1) + 1);
+                    if let Some(size) = length.checked_div(shard) {
+                        max_size = max_size.min(size);
+                    }
+                }
+                None => width = width.or_else(|| padded_width(path)),
+            }
+        }
+
+        if sharded {
+            let shard_size =
+                if (min_size..=max_size).contains(&DEFAULT_SHARD_SIZE) || min_size > max_size {
+                    DEFAULT_SHARD_SIZE
+                } else {
+                    min_size
+                };
+            Self::Sharded { shard_size }
+        } else if let Some(width) = width {
+            Self::Padded { width }
+        } else {
+            Self::Flat
+        }
+    }
+
+    pub fn file_name(&self, block: &BlockName) -> String {
+        let BlockName {
+            network,
+            length,
+            state_hash,
+        } = block;
+        match self {
+            Self::Padded { width } => format!("{network}-{length:0width$}-{state_hash}.json"),
+            Self::Flat | Self::Sharded { .. } => format!("{network}-{length}-{state_hash}.json"),
+        }
+    }
+
+    /// Path of the block file, relative to the blocks dir
+    pub fn relative_path(&self, block: &BlockName) -> PathBuf {
+        match self {
+            Self::Sharded { shard_size } => {
+                PathBuf::from((block.length / shard_size).to_string()).join(self.file_name(block))
+            }
+            Self::Flat | Self::Padded { .. } => self.file_name(block).into(),
+        }
+    }
+}
+
+impl Migration {
+    /// Plan moving every block file in `blocks_dir` into `layout`
+    ///
+    /// Nothing is moved if a block would overwrite a file.
+    pub fn plan(blocks_dir: &Path, layout: Layout) -> Result<Self, LayoutError> {
+        check_no_journal(blocks_dir)?;
+
+        let mut moves = vec![];
+        let mut targets = HashSet::new();
+        for path in block_paths(blocks_dir) {
+            let Some(block) = BlockName::from_path(&path) else {
+                continue;
+            };
+            let from = path.strip_prefix(blocks_dir).unwrap().to_path_buf();
+            let to = layout.relative_path(&block);
+            if !targets.insert(to.clone()) {
+                return Err(LayoutError::Conflict(to));
+            }
+            if from != to {
+                if blocks_dir.join(&to).exists() {
+                    return Err(LayoutError::Conflict(to));
+                }
+                moves.push(Move { from, to });
+            }
+        }
+        moves.sort_by(|a, b| a.from.cmp(&b.from));
+
+        Ok(Self {
+            blocks_dir: blocks_dir.into(),
+            moves,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.moves.is_empty()
+    }
+
+    /// Move the block files, all or none of them
+    ///
+    /// The moves are journaled before any is made. If one fails, those
+    /// already made are undone. If the process dies, [rollback] undoes them.
+    pub fn run(&self) -> Result<usize, LayoutError> {
+        check_no_journal(&self.blocks_dir)?;
+        let journal_path = self.blocks_dir.join(JOURNAL_FILE_NAME);
+        write_journal(&journal_path, &self.moves)?;
+
+        for (n, mv) in self.moves.iter().enumerate() {
+            if let Err(err) = apply(&self.blocks_dir, &mv.from, &mv.to) {
+                undo(&self.blocks_dir, &self.moves[..n])?;
+                remove_file(journal_path)?;
+                return Err(err.into());
+            }
+        }
+
+        remove_empty_shards(&self.blocks_dir, self.moves.iter().map(|mv| &mv.from));
+        remove_file(journal_path)?;
+        Ok(self.moves.len())
+    }
+}
+
+/// Undo an interrupted migration of `blocks_dir`, returning the number of
+/// block files moved back
+pub fn rollback(blocks_dir: &Path) -> Result<usize, LayoutError> {
+    let journal_path = blocks_dir.join(JOURNAL_FILE_NAME);
+    if !journal_path.exists() {
+        return Ok(0);
+    }
+
+    let moves = read_journal(&journal_path)?;
+    let undone = undo(blocks_dir, &moves)?;
+    remove_file(journal_path)?;
+    Ok(undone)
+}
+
+fn check_no_journal(blocks_dir: &Path) -> Result<(), LayoutError> {
+    let journal_path = blocks_dir.join(JOURNAL_FILE_NAME);
+    if journal_path.exists() {
+        return Err(LayoutError::Interrupted(journal_path));
+    }
+    Ok(())
+}
+
+fn apply(blocks_dir: &Path, from: &Path, to: &Path) -> io::Result<()> {
+    let to = blocks_dir.join(to);
+    if let Some(parent) = to.parent() {
+        create_dir_all(parent)?;
+    }
+    rename(blocks_dir.join(from), to)
+}
+
+/// Undo the moves which were made, latest first
+fn undo(blocks_dir: &Path, moves: &[Move]) -> io::Result<usize> {
+    let mut undone = 0;
+    for mv in moves.iter().rev() {
+        if blocks_dir.join(&mv.to).exists() && !blocks_dir.join(&mv.from).exists() {
+            apply(blocks_dir, &mv.to, &mv.from)?;
+            undone += 1;
+        }
+    }
+    remove_empty_shards(blocks_dir, moves.iter().map(|mv| &mv.to));
+    Ok(undone)
+}
+
+/// Remove the shard dirs of `paths` which are left empty
+fn remove_empty_shards<'a>(blocks_dir: &Path, paths: impl Iterator<Item = &'a PathBuf>) {
+    let shards: BTreeSet<&Path> = paths
+        .filter_map(|path| path.parent())
+        .filter(|shard| !shard.as_os_str().is_empty())
+        .collect();
+    for shard in shards {
+        // fails if not empty, which is fine
+        let _ = remove_dir(blocks_dir.join(shard));
+    }
+}
+
+/// One `{from}\t{to}` line per move, synced before any move is made
+fn write_journal(path: &Path, moves: &[Move]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for mv in moves {
+        writeln!(writer, "{}\t{}", mv.from.display(), mv.to.display())?;
+    }
+    writer.into_inner()?.sync_all()
+}
+
+fn read_journal(path: &Path) -> Result<Vec<Move>, LayoutError> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .map(|line| {
+            let line = line?;
+            match line.split_once('\t') {
+                Some((from, to)) => Ok(Move {
+                    from: from.into(),
+                    to: to.into(),
+                }),
+                None => Err(LayoutError::MalformedJournal(line)),
+            }
+        })
+        .collect()
+}
+
+/// `flat`, `padded[:width]` or `sharded[:shard_size]`
+impl FromStr for Layout {
+    type Err = LayoutError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || LayoutError::InvalidLayout(s.to_string());
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        match (name, arg) {
+            ("flat", None) => Ok(Self::Flat),
+            ("padded", None) => Ok(Self::Padded {
+                width: DEFAULT_PAD_WIDTH,
+            }),
+            ("padded", Some(width)) => Ok(Self::Padded {
+                width: width.parse().map_err(|_| invalid())?,
+            }),
+            ("sharded", None) => Ok(Self::Sharded {
+                shard_size: DEFAULT_SHARD_SIZE,
+            }),
+            ("sharded", Some(size)) => match size.parse() {
+                Ok(shard_size) if shard_size > 0 => Ok(Self::Sharded { shard_size }),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Flat => write!(f, "flat"),
+            Self::Padded { width } => write!(f, "padded:{width}"),
+            Self::Sharded { shard_size } => write!(f, "sharded:{shard_size}"),
+        }
+    }
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::InvalidLayout(s) => write!(
+                f,
+                "invalid layout {s}, expected flat, padded[:width] or sharded[:shard_size]"
+            ),
+            Self::Conflict(path) => write!(f, "more than one block at {}", path.display()),
+            Self::Interrupted(journal) => write!(
+                f,
+                "a migration was interrupted, roll it back first (journal {})",
+                journal.display()
+            ),
+            Self::MalformedJournal(line) => write!(f, "malformed journal line: {line}"),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+impl From<io::Error> for LayoutError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_to_string, write};
+
+    const HASH: &str = "3NKeMoncuHab5ScarV5ViyF16cJPT4taWNSaTLS64Dp67wuXigPZ";
+
+    fn names(blocks_dir: &Path) -> Vec<PathBuf> {
+        let mut names: Vec<PathBuf> = block_paths(blocks_dir)
+            .into_iter()
+            .map(|p| p.strip_prefix(blocks_dir).unwrap().to_path_buf())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn names_in_every_layout() {
+        let block = BlockName::parse(&format!("testworld-2-0-000042-{HASH}.json")).unwrap();
+        assert_eq!(
+            block,
+            BlockName {
+                network: "testworld-2-0".to_string(),
+                length: 42,
+                state_hash: HASH.to_string(),
+            }
+        );
+
+        for (layout, path) in [
+            ("flat", format!("testworld-2-0-42-{HASH}.json")),
+            ("padded", format!("testworld-2-0-000042-{HASH}.json")),
+            ("padded:3", format!("testworld-2-0-042-{HASH}.json")),
+            ("sharded:10", format!("4/testworld-2-0-42-{HASH}.json")),
+        ] {
+            let layout: Layout = layout.parse().unwrap();
+            assert_eq!(layout.relative_path(&block), PathBuf::from(&path));
+            assert_eq!(length_from_path(Path::new(&path)), Some(42));
+            assert_eq!(layout.to_string().parse::<Layout>().unwrap(), layout);
+        }
+        assert!("sharded:0".parse::<Layout>().is_err());
+        assert_eq!(BlockName::parse(&format!("mainnet-x-{HASH}.json")), None);
+        assert_eq!(BlockName::parse("mainnet-42.json"), None);
+    }
+
+    #[test]
+    fn detect_layouts() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks_dir = dir.path();
+        assert_eq!(Layout::detect(blocks_dir), Layout::Flat);
+
+        write(blocks_dir.join(format!("mainnet-42-{HASH}.json")), "").unwrap();
+        assert_eq!(Layout::detect(blocks_dir), Layout::Flat);
+
+        write(blocks_dir.join(format!("mainnet-0042-{HASH}.json")), "").unwrap();
+        assert_eq!(Layout::detect(blocks_dir), Layout::Padded { width: 4 });
+
+        let shard = |length: u32, shard: u32| {
+            let paths = [blocks_dir.join(format!("{shard}/mainnet-{length}-{HASH}.json"))];
+            Layout::of_paths(blocks_dir, &paths)
+        };
+        assert_eq!(shard(1500, 1), Layout::Sharded { shard_size: 1000 });
+        assert_eq!(shard(15, 1), Layout::Sharded { shard_size: 8 });
+        assert_eq!(shard(5, 0), Layout::Sharded { shard_size: 1000 });
+
+        create_dir_all(blocks_dir.join("2")).unwrap();
+        write(blocks_dir.join(format!("2/mainnet-2001-{HASH}.json")), "").unwrap();
+        assert_eq!(
+            Layout::detect(blocks_dir),
+            Layout::Sharded { shard_size: 1000 }
+        );
+    }
+
+    #[test]
+    fn migrate_and_roll_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks_dir = dir.path();
+        for length in [5, 1005, 2000] {
+            write(
+                blocks_dir.join(format!("mainnet-{length}-{HASH}.json")),
+                length.to_string(),
+            )
+            .unwrap();
+        }
+
+        let sharded = Migration::plan(blocks_dir, Layout::Sharded { shard_size: 1000 }).unwrap();
+        assert_eq!(sharded.moves.len(), 3);
+        assert_eq!(sharded.run().unwrap(), 3);
+        assert_eq!(
+            names(blocks_dir),
+            vec![
+                PathBuf::from(format!("0/mainnet-5-{HASH}.json")),
+                PathBuf::from(format!("1/mainnet-1005-{HASH}.json")),
+                PathBuf::from(format!("2/mainnet-2000-{HASH}.json")),
+            ]
+        );
+        assert!(
+            Migration::plan(blocks_dir, Layout::Sharded { shard_size: 1000 })
+                .unwrap()
+                .is_empty()
+        );
+
+        // an interrupted migration to padded is rolled back
+        let padded = Migration::plan(blocks_dir, Layout::Padded { width: 6 }).unwrap();
+        write_journal(&blocks_dir.join(JOURNAL_FILE_NAME), &padded.moves).unwrap();
+        let first = &padded.moves[0];
+        apply(blocks_dir, &first.from, &first.to).unwrap();
+        assert!(matches!(
+            Migration::plan(blocks_dir, Layout::Flat),
+            Err(LayoutError::Interrupted(_))
+        ));
+        assert_eq!(rollback(blocks_dir).unwrap(), 1);
+        assert!(!blocks_dir.join(JOURNAL_FILE_NAME).exists());
+        assert_eq!(names(blocks_dir), names_after(&sharded));
+
+        // back to flat, shards are removed
+        Migration::plan(blocks_dir, Layout::Flat)
+            .unwrap()
+            .run()
+            .unwrap();
+        assert_eq!(
+            read_to_string(blocks_dir.join(format!("mainnet-1005-{HASH}.json"))).unwrap(),
+            "1005"
+        );
+        assert!(!blocks_dir.join("1").exists());
+
+        // nothing moves if two blocks would collide
+        write(blocks_dir.join(format!("mainnet-05-{HASH}.json")), "5").unwrap();
+        assert!(matches!(
+            Migration::plan(blocks_dir, Layout::Flat),
+            Err(LayoutError::Conflict(_))
+        ));
+        assert_eq!(std::fs::read_dir(blocks_dir).unwrap().count(), 4);
+    }
+
+    fn names_after(migration: &Migration) -> Vec<PathBuf> {
+        let mut names: Vec<PathBuf> = migration.moves.iter().map(|mv| mv.to.clone()).collect();
+        names.sort();
+        names
+    }
+}
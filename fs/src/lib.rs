@@ -1,25 +1,223 @@
+pub mod layout;
+
 use std::{
-    fs::{create_dir_all, File},
-    path::PathBuf,
+    fmt,
+    fs::{create_dir_all, remove_file, File},
+    io,
+    path::{Path, PathBuf},
 };
 
-/// If path exists, assert that it's a file,
+#[derive(Debug)]
+pub enum CheckError {
+    NotAFile(PathBuf),
+    NotADir(PathBuf),
+    PermissionDenied(PathBuf),
+    /// the path or its file system is read-only
+    ReadOnly(PathBuf),
+    /// fewer bytes are available than the estimate of those required
+    DiskFull {
+        path: PathBuf,
+        available: u64,
+        required: u64,
+    },
+    Io(PathBuf, io::Error),
+}
+
+/// If path exists, check that it's a file,
 /// else create the path as a file
-pub fn check_file(file: &PathBuf) {
+pub fn check_file(file: &Path) -> Result<(), CheckError> {
     if file.exists() {
-        assert!(file.is_file(), "{} must be a file!", file.display());
-    } else {
-        create_dir_all(file.parent().unwrap()).unwrap();
-        File::create(file).unwrap();
+        if !file.is_file() {
+            return Err(CheckError::NotAFile(file.into()));
+        }
+        return Ok(());
     }
+    if let Some(parent) = file.parent() {
+        create_dir_all(parent).map_err(|err| CheckError::new(parent, err))?;
+    }
+    File::create(file).map_err(|err| CheckError::new(file, err))?;
+    Ok(())
 }
 
-/// If path exists, assert that it's a directory,
+/// If path exists, check that it's a directory,
 /// else create the path as a directory
-pub fn check_dir(dir: &PathBuf) {
+pub fn check_dir(dir: &Path) -> Result<(), CheckError> {
     if dir.exists() {
-        assert!(dir.is_dir(), "{} must be a dir!", dir.display());
-    } else {
-        create_dir_all(dir).unwrap()
+        if !dir.is_dir() {
+            return Err(CheckError::NotADir(dir.into()));
+        }
+        return Ok(());
+    }
+    create_dir_all(dir).map_err(|err| CheckError::new(dir, err))
+}
+
+/// Optional checks of a directory, beyond [check_dir]
+///
+/// ```no_run
+/// fs::DirCheck::new()
+///     .writable()
+///     .free_space(100 << 20)
+///     .check(std::path::Path::new("blocks"))
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirCheck {
+    writable: bool,
+    free_space: Option<u64>,
+}
+
+impl DirCheck {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check a file can be created in the directory
+    pub fn writable(self) -> Self {
+        Self {
+            writable: true,
+            ..self
+        }
+    }
+
+    /// Check at least `bytes` are available in the directory's file system
+    pub fn free_space(self, bytes: u64) -> Self {
+        Self {
+            free_space: Some(bytes),
+            ..self
+        }
+    }
+
+    /// Run [check_dir] and the chosen checks
+    pub fn check(&self, dir: &Path) -> Result<(), CheckError> {
+        check_dir(dir)?;
+        if self.writable {
+            check_writable(dir)?;
+        }
+        if let Some(required) = self.free_space {
+            if let Some(available) = available_space(dir)? {
+                if available < required {
+                    return Err(CheckError::DiskFull {
+                        path: dir.into(),
+                        available,
+                        required,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create and remove a probe file in `dir`
+fn check_writable(dir: &Path) -> Result<(), CheckError> {
+    let metadata = dir.metadata().map_err(|err| CheckError::new(dir, err))?;
+    if metadata.permissions().readonly() {
+        return Err(CheckError::ReadOnly(dir.into()));
+    }
+
+    let probe = dir.join(format!(".write-check-{}", std::process::id()));
+    File::create(&probe).map_err(|err| CheckError::new(dir, err))?;
+    remove_file(&probe).map_err(|err| CheckError::new(dir, err))
+}
+
+/// Bytes available to unprivileged users in the file system of `dir`, if
+/// the platform can tell
+#[cfg(unix)]
+pub fn available_space(dir: &Path) -> Result<Option<u64>, CheckError> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let c_path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|err| CheckError::new(dir, io::Error::new(io::ErrorKind::InvalidInput, err)))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is a valid C string and `stat` is only read if statvfs succeeds
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(CheckError::new(dir, io::Error::last_os_error()));
+    }
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_dir: &Path) -> Result<Option<u64>, CheckError> {
+    Ok(None)
+}
+
+impl CheckError {
+    fn new(path: &Path, err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied(path.into()),
+            io::ErrorKind::ReadOnlyFilesystem => Self::ReadOnly(path.into()),
+            io::ErrorKind::StorageFull => Self::DiskFull {
+                path: path.into(),
+                available: 0,
+                required: 0,
+            },
+            _ => Self::Io(path.into(), err),
+        }
+    }
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAFile(path) => write!(f, "{} must be a file!", path.display()),
+            Self::NotADir(path) => write!(f, "{} must be a dir!", path.display()),
+            Self::PermissionDenied(path) => write!(f, "permission denied: {}", path.display()),
+            Self::ReadOnly(path) => write!(f, "{} is read-only", path.display()),
+            Self::DiskFull {
+                path,
+                available,
+                required,
+            } => write!(
+                f,
+                "not enough space in {}: {available} bytes available, about {required} required",
+                path.display()
+            ),
+            Self::Io(path, err) => write!(f, "{}: {err}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for CheckError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{set_permissions, write};
+
+    #[test]
+    fn checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        let sub_dir = dir.path().join("a/b");
+
+        check_file(&file).unwrap();
+        check_dir(&sub_dir).unwrap();
+        assert!(file.is_file() && sub_dir.is_dir());
+        assert!(matches!(check_dir(&file), Err(CheckError::NotADir(_))));
+        assert!(matches!(check_file(&sub_dir), Err(CheckError::NotAFile(_))));
+        write(&file, "").unwrap();
+
+        DirCheck::new().writable().check(&sub_dir).unwrap();
+        assert!(available_space(dir.path()).unwrap().unwrap() > 0);
+        assert!(matches!(
+            DirCheck::new().free_space(u64::MAX).check(dir.path()),
+            Err(CheckError::DiskFull {
+                required: u64::MAX,
+                ..
+            })
+        ));
+
+        let mut permissions = sub_dir.metadata().unwrap().permissions();
+        permissions.set_readonly(true);
+        set_permissions(&sub_dir, permissions.clone()).unwrap();
+        assert!(matches!(
+            DirCheck::new().writable().check(&sub_dir),
+            Err(CheckError::ReadOnly(_))
+        ));
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        set_permissions(&sub_dir, permissions).unwrap();
     }
 }
@@ -1,179 +1,60 @@
-use bytesize::ByteSize;
-use clap::Parser;
-use glob::{glob, GlobError};
-use std::{
-    ffi::OsStr,
-    fs, io,
-    mem::size_of_val,
-    path::{Path, PathBuf},
-    time::Instant,
-    vec::IntoIter,
-};
+use clap::{Parser, Subcommand};
+use fs::layout::{rollback, Layout, Migration};
+use std::{path::PathBuf, time::Instant};
 
 #[derive(Parser, Debug)]
-#[command(name = "mina-block-patterns", author, about, long_about = Some("Iterate over Mina blocks!"))]
+#[command(name = "mina-block-patterns", author, about, long_about = Some("Arrange Mina block dirs!"))]
 struct CliArgs {
-    /// Block file glob pattern
-    #[arg(short, long)]
-    block_files_pattern: String,
-}
-
-fn main() {
-    let args = CliArgs::parse();
-    let pattern = &args.block_files_pattern;
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Move the blocks into another layout: flat, padded[:width] or sharded[:shard_size]
+    Migrate {
+        /// Directory of blocks to migrate
+        #[arg(short, long)]
+        blocks_dir: PathBuf,
+        /// Layout to migrate to
+        #[arg(short, long)]
+        layout: Layout,
+        /// Print the moves without making them
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Undo an interrupted migration
+    Rollback {
+        /// Directory of blocks with an interrupted migration
+        #[arg(short, long)]
+        blocks_dir: PathBuf,
+    },
+}
+
+fn main() -> Result<(), fs::layout::LayoutError> {
     let time = Instant::now();
-
-    // let (num_files, size_msg, iterator) = sort_no_rename(pattern);
-    // for path in iterator {
-    //     println!("{:?}", path.file_name().unwrap());
-    // }
-
-    let num_files = glob_unpad_dir(pattern).unwrap();
-    let total = time.elapsed();
-
-    println!("\n~~~ Stats ~~~");
-    // println!("{size_msg}");
-    println!("Time elapsed: {total:?}");
-    println!("Num files:    {num_files:?}\n");
-}
-
-#[allow(dead_code)]
-fn get_blockchain_length(file_name: &OsStr) -> Option<u32> {
-    file_name
-        .to_str()?
-        .split('-')
-        .fold(None, |acc, x| match x.parse::<u32>() {
-            Err(_) => acc,
-            Ok(x) => Some(x),
-        })
-}
-
-#[allow(dead_code)]
-fn sort_no_rename(pattern: &str) -> (usize, String, IntoIter<PathBuf>) {
-    let mut glob_vec: Vec<PathBuf> = glob(pattern)
-        .expect("Failed to read glob pattern")
-        .filter_map(|x| x.ok())
-        .collect();
-    glob_vec.sort_by(|x, y| {
-        get_blockchain_length(x.as_os_str()).cmp(&get_blockchain_length(y.as_os_str()))
-    });
-
-    let size_msg = format!(
-        "Size of vec:  {}",
-        ByteSize::b(size_of_val(&*glob_vec) as u64)
-    );
-    (glob_vec.len(), size_msg, glob_vec.into_iter())
-}
-
-#[allow(dead_code)]
-fn glob_rename_dir(pattern: &str) -> Result<usize, GlobError> {
-    let mut num = 0;
-    for entry in glob(pattern).expect("Failed to read glob pattern") {
-        match entry {
-            Ok(path) => {
-                let new_path = pad_height(path.clone());
-                fs::rename(path, &new_path).unwrap();
-                num += 1;
-            }
-            Err(err) => println!("{err:?}"),
-        }
-    }
-    Ok(num)
-}
-
-#[allow(dead_code)]
-fn pad_height(path: impl AsRef<Path>) -> PathBuf {
-    let path = path.as_ref();
-    let mut result = path.to_owned();
-    let fname = path.file_name().unwrap().to_owned().into_string().unwrap();
-
-    let new_fname = fname
-        .split('-')
-        .enumerate()
-        .fold(String::new(), |acc, (n, part)| {
-            let mut acc = acc;
-            if n == 1 {
-                let height = part.to_string();
-                if height.len() < 6 {
-                    let mut padded = "0".repeat(6 - height.len());
-                    padded.push_str(&height);
-
-                    acc.push('-');
-                    acc.push_str(&padded);
-                    acc.push('-');
-                } else {
-                    acc.push('-');
-                    acc.push_str(part);
-                    acc.push('-');
+    let num_files = match CliArgs::parse().command {
+        Command::Migrate {
+            blocks_dir,
+            layout,
+            dry_run,
+        } => {
+            let migration = Migration::plan(&blocks_dir, layout)?;
+            if dry_run {
+                for mv in &migration.moves {
+                    println!("{} -> {}", mv.from.display(), mv.to.display());
                 }
+                migration.moves.len()
             } else {
-                acc.push_str(part);
-            }
-            acc
-        });
-
-    result.set_file_name(&new_fname);
-    result
-}
-
-#[allow(dead_code)]
-fn glob_unpad_dir(pattern: &str) -> Result<usize, GlobError> {
-    let mut num = 0;
-    for entry in glob(pattern).expect("Failed to read glob pattern") {
-        match entry {
-            Ok(path) => {
-                let new_path = unpad_height(path.clone());
-                fs::rename(path, &new_path).unwrap();
-                num += 1;
+                migration.run()?
             }
-            Err(err) => println!("{err:?}"),
         }
-    }
-    Ok(num)
-}
-
-#[allow(dead_code)]
-fn unpad_height(path: impl AsRef<Path>) -> PathBuf {
-    let path = path.as_ref();
-    let mut result = path.to_owned();
-    let fname = path.file_name().unwrap().to_owned().into_string().unwrap();
-
-    let new_fname = fname
-        .split('-')
-        .enumerate()
-        .fold(String::new(), |acc, (n, part)| {
-            let mut acc = acc;
-            if n == 1 {
-                let height = part.to_string();
-                let height = height.trim_start_matches('0');
-                acc.push('-');
-                acc.push_str(height);
-            } else {
-                if n != 0 {
-                    acc.push('-');
-                }
-                acc.push_str(part);
-            }
-            acc
-        });
-
-    result.set_file_name(&new_fname);
-    result
-}
-
-#[allow(dead_code)]
-fn read_curr_dir() -> io::Result<()> {
-    let mut entries = fs::read_dir(".")?
-        .map(|res| res.map(|e| e.path()))
-        .collect::<Result<Vec<_>, io::Error>>()?;
-
-    // The order in which `read_dir` returns entries is not guaranteed. If reproducible
-    // ordering is required the entries should be explicitly sorted.
-
-    entries.sort();
-    for (n, entry) in entries.iter().enumerate() {
-        println!("{n}: {entry:?}");
-    }
+        Command::Rollback { blocks_dir } => rollback(&blocks_dir)?,
+    };
+    let total = time.elapsed();
 
+    println!("\n~~~ Stats ~~~");
+    println!("Time elapsed: {total:?}");
+    println!("Num files:    {num_files:?}\n");
     Ok(())
 }
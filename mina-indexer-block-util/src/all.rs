@@ -1,11 +1,7 @@
-use crate::common::check_gsutil;
+use crate::block_source::{download, BlockQuery, SourceArgs};
 use clap::Parser;
 use fs::check_dir;
-use log::error;
-use std::{
-    path::PathBuf,
-    process::{Command, Stdio},
-};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug, Clone)]
 pub struct AllArgs {
@@ -15,34 +11,18 @@ pub struct AllArgs {
     /// Name of Mina network
     #[arg(short, long, default_value = "mainnet")]
     network: String,
-    /// Name of GCP bucket
-    #[arg(long, default_value = "mina_network_block_data")]
-    bucket: String,
+    #[command(flatten)]
+    source: SourceArgs,
 }
 
 pub fn main(args: AllArgs) -> anyhow::Result<()> {
     let blocks_dir = args.blocks_dir;
     let network = args.network;
-    let bucket = args.bucket;
 
-    check_dir(&blocks_dir);
-    check_gsutil();
+    check_dir(&blocks_dir)?;
+    let source = args.source.block_source()?;
 
-    let query = format!("gs://{bucket}/{network}-*-*.json");
-    let mut gsutil_cmd = Command::new("gsutil")
-        .arg("-m")
-        .arg("cp")
-        .arg("-n")
-        .arg(&query)
-        .arg(blocks_dir)
-        .stdout(Stdio::piped())
-        .spawn()?;
-
-    match gsutil_cmd.wait() {
-        Ok(_) => Ok(()),
-        Err(err) => {
-            error!("{err}");
-            Err(anyhow::Error::msg(err))
-        }
-    }
+    let report = download(source.as_ref(), &[BlockQuery::all(&network)], &blocks_dir)?;
+    report.log();
+    Ok(())
 }
@@ -0,0 +1,431 @@
+//! Concurrent, rate limited block fetching on tokio
+
+use super::{BlockDownload, BlockQuery, BlockSource, BlockStatus, BlocksDir, SourceError};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    runtime::Runtime,
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    task::JoinSet,
+    time::{sleep, timeout, Instant},
+};
+
+/// A backend the engine fetches single block files from
+pub trait AsyncFetch: Send + Sync + 'static {
+    /// Copy the named block file to its [BlocksDir::block_path]
+    fn fetch(
+        &self,
+        name: String,
+        blocks_dir: BlocksDir,
+    ) -> impl Future<Output = Result<PathBuf, SourceError>> + Send;
+}
+
+/// Fetches from a synchronous source on tokio's blocking thread pool
+///
+/// A timed out fetch is reported as failed, but keeps running in the
+/// background as blocking calls can't be cancelled.
+pub struct Blocking(pub Arc<dyn BlockSource>);
+
+impl AsyncFetch for Blocking {
+    async fn fetch(&self, name: String, blocks_dir: BlocksDir) -> Result<PathBuf, SourceError> {
+        let source = self.0.clone();
+        tokio::task::spawn_blocking(move || source.fetch(&name, &blocks_dir))
+            .await
+            .map_err(|err| SourceError::Io(std::io::Error::other(err)))?
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EngineConfig {
+    /// number of blocks fetched at once
+    pub workers: usize,
+    /// max fetches started per second, if limited
+    pub rate_limit: Option<f64>,
+    /// fetches which can be started at once before the rate limit applies
+    pub burst: u32,
+    /// time allowed for each fetch
+    pub timeout: Duration,
+}
+
+/// Progress of a batch of fetches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchEvent {
+    /// the batch of `total` fetches started
+    Queued {
+        total: usize,
+    },
+    Started {
+        name: String,
+    },
+    Downloaded {
+        name: String,
+    },
+    Failed {
+        name: String,
+        error: String,
+    },
+}
+
+/// Fetch the named block files into `blocks_dir` with a pool of workers
+///
+/// Each fetch waits for a rate limit token and is given up on after the
+/// timeout. Progress is sent to `events`, if given. The downloads are returned
+/// in the order of `names`.
+pub async fn fetch_all<F: AsyncFetch>(
+    fetcher: Arc<F>,
+    names: &[String],
+    blocks_dir: &BlocksDir,
+    config: &EngineConfig,
+    events: Option<UnboundedSender<FetchEvent>>,
+) -> Vec<BlockDownload> {
+    let send = move |event| {
+        if let Some(events) = &events {
+            // nobody listening is fine
+            let _ = events.send(event);
+        }
+    };
+    send(FetchEvent::Queued { total: names.len() });
+
+    let queue = Arc::new(Mutex::new(
+        names.iter().cloned().enumerate().collect::<VecDeque<_>>(),
+    ));
+    let limiter = config
+        .rate_limit
+        .map(|rate| Arc::new(TokenBucket::new(rate, config.burst)));
+
+    let mut workers = JoinSet::new();
+    for _ in 0..config.workers.max(1) {
+        let (fetcher, queue, limiter, send) = (
+            fetcher.clone(),
+            queue.clone(),
+            limiter.clone(),
+            send.clone(),
+        );
+        let (blocks_dir, fetch_timeout) = (blocks_dir.clone(), config.timeout);
+        workers.spawn(async move {
+            let mut done = vec![];
+            loop {
+                let Some((i, name)) = queue.lock().unwrap().pop_front() else {
+                    return done;
+                };
+                if let Some(limiter) = &limiter {
+                    limiter.acquire().await;
+                }
+
+                send(FetchEvent::Started { name: name.clone() });
+                let res = timeout(
+                    fetch_timeout,
+                    fetcher.fetch(name.clone(), blocks_dir.clone()),
+                )
+                .await
+                .unwrap_or(Err(SourceError::Timeout));
+                let status = match res {
+                    Ok(path) => {
+                        send(FetchEvent::Downloaded { name: name.clone() });
+                        BlockStatus::Downloaded(path)
+                    }
+                    Err(err) => {
+                        send(FetchEvent::Failed {
+                            name: name.clone(),
+                            error: err.to_string(),
+                        });
+                        BlockStatus::Failed(err)
+                    }
+                };
+                done.push((i, BlockDownload { name, status }));
+            }
+        });
+    }
+
+    let mut done = vec![];
+    while let Some(res) = workers.join_next().await {
+        done.extend(res.expect("fetch worker panicked"));
+    }
+    done.sort_by_key(|(i, _)| *i);
+    done.into_iter().map(|(_, download)| download).collect()
+}
+
+/// Limits the rate of fetches to `rate` per second, allowing bursts of up to
+/// `burst` fetches after idling
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    /// tokens left and when they were counted
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        assert!(
+            rate.is_finite() && rate > 0.0,
+            "rate limit must be positive, not {rate}"
+        );
+        let capacity = burst.max(1) as f64;
+        Self {
+            rate,
+            capacity,
+            tokens: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    /// Wait for a token and take it
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut tokens = self.tokens.lock().unwrap();
+                let now = Instant::now();
+                let refilled = tokens.0 + now.duration_since(tokens.1).as_secs_f64() * self.rate;
+                *tokens = (refilled.min(self.capacity), now);
+                if tokens.0 >= 1.0 {
+                    tokens.0 -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - tokens.0) / self.rate)
+            };
+            sleep(wait).await;
+        }
+    }
+}
+
+/// A source whose blocks are fetched by the engine, with a progress bar
+pub struct EngineSource<F> {
+    source: Arc<dyn BlockSource>,
+    fetcher: Arc<F>,
+    config: EngineConfig,
+    runtime: Runtime,
+}
+
+impl<F: AsyncFetch> EngineSource<F> {
+    /// Blocks are listed by `source` and fetched by `fetcher`
+    pub fn new(
+        source: Arc<dyn BlockSource>,
+        fetcher: F,
+        config: EngineConfig,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            source,
+            fetcher: Arc::new(fetcher),
+            config,
+            runtime: Runtime::new()?,
+        })
+    }
+}
+
+impl EngineSource<Blocking> {
+    /// Blocks are listed and fetched by `source`, concurrently
+    pub fn blocking(source: Box<dyn BlockSource>, config: EngineConfig) -> std::io::Result<Self> {
+        let source: Arc<dyn BlockSource> = source.into();
+        Self::new(source.clone(), Blocking(source), config)
+    }
+}
+
+impl<F: AsyncFetch> BlockSource for EngineSource<F> {
+    fn list(&self, queries: &[BlockQuery]) -> Result<Vec<String>, SourceError> {
+        self.source.list(queries)
+    }
+
+    fn fetch(&self, name: &str, blocks_dir: &BlocksDir) -> Result<PathBuf, SourceError> {
+        self.runtime.block_on(async {
+            timeout(
+                self.config.timeout,
+                self.fetcher.fetch(name.to_string(), blocks_dir.clone()),
+            )
+            .await
+            .unwrap_or(Err(SourceError::Timeout))
+        })
+    }
+
+    fn fetch_all(&self, names: &[String], blocks_dir: &BlocksDir) -> Vec<BlockDownload> {
+        let (events, mut progress_events) = unbounded_channel();
+        let progress = self.runtime.spawn(async move {
+            let bar = ProgressBar::new(0).with_style(
+                ProgressStyle::with_template(
+                    "{bar:40} {pos}/{len} blocks, {per_sec}, eta {eta} {msg}",
+                )
+                .expect("valid progress template"),
+            );
+            while let Some(event) = progress_events.recv().await {
+                match event {
+                    FetchEvent::Queued { total } => bar.inc_length(total as u64),
+                    FetchEvent::Started { name } => bar.set_message(name),
+                    FetchEvent::Downloaded { .. } | FetchEvent::Failed { .. } => bar.inc(1),
+                }
+            }
+            bar.finish_and_clear();
+        });
+
+        let downloads = self.runtime.block_on(fetch_all(
+            self.fetcher.clone(),
+            names,
+            blocks_dir,
+            &self.config,
+            Some(events),
+        ));
+        // the sender is dropped, so the bar finishes
+        let _ = self.runtime.block_on(progress);
+        downloads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_source::http::AsyncHttpFetch;
+    use fs::layout::Layout;
+    use std::{collections::HashMap, fs::read_to_string};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::mpsc::UnboundedReceiver,
+    };
+
+    /// Serves `/bucket/{name}` from `blocks`, stalling for a second before the
+    /// body of "slow" ones
+    async fn http_stub(blocks: HashMap<String, String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let blocks = Arc::new(blocks);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let blocks = blocks.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut request_line = String::new();
+                    stream.read_line(&mut request_line).await.unwrap();
+                    let mut header = String::new();
+                    while stream.read_line(&mut header).await.unwrap() > 2 {
+                        header.clear();
+                    }
+
+                    let path = request_line.split(' ').nth(1).unwrap_or_default();
+                    let name = path.trim_start_matches("/bucket/");
+                    let stream = stream.get_mut();
+                    match blocks.get(name) {
+                        Some(body) => {
+                            let head = format!(
+                                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                                body.len()
+                            );
+                            let _ = stream.write_all(head.as_bytes()).await;
+                            let _ = stream.flush().await;
+                            if name.contains("slow") {
+                                sleep(Duration::from_secs(1)).await;
+                            }
+                            let _ = stream.write_all(body.as_bytes()).await;
+                        }
+                        None => {
+                            let response = "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+                            let _ = stream.write_all(response.as_bytes()).await;
+                        }
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    fn drain(events: &mut UnboundedReceiver<FetchEvent>) -> Vec<FetchEvent> {
+        std::iter::from_fn(|| events.try_recv().ok()).collect()
+    }
+
+    #[tokio::test]
+    async fn fetch_from_http_stub() {
+        let blocks: HashMap<String, String> = (2..8)
+            .map(|length| {
+                (
+                    format!("mainnet-{length}-3N.json"),
+                    format!("{{\"{length}\":0}}"),
+                )
+            })
+            .chain([("mainnet-8-slow.json".to_string(), "{\"8\":0}".to_string())])
+            .collect();
+        let url = http_stub(blocks.clone()).await;
+        let blocks_dir = tempfile::tempdir().unwrap();
+
+        let mut names: Vec<String> = (2..8).map(|l| format!("mainnet-{l}-3N.json")).collect();
+        names.push("mainnet-8-slow.json".to_string());
+        names.push("mainnet-9-missing.json".to_string());
+        let config = EngineConfig {
+            workers: 3,
+            rate_limit: None,
+            burst: 1,
+            timeout: Duration::from_millis(300),
+        };
+        let (events, mut received) = unbounded_channel();
+        let downloads = fetch_all(
+            Arc::new(AsyncHttpFetch::new(&url, "bucket")),
+            &names,
+            &BlocksDir::new(blocks_dir.path(), Layout::Flat),
+            &config,
+            Some(events),
+        )
+        .await;
+
+        assert_eq!(
+            downloads.iter().map(|d| &d.name).collect::<Vec<_>>(),
+            names.iter().collect::<Vec<_>>()
+        );
+        for download in &downloads[..6] {
+            let BlockStatus::Downloaded(path) = &download.status else {
+                panic!("{download:?}");
+            };
+            assert_eq!(read_to_string(path).unwrap(), blocks[&download.name]);
+        }
+        assert!(matches!(
+            downloads[6].status,
+            BlockStatus::Failed(SourceError::Timeout)
+        ));
+        assert!(matches!(
+            downloads[7].status,
+            BlockStatus::Failed(SourceError::Request(_))
+        ));
+        assert!(!blocks_dir.path().join("mainnet-9-missing.json").exists());
+        // the timed out fetch leaves no partial file
+        assert_eq!(
+            std::fs::read_dir(blocks_dir.path()).unwrap().count(),
+            6,
+            "{:?}",
+            std::fs::read_dir(blocks_dir.path())
+                .unwrap()
+                .collect::<Vec<_>>()
+        );
+
+        let events = drain(&mut received);
+        assert_eq!(events[0], FetchEvent::Queued { total: 8 });
+        let count = |f: fn(&FetchEvent) -> bool| events.iter().filter(|e| f(e)).count();
+        assert_eq!(count(|e| matches!(e, FetchEvent::Started { .. })), 8);
+        assert_eq!(count(|e| matches!(e, FetchEvent::Downloaded { .. })), 6);
+        assert_eq!(count(|e| matches!(e, FetchEvent::Failed { .. })), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit() {
+        let bucket = TokenBucket::new(2.0, 3);
+        let start = Instant::now();
+
+        // the burst is free, then 2 per sec
+        for _ in 0..3 {
+            bucket.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        for _ in 0..4 {
+            bucket.acquire().await;
+        }
+        assert_eq!(start.elapsed().as_millis(), 2000);
+
+        // idling refills up to the burst only
+        sleep(Duration::from_secs(10)).await;
+        let start = Instant::now();
+        for _ in 0..4 {
+            bucket.acquire().await;
+        }
+        assert_eq!(start.elapsed().as_millis(), 500);
+    }
+}
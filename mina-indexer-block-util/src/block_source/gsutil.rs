@@ -0,0 +1,150 @@
+use super::{BlockDownload, BlockQuery, BlockSource, BlockStatus, BlocksDir, SourceError};
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+/// Number of URLs given to a single `gsutil ls`
+const LS_CHUNK_SIZE: usize = 100;
+
+/// A GCP bucket, through the gsutil CLI
+pub struct GsutilSource {
+    bucket: String,
+}
+
+impl GsutilSource {
+    pub fn new(bucket: &str) -> Self {
+        Self {
+            bucket: bucket.to_string(),
+        }
+    }
+
+    fn uri(&self, name: impl std::fmt::Display) -> String {
+        format!("gs://{}/{name}", self.bucket)
+    }
+
+    /// Copy the named block files into `dir` with one parallel transfer
+    fn copy_all<'a>(
+        &self,
+        names: impl Iterator<Item = &'a String>,
+        dir: &Path,
+    ) -> io::Result<Output> {
+        Command::new("gsutil")
+            .arg("-m")
+            .arg("cp")
+            .arg("-n")
+            .arg("-I")
+            .arg(dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                let mut stdin = child.stdin.take().unwrap();
+                for name in names {
+                    writeln!(stdin, "{}", self.uri(name))?;
+                }
+                drop(stdin);
+                child.wait_with_output()
+            })
+    }
+}
+
+impl BlockSource for GsutilSource {
+    fn list(&self, queries: &[BlockQuery]) -> Result<Vec<String>, SourceError> {
+        let mut names = vec![];
+        for chunk in queries.chunks(LS_CHUNK_SIZE) {
+            let output = Command::new("gsutil")
+                .arg("ls")
+                .args(chunk.iter().map(|q| self.uri(q)))
+                .output()?;
+
+            // URLs matching nothing fail the command but don't affect the others
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if !output.status.success() && !stderr.contains("matched no objects") {
+                return Err(SourceError::Command(stderr.into_owned()));
+            }
+            names.extend(
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .filter_map(|uri| uri.rsplit('/').next())
+                    .filter(|name| chunk.iter().any(|q| q.matches(name)))
+                    .map(str::to_string),
+            );
+        }
+        Ok(names)
+    }
+
+    fn fetch(&self, name: &str, blocks_dir: &BlocksDir) -> Result<PathBuf, SourceError> {
+        let path = blocks_dir.block_path(name)?;
+        let output = Command::new("gsutil")
+            .arg("cp")
+            .arg("-n")
+            .arg(self.uri(name))
+            .arg(&path)
+            .output()?;
+        copied(path, &output)
+    }
+
+    /// A parallel `gsutil -m cp -n -I` transfer per destination dir
+    ///
+    /// Blocks renamed by the layout, e.g. padded ones, are copied one by one
+    fn fetch_all(&self, names: &[String], blocks_dir: &BlocksDir) -> Vec<BlockDownload> {
+        let mut statuses: Vec<Option<BlockStatus>> = names.iter().map(|_| None).collect();
+        let mut batches: BTreeMap<PathBuf, Vec<usize>> = BTreeMap::new();
+        for (i, name) in names.iter().enumerate() {
+            match blocks_dir.block_path(name) {
+                Ok(path) if path.file_name() == Some(OsStr::new(name)) => {
+                    let dir = path.parent().unwrap_or(&blocks_dir.path).to_path_buf();
+                    batches.entry(dir).or_default().push(i);
+                }
+                Ok(_) => statuses[i] = Some(status(self.fetch(name, blocks_dir))),
+                Err(err) => statuses[i] = Some(BlockStatus::Failed(err.into())),
+            }
+        }
+
+        for (dir, batch) in batches {
+            let output = self.copy_all(batch.iter().map(|&i| &names[i]), &dir);
+            for i in batch {
+                statuses[i] = Some(status(
+                    output
+                        .as_ref()
+                        .map_err(|err| SourceError::Command(err.to_string()))
+                        .and_then(|output| copied(dir.join(&names[i]), output)),
+                ));
+            }
+        }
+
+        names
+            .iter()
+            .zip(statuses)
+            .map(|(name, status)| BlockDownload {
+                name: name.clone(),
+                status: status.expect("every block is fetched"),
+            })
+            .collect()
+    }
+}
+
+fn status(res: Result<PathBuf, SourceError>) -> BlockStatus {
+    match res {
+        Ok(path) => BlockStatus::Downloaded(path),
+        Err(err) => BlockStatus::Failed(err),
+    }
+}
+
+/// gsutil doesn't report per file, so check the block file is there
+fn copied(path: PathBuf, output: &Output) -> Result<PathBuf, SourceError> {
+    if path.exists() {
+        Ok(path)
+    } else if !output.status.success() {
+        Err(SourceError::Command(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ))
+    } else {
+        Err(SourceError::NotCopied)
+    }
+}
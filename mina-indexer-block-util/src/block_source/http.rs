@@ -0,0 +1,118 @@
+use super::{
+    engine::AsyncFetch, write_block, BlockQuery, BlockSource, BlocksDir, PartFile, SourceError,
+};
+use serde::Deserialize;
+use std::path::PathBuf;
+use tokio::{fs, io::AsyncWriteExt};
+
+pub const GCS_URL: &str = "https://storage.googleapis.com";
+
+/// A public bucket, listed via its JSON API and downloaded over plain HTTP
+///
+/// Objects are listed from `{url}/storage/v1/b/{bucket}/o` and downloaded
+/// from `{url}/{bucket}/{name}`, like Google Cloud Storage
+pub struct HttpSource {
+    url: String,
+    bucket: String,
+    agent: ureq::Agent,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListPage {
+    #[serde(default)]
+    items: Vec<ListItem>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListItem {
+    name: String,
+}
+
+impl HttpSource {
+    pub fn new(url: &str, bucket: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            bucket: bucket.to_string(),
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, SourceError> {
+        let url = format!("{}/storage/v1/b/{}/o", self.url, self.bucket);
+        let mut names = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut request = self
+                .agent
+                .get(&url)
+                .query("prefix", prefix)
+                .query("fields", "items(name),nextPageToken");
+            if let Some(page_token) = &page_token {
+                request = request.query("pageToken", page_token);
+            }
+
+            let page: ListPage = serde_json::from_reader(request.call()?.into_reader())?;
+            names.extend(page.items.into_iter().map(|item| item.name));
+            match page.next_page_token {
+                Some(next) => page_token = Some(next),
+                None => return Ok(names),
+            }
+        }
+    }
+}
+
+impl BlockSource for HttpSource {
+    fn list(&self, queries: &[BlockQuery]) -> Result<Vec<String>, SourceError> {
+        let mut names = vec![];
+        for query in queries {
+            names.extend(
+                self.list_prefix(&query.prefix())?
+                    .into_iter()
+                    .filter(|name| query.matches(name)),
+            );
+        }
+        Ok(names)
+    }
+
+    fn fetch(&self, name: &str, blocks_dir: &BlocksDir) -> Result<PathBuf, SourceError> {
+        let url = format!("{}/{}/{name}", self.url, self.bucket);
+        write_block(blocks_dir, name, self.agent.get(&url).call()?.into_reader())
+    }
+}
+
+/// Downloads block files from `{url}/{bucket}/{name}` for the async engine
+pub struct AsyncHttpFetch {
+    url: String,
+    bucket: String,
+    client: reqwest::Client,
+}
+
+impl AsyncHttpFetch {
+    pub fn new(url: &str, bucket: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            bucket: bucket.to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl AsyncFetch for AsyncHttpFetch {
+    /// Streams the block into a partial file, renamed once complete
+    async fn fetch(&self, name: String, blocks_dir: BlocksDir) -> Result<PathBuf, SourceError> {
+        let url = format!("{}/{}/{name}", self.url, self.bucket);
+        let mut response = self.client.get(url).send().await?.error_for_status()?;
+
+        let path = blocks_dir.block_path(&name)?;
+        let part = PartFile::new(&path);
+        let mut file = fs::File::create(&part.0).await?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        fs::rename(&part.0, &path).await?;
+        Ok(path)
+    }
+}
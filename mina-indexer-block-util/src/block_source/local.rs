@@ -0,0 +1,37 @@
+use super::{write_block, BlockQuery, BlockSource, BlocksDir, SourceError};
+use glob::glob;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+/// A local directory mirroring a bucket, e.g. a test fixture
+pub struct LocalSource {
+    dir: PathBuf,
+}
+
+impl LocalSource {
+    pub fn new(dir: &Path) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl BlockSource for LocalSource {
+    fn list(&self, queries: &[BlockQuery]) -> Result<Vec<String>, SourceError> {
+        let mut names = vec![];
+        for query in queries {
+            let pattern = format!("{}/{query}", self.dir.display());
+            for path in glob(&pattern).expect("valid glob pattern").flatten() {
+                let name = path.file_name().unwrap().to_string_lossy();
+                if query.matches(&name) {
+                    names.push(name.into_owned());
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn fetch(&self, name: &str, blocks_dir: &BlocksDir) -> Result<PathBuf, SourceError> {
+        write_block(blocks_dir, name, File::open(self.dir.join(name))?)
+    }
+}
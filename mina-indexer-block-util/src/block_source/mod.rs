@@ -0,0 +1,676 @@
+//! Where blocks are downloaded from
+
+pub mod engine;
+pub mod gsutil;
+pub mod http;
+pub mod local;
+
+use crate::{common::check_gsutil, verify::VerifyingSource};
+use blockchain::archive::{segments, ArchiveError};
+use clap::{Args, ValueEnum};
+use engine::{EngineConfig, EngineSource};
+use fs::{
+    layout::{block_paths, BlockName, Layout},
+    CheckError, DirCheck,
+};
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{create_dir_all, rename, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// A bucket of `{network}-{length}-{state_hash}.json` block files
+pub trait BlockSource: Send + Sync {
+    /// Names of the block files matching any of the queries
+    fn list(&self, queries: &[BlockQuery]) -> Result<Vec<String>, SourceError>;
+
+    /// Copy the named block file to its [BlocksDir::block_path]
+    fn fetch(&self, name: &str, blocks_dir: &BlocksDir) -> Result<PathBuf, SourceError>;
+
+    /// Copy the named block files to their [BlocksDir::block_path]s
+    ///
+    /// Sources with bulk transfers should override this
+    fn fetch_all(&self, names: &[String], blocks_dir: &BlocksDir) -> Vec<BlockDownload> {
+        names
+            .iter()
+            .map(|name| BlockDownload {
+                name: name.clone(),
+                status: match self.fetch(name, blocks_dir) {
+                    Ok(path) => BlockStatus::Downloaded(path),
+                    Err(err) => BlockStatus::Failed(err),
+                },
+            })
+            .collect()
+    }
+}
+
+/// A blocks dir and the layout block files are written in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlocksDir {
+    pub path: PathBuf,
+    pub layout: Layout,
+}
+
+/// Block files of a network, optionally of a given length and state hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockQuery {
+    pub network: String,
+    pub length: Option<u32>,
+    pub state_hash: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct BlockDownload {
+    /// block file name
+    pub name: String,
+    pub status: BlockStatus,
+}
+
+#[derive(Debug)]
+pub enum BlockStatus {
+    Downloaded(PathBuf),
+    /// already in the blocks dir, or packed into one of its segments, not
+    /// downloaded again
+    Exists(PathBuf),
+    Failed(SourceError),
+}
+
+/// Outcome of each block matching a download's queries
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+    pub blocks: Vec<BlockDownload>,
+    /// queries matching no blocks
+    pub not_found: Vec<BlockQuery>,
+}
+
+#[derive(Debug)]
+pub enum SourceError {
+    Io(io::Error),
+    Http(Box<ureq::Error>),
+    /// an async HTTP request failed
+    Request(reqwest::Error),
+    /// the fetch took longer than the engine's timeout
+    Timeout,
+    Json(serde_json::Error),
+    /// a command exited unsuccessfully, with its stderr
+    Command(String),
+    /// the block file is not in the blocks dir after its transfer
+    NotCopied,
+    /// the block file failed verification and was quarantined
+    Invalid(String),
+    /// the blocks dir can't take the blocks
+    Check(CheckError),
+    /// the segments of packed blocks can't be read
+    Archive(ArchiveError),
+}
+
+/// A generous estimate of a block file's size, to check for free space
+pub const ESTIMATED_BLOCK_SIZE: u64 = 1 << 20;
+
+/// Download all blocks matching `queries` which aren't already in `blocks_dir`
+pub fn download(
+    source: &dyn BlockSource,
+    queries: &[BlockQuery],
+    blocks_dir: &Path,
+) -> Result<DownloadReport, SourceError> {
+    let mut names = source.list(queries)?;
+    names.sort();
+    names.dedup();
+
+    let not_found = queries
+        .iter()
+        .filter(|q| !names.iter().any(|name| q.matches(name)))
+        .cloned()
+        .collect();
+
+    // blocks are found and written in the layout of the blocks dir
+    let paths = block_paths(blocks_dir);
+    let blocks_dir = BlocksDir::new(blocks_dir, Layout::of_paths(blocks_dir, &paths));
+    let mut existing: HashMap<BlockName, PathBuf> = paths
+        .into_iter()
+        .filter_map(|path| Some((BlockName::from_path(&path)?, path)))
+        .collect();
+    for segment in segments(&blocks_dir.path)? {
+        for entry in segment.header.entries.values() {
+            if let Some(block) = BlockName::parse(&entry.name) {
+                existing
+                    .entry(block)
+                    .or_insert_with(|| segment.block_path(entry));
+            }
+        }
+    }
+
+    let mut blocks = vec![];
+    let mut to_fetch = vec![];
+    for name in names {
+        let path = match BlockName::parse(&name) {
+            Some(block) => existing.remove(&block),
+            None => Some(blocks_dir.path.join(&name)).filter(|path| path.exists()),
+        };
+        match path {
+            Some(path) => blocks.push(BlockDownload {
+                name,
+                status: BlockStatus::Exists(path),
+            }),
+            None => to_fetch.push(name),
+        }
+    }
+    if !to_fetch.is_empty() {
+        DirCheck::new()
+            .writable()
+            .free_space(to_fetch.len() as u64 * ESTIMATED_BLOCK_SIZE)
+            .check(&blocks_dir.path)?;
+    }
+    blocks.extend(source.fetch_all(&to_fetch, &blocks_dir));
+
+    Ok(DownloadReport { blocks, not_found })
+}
+
+impl BlocksDir {
+    pub fn new(path: &Path, layout: Layout) -> Self {
+        Self {
+            path: path.into(),
+            layout,
+        }
+    }
+
+    /// Where the named block file goes, creating its shard dir if missing
+    pub fn block_path(&self, name: &str) -> io::Result<PathBuf> {
+        let Some(block) = BlockName::parse(name) else {
+            return Ok(self.path.join(name));
+        };
+        let path = self.path.join(self.layout.relative_path(&block));
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        Ok(path)
+    }
+}
+
+impl BlockQuery {
+    pub fn all(network: &str) -> Self {
+        Self {
+            network: network.to_string(),
+            length: None,
+            state_hash: None,
+        }
+    }
+
+    pub fn length(network: &str, length: u32) -> Self {
+        Self {
+            length: Some(length),
+            ..Self::all(network)
+        }
+    }
+
+    pub fn block(network: &str, length: u32, state_hash: &str) -> Self {
+        Self {
+            state_hash: Some(state_hash.to_string()),
+            ..Self::length(network, length)
+        }
+    }
+
+    /// Longest object name prefix shared by all matching block files
+    pub fn prefix(&self) -> String {
+        match (self.length, &self.state_hash) {
+            (None, _) => format!("{}-", self.network),
+            (Some(length), None) => format!("{}-{length}-", self.network),
+            (Some(_), Some(_)) => self.to_string(),
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let Some((length, state_hash)) = name
+            .strip_prefix(&self.network)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .and_then(|rest| rest.strip_suffix(".json"))
+            .and_then(|rest| rest.split_once('-'))
+        else {
+            return false;
+        };
+        let Ok(length) = length.parse::<u32>() else {
+            return false;
+        };
+
+        !state_hash.is_empty()
+            && self.length.is_none_or(|l| l == length)
+            && self.state_hash.as_ref().is_none_or(|h| h == state_hash)
+    }
+}
+
+/// The glob pattern of the matching block files, e.g. `mainnet-5-*.json`
+impl fmt::Display for BlockQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-", self.network)?;
+        match self.length {
+            Some(length) => write!(f, "{length}-")?,
+            None => write!(f, "*-")?,
+        }
+        match &self.state_hash {
+            Some(state_hash) => write!(f, "{state_hash}.json"),
+            None => write!(f, "*.json"),
+        }
+    }
+}
+
+impl DownloadReport {
+    pub fn downloaded(&self) -> impl Iterator<Item = &PathBuf> {
+        self.blocks.iter().filter_map(|b| match &b.status {
+            BlockStatus::Downloaded(path) => Some(path),
+            _ => None,
+        })
+    }
+
+    pub fn existing(&self) -> impl Iterator<Item = &PathBuf> {
+        self.blocks.iter().filter_map(|b| match &b.status {
+            BlockStatus::Exists(path) => Some(path),
+            _ => None,
+        })
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = (&str, &SourceError)> {
+        self.blocks.iter().filter_map(|b| match &b.status {
+            BlockStatus::Failed(err) => Some((b.name.as_str(), err)),
+            _ => None,
+        })
+    }
+
+    /// Print the downloaded blocks, log failures and a summary
+    pub fn log(&self) {
+        self.log_to(module_path!())
+    }
+
+    /// Log with the given log target, e.g. a network name
+    pub fn log_to(&self, target: &str) {
+        for path in self.downloaded() {
+            println!("Downloaded {}", path.display());
+        }
+        for (name, err) in self.failed() {
+            warn!(target: target, "Failed to download {name}: {err}");
+        }
+        info!(
+            target: target,
+            "{} downloaded, {} already present, {} failed, {} queries matched nothing",
+            self.downloaded().count(),
+            self.existing().count(),
+            self.failed().count(),
+            self.not_found.len(),
+        );
+    }
+}
+
+/// Write a block file atomically, so an interrupted transfer leaves no partial block
+pub(crate) fn write_block(
+    blocks_dir: &BlocksDir,
+    name: &str,
+    mut contents: impl Read,
+) -> Result<PathBuf, SourceError> {
+    let path = blocks_dir.block_path(name)?;
+    let part = PartFile::new(&path);
+    io::copy(&mut contents, &mut File::create(&part.0)?)?;
+    rename(&part.0, &path)?;
+    Ok(path)
+}
+
+/// The partial file a block file is written to, removed when dropped, i.e.
+/// unless it was renamed to the block file
+///
+/// This also cleans up after fetches cancelled by the engine's timeout
+pub(crate) struct PartFile(pub(crate) PathBuf);
+
+impl PartFile {
+    pub(crate) fn new(path: &Path) -> Self {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        Self(path.with_file_name(format!(".{file_name}.part")))
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    /// Shell out to gsutil
+    Gsutil,
+    /// Use the bucket's HTTP JSON API
+    Http,
+    /// Copy from a local directory mirroring the bucket
+    Local,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SourceArgs {
+    /// Where to download blocks from
+    #[arg(long, value_enum, default_value_t = SourceKind::Gsutil)]
+    pub source: SourceKind,
+    /// Name of GCP bucket
+    #[arg(long, default_value = "mina_network_block_data")]
+    pub bucket: String,
+    /// Base URL of the bucket's HTTP API, for the http source
+    #[arg(long, default_value = http::GCS_URL)]
+    pub http_url: String,
+    /// Directory mirroring the bucket, for the local source
+    #[arg(long)]
+    pub mirror_dir: Option<PathBuf>,
+    /// Verify each downloaded block, moving bad ones into `<blocks-dir>/.quarantine`
+    #[arg(long, default_value_t = false)]
+    pub verify: bool,
+    /// Fetch this many blocks at once with the async engine
+    #[arg(long)]
+    pub workers: Option<usize>,
+    /// Max blocks fetched per second by the async engine
+    #[arg(long, value_parser = parse_rate)]
+    pub rate_limit: Option<f64>,
+    /// Blocks the async engine fetches at once before the rate limit applies
+    #[arg(long, default_value_t = 1)]
+    pub burst: u32,
+    /// Time (in sec) the async engine allows each block fetch
+    #[arg(long, default_value_t = 60)]
+    pub timeout: u64,
+}
+
+/// A rate must be a positive number of blocks per second
+fn parse_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        Ok(_) => Err("must be positive".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+impl SourceArgs {
+    pub fn block_source(&self) -> anyhow::Result<Box<dyn BlockSource>> {
+        let mut source: Box<dyn BlockSource> = match self.source {
+            SourceKind::Gsutil => {
+                check_gsutil();
+                Box::new(gsutil::GsutilSource::new(&self.bucket))
+            }
+            SourceKind::Http => Box::new(http::HttpSource::new(&self.http_url, &self.bucket)),
+            SourceKind::Local => match &self.mirror_dir {
+                Some(mirror_dir) => Box::new(local::LocalSource::new(mirror_dir)),
+                None => anyhow::bail!("`--source local` requires `--mirror-dir`"),
+            },
+        };
+
+        if let Some(workers) = self.workers {
+            let config = EngineConfig {
+                workers,
+                rate_limit: self.rate_limit,
+                burst: self.burst,
+                timeout: Duration::from_secs(self.timeout),
+            };
+            source = match self.source {
+                SourceKind::Http => Box::new(EngineSource::new(
+                    source.into(),
+                    http::AsyncHttpFetch::new(&self.http_url, &self.bucket),
+                    config,
+                )?),
+                _ => Box::new(EngineSource::blocking(source, config)?),
+            };
+        }
+
+        if self.verify {
+            Ok(Box::new(VerifyingSource::with_default_quarantine(source)))
+        } else {
+            Ok(source)
+        }
+    }
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Http(err) => write!(f, "{err}"),
+            Self::Request(err) => write!(f, "{err}"),
+            Self::Timeout => write!(f, "timed out"),
+            Self::Json(err) => write!(f, "malformed bucket listing: {err}"),
+            Self::Command(stderr) => write!(f, "command failed: {stderr}"),
+            Self::NotCopied => write!(f, "block was not copied"),
+            Self::Invalid(err) => write!(f, "invalid block, quarantined: {err}"),
+            Self::Check(err) => write!(f, "{err}"),
+            Self::Archive(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl From<io::Error> for SourceError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<ureq::Error> for SourceError {
+    fn from(err: ureq::Error) -> Self {
+        Self::Http(Box::new(err))
+    }
+}
+
+impl From<CheckError> for SourceError {
+    fn from(err: CheckError) -> Self {
+        Self::Check(err)
+    }
+}
+
+impl From<ArchiveError> for SourceError {
+    fn from(err: ArchiveError) -> Self {
+        Self::Archive(err)
+    }
+}
+
+impl From<reqwest::Error> for SourceError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Request(err)
+    }
+}
+
+impl From<serde_json::Error> for SourceError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_to_string, write};
+
+    const HASH: &str = "3NKeMoncuHab5ScarV5ViyF16cJPT4taWNSaTLS64Dp67wuXigPZ";
+
+    #[test]
+    fn queries() {
+        let name = format!("mainnet-5-{HASH}.json");
+        assert_eq!(BlockQuery::all("mainnet").to_string(), "mainnet-*-*.json");
+        assert_eq!(BlockQuery::length("mainnet", 5).prefix(), "mainnet-5-");
+        assert_eq!(BlockQuery::block("mainnet", 5, HASH).prefix(), name);
+
+        assert!(BlockQuery::all("mainnet").matches(&name));
+        assert!(BlockQuery::length("mainnet", 5).matches(&name));
+        assert!(BlockQuery::block("mainnet", 5, HASH).matches(&name));
+        assert!(!BlockQuery::length("mainnet", 50).matches(&name));
+        assert!(!BlockQuery::all("devnet").matches(&name));
+        assert!(!BlockQuery::all("mainnet").matches("mainnet-5.json"));
+    }
+
+    #[test]
+    fn download_from_local_mirror() {
+        let mirror = tempfile::tempdir().unwrap();
+        let blocks_dir = tempfile::tempdir().unwrap();
+        for name in [
+            format!("mainnet-2-{HASH}.json"),
+            "mainnet-3-3Na.json".to_string(),
+            "mainnet-3-3Nb.json".to_string(),
+            "devnet-3-3Nc.json".to_string(),
+        ] {
+            write(mirror.path().join(name), "{}").unwrap();
+        }
+        write(blocks_dir.path().join("mainnet-3-3Na.json"), "{}").unwrap();
+
+        let source = local::LocalSource::new(mirror.path());
+        let queries = [
+            BlockQuery::block("mainnet", 2, HASH),
+            BlockQuery::length("mainnet", 3),
+            BlockQuery::length("mainnet", 4),
+        ];
+        let report = download(&source, &queries, blocks_dir.path()).unwrap();
+
+        assert_eq!(report.not_found, vec![BlockQuery::length("mainnet", 4)]);
+        assert_eq!(report.failed().count(), 0);
+        assert_eq!(
+            report.downloaded().collect::<Vec<_>>(),
+            vec![
+                &blocks_dir.path().join(format!("mainnet-2-{HASH}.json")),
+                &blocks_dir.path().join("mainnet-3-3Nb.json"),
+            ]
+        );
+        assert!(matches!(
+            report
+                .blocks
+                .iter()
+                .find(|b| b.name == "mainnet-3-3Na.json")
+                .unwrap()
+                .status,
+            BlockStatus::Exists(_)
+        ));
+        assert_eq!(
+            read_to_string(blocks_dir.path().join("mainnet-3-3Nb.json")).unwrap(),
+            "{}"
+        );
+        assert!(!blocks_dir.path().join("devnet-3-3Nc.json").exists());
+
+        // failures are reported per block
+        let missing = source.fetch_all(
+            &["mainnet-9-3Nz.json".to_string()],
+            &BlocksDir::new(blocks_dir.path(), Layout::Flat),
+        );
+        assert!(matches!(missing[0].status, BlockStatus::Failed(_)));
+    }
+
+    #[test]
+    fn download_in_layout() {
+        let mirror = tempfile::tempdir().unwrap();
+        for name in [
+            "mainnet-2-3Na.json",
+            "mainnet-1500-3Na.json",
+            "mainnet-1500-3Nb.json",
+        ] {
+            write(mirror.path().join(name), "{}").unwrap();
+        }
+        let source = local::LocalSource::new(mirror.path());
+        let queries = [
+            BlockQuery::length("mainnet", 2),
+            BlockQuery::length("mainnet", 1500),
+        ];
+
+        // found in their shards, new blocks go into missing shards
+        let sharded = tempfile::tempdir().unwrap();
+        create_dir_all(sharded.path().join("1")).unwrap();
+        write(sharded.path().join("1/mainnet-1500-3Na.json"), "{}").unwrap();
+        let report = download(&source, &queries, sharded.path()).unwrap();
+        assert_eq!(
+            report.existing().collect::<Vec<_>>(),
+            vec![&sharded.path().join("1/mainnet-1500-3Na.json")]
+        );
+        assert_eq!(
+            report.downloaded().collect::<Vec<_>>(),
+            vec![
+                &sharded.path().join("1/mainnet-1500-3Nb.json"),
+                &sharded.path().join("0/mainnet-2-3Na.json"),
+            ]
+        );
+        assert!(!sharded.path().join("mainnet-2-3Na.json").exists());
+
+        // padded names are matched and written
+        let padded = tempfile::tempdir().unwrap();
+        write(padded.path().join("mainnet-000002-3Na.json"), "{}").unwrap();
+        let report = download(&source, &queries, padded.path()).unwrap();
+        assert_eq!(
+            report.existing().collect::<Vec<_>>(),
+            vec![&padded.path().join("mainnet-000002-3Na.json")]
+        );
+        assert_eq!(
+            report.downloaded().collect::<Vec<_>>(),
+            vec![
+                &padded.path().join("mainnet-001500-3Na.json"),
+                &padded.path().join("mainnet-001500-3Nb.json"),
+            ]
+        );
+    }
+
+    #[test]
+    fn download_into_packed_dir() {
+        use blockchain::archive::{pack, PackConfig};
+
+        let mirror = tempfile::tempdir().unwrap();
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let hash = |c: char| format!("{}{c}", &HASH[..HASH.len() - 1]);
+        for (length, hash) in [(2, hash('A')), (3, hash('B')), (3, hash('C'))] {
+            let contents = format!(
+                r#"{{"protocol_state":{{"previous_state_hash":"3N","body":{{"consensus_state":{{"blockchain_length":"{length}","global_slot_since_genesis":"{length}","block_creator":"B62q"}}}}}}}}"#
+            );
+            let name = format!("mainnet-{length}-{hash}.json");
+            write(mirror.path().join(&name), &contents).unwrap();
+            if !hash.ends_with('C') {
+                write(blocks_dir.path().join(&name), &contents).unwrap();
+            }
+        }
+        let config = PackConfig {
+            segment_size: 1000,
+            level: 3,
+            all: true,
+            keep_files: false,
+        };
+        assert_eq!(pack(blocks_dir.path(), &config).unwrap().blocks, 2);
+
+        // packed blocks aren't downloaded again
+        let source = local::LocalSource::new(mirror.path());
+        let queries = [
+            BlockQuery::length("mainnet", 2),
+            BlockQuery::length("mainnet", 3),
+        ];
+        let report = download(&source, &queries, blocks_dir.path()).unwrap();
+        assert_eq!(report.existing().count(), 2);
+        assert_eq!(
+            report.downloaded().collect::<Vec<_>>(),
+            vec![&blocks_dir
+                .path()
+                .join(format!("mainnet-3-{}.json", hash('C')))]
+        );
+    }
+
+    #[test]
+    fn failed_write_leaves_no_part_file() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("connection reset"))
+            }
+        }
+
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let dir = BlocksDir::new(blocks_dir.path(), Layout::Flat);
+        let contents = "{".as_bytes().chain(Broken);
+        assert!(write_block(&dir, "mainnet-2-3Na.json", contents).is_err());
+        assert_eq!(std::fs::read_dir(blocks_dir.path()).unwrap().count(), 0);
+
+        write_block(&dir, "mainnet-2-3Na.json", "{}".as_bytes()).unwrap();
+        assert_eq!(std::fs::read_dir(blocks_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn rate_limits() {
+        assert_eq!(parse_rate("2.5"), Ok(2.5));
+        for rate in ["0", "-1", "NaN", "inf", "fast"] {
+            assert!(parse_rate(rate).is_err(), "{rate}");
+        }
+    }
+}
@@ -1,5 +1,51 @@
+use blockchain::genesis::GenesisConstants;
+use clap::Args;
 use log::debug;
-use std::process::{self, Command};
+use std::{
+    process::{self, Command},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Genesis constants, needed for networks without built-in ones
+#[derive(Args, Debug, Clone, Default)]
+pub struct GenesisArgs {
+    /// Unix time (in ms) of the network's genesis, overriding the built-in one
+    #[arg(long)]
+    genesis_timestamp: Option<u64>,
+    /// Slot duration (in ms) of the network, overriding the built-in one
+    #[arg(long)]
+    slot_duration: Option<u64>,
+}
+
+impl GenesisArgs {
+    pub fn constants(&self, network: &str) -> anyhow::Result<GenesisConstants> {
+        let built_in = GenesisConstants::for_network(network);
+        match (
+            self.genesis_timestamp
+                .or(built_in.map(|g| g.genesis_timestamp)),
+            self.slot_duration.or(built_in.map(|g| g.slot_duration)),
+        ) {
+            (Some(genesis_timestamp), Some(slot_duration)) if slot_duration > 0 => {
+                Ok(GenesisConstants {
+                    genesis_timestamp,
+                    slot_duration,
+                })
+            }
+            (Some(_), Some(_)) => anyhow::bail!("--slot-duration must be positive"),
+            _ => anyhow::bail!(
+                "No genesis constants for {network}, pass --genesis-timestamp and --slot-duration"
+            ),
+        }
+    }
+}
+
+/// Current unix time (in ms)
+pub fn unix_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is after the unix epoch")
+        .as_millis() as u64
+}
 
 /// Check that gsutil is installed
 pub fn check_gsutil() {
@@ -1,15 +1,25 @@
-use crate::common::check_gsutil;
-use blockchain::length_from_path;
+use crate::{
+    block_source::{download, BlockQuery, BlockSource, BlockStatus, SourceArgs},
+    loop_config::{LoopConfig, Target},
+    loop_state::{unix_now, LoopState, RetryPolicy},
+};
+use blockchain::{block_index::BlockIndex, get_blockchain_length};
 use clap::Parser;
-use fs::{check_dir, check_file};
-use glob::glob;
-use log::{debug, info};
+use fs::{
+    check_dir,
+    layout::{block_paths, BlockName},
+};
+use log::{debug, error, info};
 use std::{
-    fs::File,
-    io::prelude::*,
+    collections::BTreeSet,
+    ffi::OsStr,
     path::{Path, PathBuf},
-    process::{Command, Stdio},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 #[derive(Parser, Debug, Clone)]
@@ -20,132 +30,259 @@ pub struct LoopArgs {
     /// How often to query for new blocks (in sec)
     #[arg(short, long, default_value_t = 10)]
     frequency: u64,
-    /// File to write queries to
-    #[arg(short, long, default_value = concat!(env!("HOME"), "/.mina-indexer-loop-block-queries"))]
-    query_file: PathBuf,
     /// Name of Mina network
     #[arg(short, long, default_value = "mainnet")]
     network: String,
-    /// Name of GCP bucket
-    #[arg(long, default_value = "mina_network_block_data")]
-    bucket: String,
-    /// Number of previous block_lengths to query each time
+    /// Number of block_lengths below the highest seen one to query again each time, for blocks which
+    /// arrive late
     #[arg(long, default_value_t = 5)]
     buffer: u32,
     /// Number of block_lengths above current max to query each time before entering the maintenance loop
     #[arg(long, default_value_t = 100)]
     additional: u32,
+    /// File to keep the loop's progress in, so it resumes where it left off
+    #[arg(long, default_value = concat!(env!("HOME"), "/.mina-indexer-loop-state"))]
+    state_file: PathBuf,
+    /// Number of times to retry a missing or failed length before giving up on it
+    #[arg(long, default_value_t = 10)]
+    max_retries: u32,
+    /// Longest delay between retries of a length (in sec)
+    #[arg(long, default_value_t = 600)]
+    max_backoff: u64,
+    /// JSON file of (network, bucket, blocks dir, buffer) targets to service concurrently,
+    /// instead of `--network`, `--bucket`, `--blocks-dir`, `--buffer` and `--state-file`
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    source: SourceArgs,
 }
 
 pub fn main(args: LoopArgs) -> anyhow::Result<()> {
-    let blocks_dir = args.blocks_dir;
+    let targets = match &args.config {
+        Some(config_path) => LoopConfig::read(config_path)?.targets,
+        None => vec![Target {
+            network: args.network.clone(),
+            bucket: args.source.bucket.clone(),
+            blocks_dir: args.blocks_dir.clone(),
+            buffer: args.buffer,
+            state_file: Some(args.state_file.clone()),
+        }],
+    };
+
+    // flush the states and stop on SIGINT/SIGTERM
+    let shutdown = Arc::new(AtomicBool::new(false));
+    {
+        let shutdown = shutdown.clone();
+        ctrlc::set_handler(move || shutdown.store(true, Ordering::SeqCst))?;
+    }
+
+    // each target runs in its own thread, a failing target doesn't stop the others
+    let failed = thread::scope(|scope| {
+        let handles: Vec<_> = targets
+            .iter()
+            .map(|target| {
+                let (args, shutdown) = (&args, &shutdown);
+                thread::Builder::new()
+                    .name(target.network.clone())
+                    .spawn_scoped(scope, move || run(target, args, shutdown))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let mut failed = vec![];
+        for (target, handle) in targets.iter().zip(handles) {
+            let res = handle
+                .join()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("thread panicked")));
+            if let Err(err) = res {
+                error!(target: &target.network, "{} loop failed: {err}", target.network);
+                failed.push(target.network.as_str());
+            }
+        }
+        anyhow::Ok(failed)
+    })?;
+
+    if !failed.is_empty() {
+        anyhow::bail!("loop failed for {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+/// Keep a target's blocks dir up to date until shutdown
+///
+/// Everything is logged with the network's name as the log target, so each
+/// network's logs can be filtered, e.g. `RUST_LOG=info,devnet=debug`.
+fn run(target: &Target, args: &LoopArgs, shutdown: &AtomicBool) -> anyhow::Result<()> {
+    let network = target.network.as_str();
+    let blocks_dir = &target.blocks_dir;
+    let state_file_path = target.state_file();
     let frequency = args.frequency;
-    let network = args.network;
-    let bucket = args.bucket;
-    let buffer = args.buffer;
-    let query_file_path = args.query_file;
     let additional = args.additional;
+    let policy = RetryPolicy {
+        base_delay: frequency,
+        max_delay: args.max_backoff,
+        max_retries: args.max_retries,
+    };
 
-    check_file(&query_file_path);
-    check_dir(&blocks_dir);
-    check_gsutil();
+    check_dir(blocks_dir)?;
+    let source = SourceArgs {
+        bucket: target.bucket.clone(),
+        ..args.source.clone()
+    }
+    .block_source()?;
 
-    info!("Doing the initial catchup...");
-    let sleep_duration = Duration::new(frequency, 0);
+    // the buffer window was queried before a restart, so it's skipped once
+    let mut buffer = None;
+    let mut state = match LoopState::load(&state_file_path)? {
+        Some(state) => {
+            info!(
+                target: network,
+                "Resuming from {}: {network} blocks confirmed up to length {}, {} in flight, {} given up",
+                state_file_path.display(),
+                state.last_confirmed(),
+                state.in_flight.len(),
+                state.given_up.len()
+            );
+            if !state.given_up.is_empty() {
+                error!(
+                    target: network,
+                    "Gave up on {network} lengths {:?}",
+                    state.given_up
+                );
+            }
+            state
+        }
+        None => {
+            info!(target: network, "Reading {network} blocks in {}...", blocks_dir.display());
+            let max_length = max_local_length(blocks_dir, network)?;
+            info!(target: network, "Max length of {network} blocks: {max_length}");
+            LoopState::new(max_length, target.buffer)
+        }
+    };
 
     // before entering the maintenance loop, we grab blocks until we find a length that doesn't exist
-    let mut output = read_current_blocks_and_query(
-        &blocks_dir,
-        &query_file_path,
-        &network,
-        &bucket,
-        buffer,
-        additional,
-    )?;
-
-    while !output
-        .split('\n')
-        .any(|s| s.starts_with("CommandException"))
-    {
-        output = read_current_blocks_and_query(
-            &blocks_dir,
-            &query_file_path,
-            &network,
-            &bucket,
-            buffer,
-            additional,
-        )?;
-    }
+    info!(target: network, "Doing the initial {network} catchup...");
+    let mut catching_up = true;
+    while !shutdown.load(Ordering::SeqCst) {
+        let lookahead = if catching_up {
+            additional
+        } else {
+            frequency as u32 / 3 + 1
+        };
+        let now = unix_now();
+        let queried = state.due(now, buffer, lookahead);
+        let found = query(source.as_ref(), blocks_dir, network, &queried);
+        buffer = Some(target.buffer);
 
-    std::thread::sleep(sleep_duration);
-    info!("Entering maintenance loop...");
-
-    // maintenance loop
-    loop {
-        read_current_blocks_and_query(
-            &blocks_dir,
-            &query_file_path,
-            &network,
-            &bucket,
-            buffer,
-            frequency as u32 / 3 + 1,
-        )?;
-
-        std::thread::sleep(sleep_duration);
+        let frontier_found = state.record(network, &queried, &found, now, &policy);
+        state.save(&state_file_path)?;
+
+        if catching_up && !frontier_found {
+            catching_up = false;
+            info!(target: network, "Entering {network} maintenance loop...");
+        }
+        if !catching_up {
+            sleep_until_shutdown(Duration::from_secs(frequency), shutdown);
+        }
     }
+
+    info!(
+        target: network,
+        "Shutting down, {network} state saved to {}",
+        state_file_path.display()
+    );
+    state.save(&state_file_path)
 }
 
-fn read_current_blocks_and_query(
+/// Download the blocks of the given lengths, returning the lengths whose
+/// blocks were all downloaded
+fn query(
+    source: &dyn BlockSource,
     blocks_dir: &Path,
-    query_file_path: &Path,
     network: &str,
-    bucket: &str,
-    buffer: u32,
-    additional: u32,
-) -> anyhow::Result<String> {
-    info!("Reading {network} blocks in {}...", blocks_dir.display());
-    let mut our_block_paths: Vec<PathBuf> =
-        glob(&format!("{}/{network}-*-*.json", blocks_dir.display()))
-            .unwrap()
-            .filter_map(|p| p.ok())
-            .collect();
-    our_block_paths.sort_by_key(|x| length_from_path(x).unwrap_or(0));
-
-    let max_network_length = our_block_paths
-        .last()
-        .map_or(0, |path| length_from_path(path).unwrap());
-
-    info!("Max length of {network} blocks: {max_network_length}");
-    debug!("Writing query file: {}", query_file_path.display());
-
-    let mut query_file = File::create(query_file_path).unwrap();
-    query_file.set_len(0)?;
-
-    let start = 2.max(max_network_length.saturating_sub(buffer));
-    let end = max_network_length + additional;
-
-    for length in start..=end {
-        writeln!(query_file, "gs://{bucket}/{network}-{length}-*.json")?;
+    lengths: &[u32],
+) -> BTreeSet<u32> {
+    debug!(target: network, "Querying {network} block lengths {lengths:?}");
+    let queries: Vec<BlockQuery> = lengths
+        .iter()
+        .map(|length| BlockQuery::length(network, *length))
+        .collect();
+
+    let report = match download(source, &queries, blocks_dir) {
+        Ok(report) => report,
+        Err(err) => {
+            error!(target: network, "Failed to query {network} blocks: {err}");
+            return BTreeSet::new();
+        }
+    };
+    report.log_to(network);
+
+    let mut found = BTreeSet::new();
+    let mut failed = BTreeSet::new();
+    for block in &report.blocks {
+        if let Some(length) = get_blockchain_length(OsStr::new(&block.name)) {
+            match block.status {
+                BlockStatus::Failed(_) => failed.insert(length),
+                _ => found.insert(length),
+            };
+        }
     }
+    &found - &failed
+}
 
-    let cat_cmd = Command::new("cat")
-        .arg(query_file_path)
-        .stdout(Stdio::piped())
-        .spawn()?;
-
-    let gsutil_cmd = Command::new("gsutil")
-        .arg("-m")
-        .arg("cp")
-        .arg("-n")
-        .arg("-I")
-        .arg(blocks_dir)
-        .stdin(Stdio::from(cat_cmd.stdout.unwrap()))
-        .output()?;
-
-    let output = String::from_utf8(gsutil_cmd.stderr.clone()).map_err(anyhow::Error::new);
-    for line in output?.split('\n').filter(|s| s.starts_with("Copying")) {
-        println!("{line}");
+/// Max length of the network's blocks, other networks may share the blocks dir
+fn max_local_length(blocks_dir: &Path, network: &str) -> anyhow::Result<u32> {
+    Ok(match BlockIndex::open_updated(blocks_dir)? {
+        Some(index) => index.max_network_length(network).unwrap_or(0),
+        None => block_paths(blocks_dir)
+            .into_iter()
+            .filter_map(|path| BlockName::from_path(&path))
+            .filter(|name| name.network == network)
+            .map(|name| name.length)
+            .max()
+            .unwrap_or(0),
+    })
+}
+
+/// Sleep in short steps, waking up early on shutdown
+fn sleep_until_shutdown(duration: Duration, shutdown: &AtomicBool) {
+    const STEP: Duration = Duration::from_millis(100);
+    let start = Instant::now();
+    while !shutdown.load(Ordering::SeqCst) && start.elapsed() < duration {
+        std::thread::sleep(STEP.min(duration.saturating_sub(start.elapsed())));
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_source::local::LocalSource;
+    use std::fs::write;
 
-    String::from_utf8(gsutil_cmd.stderr).map_err(anyhow::Error::new)
+    #[test]
+    fn late_blocks_at_found_lengths() {
+        let mirror = tempfile::tempdir().unwrap();
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let source = LocalSource::new(mirror.path());
+        let policy = RetryPolicy {
+            base_delay: 10,
+            max_delay: 60,
+            max_retries: 3,
+        };
+        for name in ["mainnet-5-3Na.json", "mainnet-6-3Na.json"] {
+            write(mirror.path().join(name), "{}").unwrap();
+        }
+
+        let mut state = LoopState::new(4, 2);
+        let queried = state.due(0, None, 3);
+        let found = query(&source, blocks_dir.path(), "mainnet", &queried);
+        state.record("mainnet", &queried, &found, 0, &policy);
+        assert_eq!(state.highest_seen, 6);
+
+        // a fork block arrives at a length which was already found
+        write(mirror.path().join("mainnet-5-3Nb.json"), "{}").unwrap();
+        let queried = state.due(0, Some(2), 1);
+        assert_eq!(queried, vec![4, 5, 6, 7]);
+        query(&source, blocks_dir.path(), "mainnet", &queried);
+        assert!(blocks_dir.path().join("mainnet-5-3Nb.json").exists());
+    }
 }
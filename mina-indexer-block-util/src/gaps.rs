@@ -0,0 +1,76 @@
+use crate::block_source::{download, BlockQuery, SourceArgs};
+use blockchain::{block_index::read_block_files, gap_report::GapReport};
+use clap::Parser;
+use fs::check_dir;
+use log::info;
+use std::{fs::File, path::PathBuf};
+
+#[derive(Parser, Debug, Clone)]
+pub struct GapsArgs {
+    /// Gap report from `blockchain gaps` (text or JSON), computed from the blocks dir if absent
+    #[arg(short, long)]
+    report: Option<PathBuf>,
+    /// Directory to dump blocks into
+    #[arg(short, long, default_value = concat!(env!("HOME"), "/.mina-indexer-blocks"))]
+    blocks_dir: PathBuf,
+    /// Name of Mina network
+    #[arg(short, long, default_value = "mainnet")]
+    network: String,
+    /// Only print the gsutil queries, don't download
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+    #[command(flatten)]
+    source: SourceArgs,
+}
+
+pub fn main(args: GapsArgs) -> anyhow::Result<()> {
+    let blocks_dir = args.blocks_dir;
+    let network = args.network;
+
+    check_dir(&blocks_dir)?;
+
+    let report = match args.report {
+        Some(report_path) => GapReport::read(File::open(report_path)?)?,
+        None => {
+            let blocks = read_block_files(&blocks_dir)?;
+            GapReport::new(blocks.iter().map(|b| &b.block))
+        }
+    };
+
+    if report.is_empty() {
+        info!(
+            "No gaps between {network} block lengths {} and {}",
+            report.min_length, report.max_length
+        );
+        return Ok(());
+    }
+    info!(
+        "Filling {} missing lengths and {} dangling parents",
+        report.missing_lengths.len(),
+        report.dangling_parents.len()
+    );
+
+    if args.dry_run {
+        for query in report.queries(&args.source.bucket, &network) {
+            println!("{query}");
+        }
+        return Ok(());
+    }
+
+    // every block of a missing length, only the parent of a dangling block
+    let queries: Vec<BlockQuery> = report
+        .missing_lengths
+        .iter()
+        .map(|length| BlockQuery::length(&network, *length))
+        .chain(
+            report
+                .dangling_parents
+                .iter()
+                .map(|parent| BlockQuery::block(&network, parent.length, &parent.state_hash)),
+        )
+        .collect();
+
+    let source = args.source.block_source()?;
+    download(source.as_ref(), &queries, &blocks_dir)?.log();
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use serde::Deserialize;
+use std::{collections::BTreeSet, fs::File, io::BufReader, path::Path, path::PathBuf};
+
+/// Networks serviced concurrently by one `loop` run
+///
+/// ```json
+/// {
+///   "targets": [
+///     { "network": "mainnet", "bucket": "mina_network_block_data", "blocks_dir": "/blocks/mainnet" },
+///     { "network": "devnet", "bucket": "mina_network_block_data", "blocks_dir": "/blocks/devnet", "buffer": 20 }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoopConfig {
+    pub targets: Vec<Target>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    /// Name of Mina network
+    pub network: String,
+    /// Name of the bucket to download the network's blocks from
+    pub bucket: String,
+    /// Directory to dump blocks into
+    pub blocks_dir: PathBuf,
+    /// Number of previous block lengths to query when starting without a state file
+    #[serde(default = "default_buffer")]
+    pub buffer: u32,
+    /// File to keep the target's progress in, `<blocks_dir>/.<network>-loop-state` by default
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+}
+
+fn default_buffer() -> u32 {
+    5
+}
+
+impl LoopConfig {
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let config: Self = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Targets need separate state files, or they would overwrite each other's
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.targets.is_empty() {
+            anyhow::bail!("no targets in loop config");
+        }
+
+        let mut state_files = BTreeSet::new();
+        for target in &self.targets {
+            if !state_files.insert(target.state_file()) {
+                anyhow::bail!(
+                    "{} target shares its state file {} with another target",
+                    target.network,
+                    target.state_file().display()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Target {
+    pub fn state_file(&self) -> PathBuf {
+        self.state_file.clone().unwrap_or_else(|| {
+            self.blocks_dir
+                .join(format!(".{}-loop-state", self.network))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> anyhow::Result<LoopConfig> {
+        let config: LoopConfig = serde_json::from_str(json)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn targets() {
+        let config = parse(
+            r#"{"targets": [
+                {"network": "mainnet", "bucket": "mainnet-blocks", "blocks_dir": "/blocks"},
+                {"network": "devnet", "bucket": "devnet-blocks", "blocks_dir": "/blocks", "buffer": 20},
+                {"network": "berkeley", "bucket": "berkeley-blocks", "blocks_dir": "/berkeley", "state_file": "/state"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config
+                .targets
+                .iter()
+                .map(|t| (t.buffer, t.state_file()))
+                .collect::<Vec<_>>(),
+            vec![
+                (5, PathBuf::from("/blocks/.mainnet-loop-state")),
+                (20, PathBuf::from("/blocks/.devnet-loop-state")),
+                (5, PathBuf::from("/state")),
+            ]
+        );
+
+        assert!(parse(r#"{"targets": []}"#).is_err());
+        assert!(parse(
+            r#"{"targets": [
+                {"network": "mainnet", "bucket": "a", "blocks_dir": "/blocks"},
+                {"network": "mainnet", "bucket": "b", "blocks_dir": "/blocks"}
+            ]}"#
+        )
+        .is_err());
+        assert!(parse(
+            r#"{"targets": [{"network": "mainnet", "bucket": "a", "blocks_dir": "/blocks", "bufer": 5}]}"#
+        )
+        .is_err());
+    }
+}
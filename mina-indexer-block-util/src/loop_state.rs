@@ -0,0 +1,257 @@
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{rename, File},
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Progress of the continuous loop, persisted between runs
+///
+/// Every length up to the highest seen one is either downloaded, in flight or
+/// given up on. In-flight lengths are missing, or failed to download, and are
+/// retried with exponential backoff until they succeed or run out of retries.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoopState {
+    /// highest length with a downloaded block
+    pub highest_seen: u32,
+    /// lengths still to download
+    pub in_flight: BTreeMap<u32, Retry>,
+    /// lengths which ran out of retries, they're never confirmed
+    #[serde(default)]
+    pub given_up: BTreeSet<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retry {
+    pub attempts: u32,
+    /// unix time (in sec) of the next attempt
+    pub next_attempt: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// delay (in sec) after the first failed attempt, doubled after each one
+    pub base_delay: u64,
+    pub max_delay: u64,
+    /// failed attempts before a length is given up on
+    pub max_retries: u32,
+}
+
+impl LoopState {
+    /// Start from our max length, re-downloading the `buffer` lengths below it
+    pub fn new(max_length: u32, buffer: u32) -> Self {
+        Self {
+            highest_seen: max_length,
+            in_flight: (2.max(max_length.saturating_sub(buffer))..=max_length)
+                .map(|length| (length, Retry::default()))
+                .collect(),
+            given_up: BTreeSet::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_reader(BufReader::new(File::open(
+            path,
+        )?))?))
+    }
+
+    /// Write the state file atomically
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Highest length below which every length is downloaded
+    pub fn last_confirmed(&self) -> u32 {
+        let in_flight = self.in_flight.keys().next();
+        let given_up = self.given_up.iter().next();
+        in_flight
+            .into_iter()
+            .chain(given_up)
+            .min()
+            .map_or(self.highest_seen, |length| length - 1)
+    }
+
+    /// Lengths to query at `now`: in-flight lengths which are due, the
+    /// highest seen and the `buffer` lengths below it, if given, as they may
+    /// get more blocks, and the `lookahead` lengths above it, in increasing
+    /// order
+    pub fn due(&self, now: u64, buffer: Option<u32>, lookahead: u32) -> Vec<u32> {
+        let start = 2.max(self.highest_seen + 1);
+        let recent = buffer.map_or(0..0, |buffer| {
+            2.max(self.highest_seen.saturating_sub(buffer))..start
+        });
+        let lengths: BTreeSet<u32> = self
+            .in_flight
+            .iter()
+            .filter(|(_, retry)| retry.next_attempt <= now)
+            .map(|(length, _)| *length)
+            .chain(recent)
+            .chain(start..start + lookahead)
+            .collect();
+        lengths.into_iter().collect()
+    }
+
+    /// Record the outcome of querying the `queried` lengths of the network,
+    /// of which `found` were downloaded. Returns whether every length above
+    /// the previous highest seen one was found, i.e. we're still catching up.
+    pub fn record(
+        &mut self,
+        network: &str,
+        queried: &[u32],
+        found: &BTreeSet<u32>,
+        now: u64,
+        policy: &RetryPolicy,
+    ) -> bool {
+        let previous_highest = self.highest_seen;
+        self.highest_seen = found.iter().copied().fold(self.highest_seen, u32::max);
+
+        let mut frontier_found = true;
+        for length in queried {
+            if found.contains(length) {
+                self.in_flight.remove(length);
+                self.given_up.remove(length);
+                continue;
+            }
+            if *length > previous_highest {
+                frontier_found = false;
+            }
+
+            // lengths above the highest seen one may not exist yet, they aren't failures
+            if *length > self.highest_seen {
+                continue;
+            }
+            let retry = self.in_flight.entry(*length).or_default();
+            retry.attempts += 1;
+            if retry.attempts > policy.max_retries {
+                error!(
+                    target: network,
+                    "Giving up on {network} length {length} after {} attempts",
+                    policy.max_retries
+                );
+                self.in_flight.remove(length);
+                self.given_up.insert(*length);
+            } else {
+                retry.next_attempt = now + policy.delay(retry.attempts);
+                debug!(
+                    target: network,
+                    "{network} length {length} failed {} times, retrying at {}",
+                    retry.attempts, retry.next_attempt
+                );
+            }
+        }
+        frontier_found
+    }
+}
+
+impl RetryPolicy {
+    /// Delay after the given number of failed attempts
+    pub fn delay(&self, attempts: u32) -> u64 {
+        self.base_delay
+            .saturating_mul(1 << attempts.saturating_sub(1).min(32))
+            .min(self.max_delay)
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is after the unix epoch")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        base_delay: 10,
+        max_delay: 60,
+        max_retries: 3,
+    };
+
+    #[test]
+    fn resume_from_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+        let mut state = LoopState::new(100, 5);
+        assert_eq!(
+            state.due(0, None, 2),
+            vec![95, 96, 97, 98, 99, 100, 101, 102]
+        );
+        assert_eq!(state.due(0, Some(5), 2), state.due(0, None, 2));
+
+        let found = [95, 96, 98, 99, 100, 101].into_iter().collect();
+        assert!(!state.record("mainnet", &state.due(0, Some(5), 2), &found, 0, &POLICY));
+        assert_eq!(state.highest_seen, 101);
+        assert_eq!(state.last_confirmed(), 96);
+        // a restart skips the buffer window, it's queried again on later iterations
+        assert_eq!(state.due(0, None, 2), vec![102, 103]);
+        assert_eq!(state.due(10, None, 2), vec![97, 102, 103]);
+        assert_eq!(state.due(0, Some(3), 2), vec![98, 99, 100, 101, 102, 103]);
+
+        assert_eq!(LoopState::default().due(0, Some(5), 2), vec![2, 3]);
+        assert_eq!(LoopState::load(&path).unwrap(), None);
+        state.save(&path).unwrap();
+        assert_eq!(LoopState::load(&path).unwrap(), Some(state));
+    }
+
+    #[test]
+    fn backoff_and_give_up() {
+        let mut state = LoopState::new(10, 0);
+        let found = [10, 12].into_iter().collect();
+        state.record("mainnet", &[10, 11, 12, 13], &found, 0, &POLICY);
+        assert_eq!(state.in_flight.keys().collect::<Vec<_>>(), vec![&11]);
+
+        for attempts in 2..=3 {
+            let now = state.in_flight[&11].next_attempt;
+            assert!(state.due(now - 1, None, 0).is_empty());
+            state.record(
+                "mainnet",
+                &state.due(now, None, 0),
+                &BTreeSet::new(),
+                now,
+                &POLICY,
+            );
+            assert_eq!(
+                state.in_flight[&11],
+                Retry {
+                    attempts,
+                    next_attempt: now + POLICY.delay(attempts),
+                }
+            );
+        }
+        assert_eq!(
+            (1..=5).map(|n| POLICY.delay(n)).collect::<Vec<_>>(),
+            vec![10, 20, 40, 60, 60]
+        );
+
+        let now = state.in_flight[&11].next_attempt;
+        state.record(
+            "mainnet",
+            &state.due(now, None, 0),
+            &BTreeSet::new(),
+            now,
+            &POLICY,
+        );
+        assert!(state.in_flight.is_empty());
+        assert_eq!(state.given_up, BTreeSet::from([11]));
+        assert_eq!(state.last_confirmed(), 10);
+        assert!(state.due(u64::MAX, None, 0).is_empty());
+
+        // a given up length which turns up after all is confirmed
+        state.record("mainnet", &[11], &BTreeSet::from([11]), now, &POLICY);
+        assert!(state.given_up.is_empty());
+        assert_eq!(state.last_confirmed(), 12);
+    }
+}
@@ -1,10 +1,15 @@
 use clap::{Parser, Subcommand};
 
 mod all;
+mod block_source;
 mod common;
 mod contiguous;
 mod continuous_loop;
+mod gaps;
+mod loop_config;
+mod loop_state;
 mod new_only;
+mod verify;
 
 #[derive(Parser, Debug)]
 #[command(name = "mina-indexer-block-util", author, about, long_about = Some("
@@ -25,6 +30,10 @@ enum Command {
     NewOnly(new_only::NewArgs),
     /// Run the block fetcher in a continuous loop
     Loop(continuous_loop::LoopArgs),
+    /// Only download the blocks missing from a gap report
+    Gaps(gaps::GapsArgs),
+    /// Check block files are complete and match their names
+    Verify(verify::VerifyArgs),
 }
 
 fn main() -> anyhow::Result<()> {
@@ -36,5 +45,7 @@ fn main() -> anyhow::Result<()> {
         Command::Contiguous(args) => contiguous::main(args),
         Command::NewOnly(args) => new_only::main(args),
         Command::Loop(args) => continuous_loop::main(args),
+        Command::Gaps(args) => gaps::main(args),
+        Command::Verify(args) => verify::main(args),
     }
 }
@@ -0,0 +1,376 @@
+use crate::block_source::{
+    download, BlockDownload, BlockQuery, BlockSource, BlockStatus, BlocksDir, SourceArgs,
+    SourceError,
+};
+use blockchain::{
+    get_blockchain_length, get_state_hash,
+    precomputed_block::{BlockParseError, PrecomputedBlock},
+};
+use clap::Parser;
+use fs::{
+    check_dir,
+    layout::{block_paths, BlockName},
+};
+use log::{error, info, warn};
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::{
+    fmt,
+    fs::{create_dir_all, read, rename},
+    path::{Path, PathBuf},
+};
+
+/// Version byte of base58check encoded state hashes
+const STATE_HASH_VERSION: u8 = 0x10;
+
+#[derive(Parser, Debug, Clone)]
+pub struct VerifyArgs {
+    /// Directory of blocks to verify
+    #[arg(short, long, default_value = concat!(env!("HOME"), "/.mina-indexer-blocks"))]
+    blocks_dir: PathBuf,
+    /// Directory to move bad blocks into, `<blocks-dir>/.quarantine` by default
+    #[arg(short, long)]
+    quarantine_dir: Option<PathBuf>,
+    /// Download bad blocks again instead of only quarantining them
+    #[arg(long, default_value_t = false)]
+    redownload: bool,
+    #[command(flatten)]
+    source: SourceArgs,
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    Io(std::io::Error),
+    /// the file isn't complete JSON, e.g. truncated
+    Json(serde_json::Error),
+    InvalidFileName,
+    Header(BlockParseError),
+    /// the file name's state hash isn't a base58check state hash
+    InvalidStateHash,
+    LengthMismatch {
+        file_name: u32,
+        contents: u32,
+    },
+    StateHashMismatch {
+        contents: String,
+    },
+}
+
+/// The parts of a block file checked beyond its header
+#[derive(Deserialize)]
+struct BlockContents {
+    state_hash: Option<String>,
+    data: Option<Box<BlockContents>>,
+}
+
+/// Check a block file is complete and consistent with its name
+///
+/// The whole file is parsed. The length in the file name must match the
+/// block's `blockchain_length`. Precomputed blocks don't contain their own
+/// state hash, so the file name's state hash must be valid base58check and,
+/// if the contents do have a `state_hash`, match it.
+pub fn verify_block(path: &Path) -> Result<PrecomputedBlock, VerifyError> {
+    let file_name = path.file_name().ok_or(VerifyError::InvalidFileName)?;
+    let state_hash = get_state_hash(file_name).ok_or(VerifyError::InvalidFileName)?;
+    let length = get_blockchain_length(file_name).ok_or(VerifyError::InvalidFileName)?;
+
+    let bytes = read(path)?;
+    let contents: BlockContents = serde_json::from_slice(&bytes)?;
+    let block = PrecomputedBlock::from_reader(state_hash.clone(), bytes.as_slice())
+        .map_err(VerifyError::Header)?;
+
+    if !is_valid_state_hash(&state_hash) {
+        return Err(VerifyError::InvalidStateHash);
+    }
+    if block.blockchain_length != length {
+        return Err(VerifyError::LengthMismatch {
+            file_name: length,
+            contents: block.blockchain_length,
+        });
+    }
+    if let Some(contents) = contents.state_hash() {
+        if contents != state_hash {
+            return Err(VerifyError::StateHashMismatch {
+                contents: contents.to_string(),
+            });
+        }
+    }
+    Ok(block)
+}
+
+fn is_valid_state_hash(state_hash: &str) -> bool {
+    bs58::decode(state_hash)
+        .with_check(Some(STATE_HASH_VERSION))
+        .into_vec()
+        .is_ok()
+}
+
+impl BlockContents {
+    fn state_hash(&self) -> Option<&str> {
+        self.state_hash
+            .as_deref()
+            .or_else(|| self.data.as_ref().and_then(|data| data.state_hash()))
+    }
+}
+
+/// Move a block file into `quarantine_dir`
+pub fn quarantine(path: &Path, quarantine_dir: &Path) -> std::io::Result<PathBuf> {
+    create_dir_all(quarantine_dir)?;
+    let quarantined = quarantine_dir.join(path.file_name().unwrap());
+    rename(path, &quarantined)?;
+    Ok(quarantined)
+}
+
+fn default_quarantine_dir(blocks_dir: &Path) -> PathBuf {
+    blocks_dir.join(".quarantine")
+}
+
+pub fn main(args: VerifyArgs) -> anyhow::Result<()> {
+    let blocks_dir = args.blocks_dir;
+    let quarantine_dir = args
+        .quarantine_dir
+        .unwrap_or_else(|| default_quarantine_dir(&blocks_dir));
+
+    check_dir(&blocks_dir)?;
+    let paths = block_paths(&blocks_dir);
+
+    info!(
+        "Verifying {} blocks in {}",
+        paths.len(),
+        blocks_dir.display()
+    );
+    let bad: Vec<(PathBuf, VerifyError)> = paths
+        .into_par_iter()
+        .filter_map(|path| verify_block(&path).err().map(|err| (path, err)))
+        .collect();
+
+    let mut queries = vec![];
+    for (path, err) in &bad {
+        let quarantined = quarantine(path, &quarantine_dir)?;
+        warn!(
+            "{}: {err}, moved to {}",
+            path.display(),
+            quarantined.display()
+        );
+
+        queries.extend(redownload_query(path));
+    }
+    info!("{} bad blocks quarantined", bad.len());
+
+    if args.redownload && !queries.is_empty() {
+        info!("Downloading {} bad blocks again", queries.len());
+        let source = VerifyingSource::new(args.source.block_source()?, &quarantine_dir);
+        download(&source, &queries, &blocks_dir)?.log();
+    }
+    Ok(())
+}
+
+/// Query for the block at `path`, in any layout and of any network
+fn redownload_query(path: &Path) -> Option<BlockQuery> {
+    let block = BlockName::from_path(path)?;
+    Some(BlockQuery::block(
+        &block.network,
+        block.length,
+        &block.state_hash,
+    ))
+}
+
+/// Verifies each block fetched from a source, quarantining bad ones
+pub struct VerifyingSource {
+    source: Box<dyn BlockSource>,
+    quarantine_dir: Option<PathBuf>,
+}
+
+impl VerifyingSource {
+    pub fn new(source: Box<dyn BlockSource>, quarantine_dir: &Path) -> Self {
+        Self {
+            source,
+            quarantine_dir: Some(quarantine_dir.into()),
+        }
+    }
+
+    /// Quarantine into `<blocks-dir>/.quarantine`
+    pub fn with_default_quarantine(source: Box<dyn BlockSource>) -> Self {
+        Self {
+            source,
+            quarantine_dir: None,
+        }
+    }
+
+    fn check(&self, path: PathBuf, blocks_dir: &BlocksDir) -> Result<PathBuf, SourceError> {
+        match verify_block(&path) {
+            Ok(_) => Ok(path),
+            Err(err) => {
+                let quarantine_dir = self
+                    .quarantine_dir
+                    .clone()
+                    .unwrap_or_else(|| default_quarantine_dir(&blocks_dir.path));
+                if let Err(err) = quarantine(&path, &quarantine_dir) {
+                    error!("Failed to quarantine {}: {err}", path.display());
+                }
+                Err(SourceError::Invalid(err.to_string()))
+            }
+        }
+    }
+}
+
+impl BlockSource for VerifyingSource {
+    fn list(&self, queries: &[BlockQuery]) -> Result<Vec<String>, SourceError> {
+        self.source.list(queries)
+    }
+
+    fn fetch(&self, name: &str, blocks_dir: &BlocksDir) -> Result<PathBuf, SourceError> {
+        self.check(self.source.fetch(name, blocks_dir)?, blocks_dir)
+    }
+
+    fn fetch_all(&self, names: &[String], blocks_dir: &BlocksDir) -> Vec<BlockDownload> {
+        self.source
+            .fetch_all(names, blocks_dir)
+            .into_iter()
+            .map(|download| match download.status {
+                BlockStatus::Downloaded(path) => BlockDownload {
+                    status: match self.check(path, blocks_dir) {
+                        Ok(path) => BlockStatus::Downloaded(path),
+                        Err(err) => BlockStatus::Failed(err),
+                    },
+                    ..download
+                },
+                _ => download,
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Json(err) => write!(f, "incomplete or malformed JSON: {err}"),
+            Self::InvalidFileName => write!(f, "not a block file name"),
+            Self::Header(err) => write!(f, "{err}"),
+            Self::InvalidStateHash => write!(f, "invalid state hash in file name"),
+            Self::LengthMismatch {
+                file_name,
+                contents,
+            } => write!(
+                f,
+                "file name has length {file_name}, contents have length {contents}"
+            ),
+            Self::StateHashMismatch { contents } => {
+                write!(f, "contents have state hash {contents}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl From<std::io::Error> for VerifyError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for VerifyError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_source::local::LocalSource;
+    use std::fs::write;
+
+    const STATE_HASH: &str = "3NKeMoncuHab5ScarV5ViyF16cJPT4taWNSaTLS64Dp67wuXigPZ";
+    const PARENT_HASH: &str = "3NLoKn22eMnyQ7rxh5pxB6vBA3XhSAhhrf7akdqS6HbAKD14Dh1d";
+
+    fn block_json(length: u32) -> String {
+        format!(
+            r#"{{"protocol_state":{{"previous_state_hash":"{PARENT_HASH}","body":{{"consensus_state":{{"blockchain_length":"{length}","global_slot_since_genesis":"{length}","block_creator":"B62q"}}}}}},"staged_ledger_diff":{{}}}}"#
+        )
+    }
+
+    #[test]
+    fn bad_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path =
+            |length: u32, hash: &str| dir.path().join(format!("mainnet-{length}-{hash}.json"));
+        let verify = |path: &Path, contents: &str| {
+            write(path, contents).unwrap();
+            verify_block(path)
+        };
+
+        assert!(verify(&path(5, STATE_HASH), &block_json(5)).is_ok());
+        assert!(matches!(
+            verify(&path(5, STATE_HASH), &block_json(5)[..100]),
+            Err(VerifyError::Json(_))
+        ));
+        assert!(matches!(
+            verify(&path(6, STATE_HASH), &block_json(5)),
+            Err(VerifyError::LengthMismatch {
+                file_name: 6,
+                contents: 5
+            })
+        ));
+        assert!(matches!(
+            verify(
+                &path(5, "3NKeMoncuHab5ScarV5ViyF16cJPT4taWNSaTLS64Dp67wuXigPz"),
+                &block_json(5)
+            ),
+            Err(VerifyError::InvalidStateHash)
+        ));
+        assert!(matches!(
+            verify(
+                &path(5, STATE_HASH),
+                &format!(r#"{{"version":1,"data":{}}}"#, block_json(5)).replace(
+                    r#""staged_ledger_diff""#,
+                    &format!(r#""state_hash":"{PARENT_HASH}","staged_ledger_diff""#)
+                )
+            ),
+            Err(VerifyError::StateHashMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn quarantine_fetched() {
+        let mirror = tempfile::tempdir().unwrap();
+        let blocks_dir = tempfile::tempdir().unwrap();
+        let good = format!("mainnet-5-{STATE_HASH}.json");
+        let bad = format!("mainnet-5-{PARENT_HASH}.json");
+        write(mirror.path().join(&good), block_json(5)).unwrap();
+        write(mirror.path().join(&bad), &block_json(5)[..100]).unwrap();
+
+        let source =
+            VerifyingSource::with_default_quarantine(Box::new(LocalSource::new(mirror.path())));
+        let report = download(
+            &source,
+            &[BlockQuery::length("mainnet", 5)],
+            blocks_dir.path(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            report.downloaded().collect::<Vec<_>>(),
+            vec![&blocks_dir.path().join(&good)]
+        );
+        assert_eq!(
+            report.failed().map(|(name, _)| name).collect::<Vec<_>>(),
+            vec![bad.as_str()]
+        );
+        assert!(!blocks_dir.path().join(&bad).exists());
+        assert!(blocks_dir.path().join(".quarantine").join(&bad).exists());
+    }
+
+    #[test]
+    fn redownload_queries() {
+        assert_eq!(
+            redownload_query(Path::new(&format!(
+                "0/testworld-2-0-000042-{STATE_HASH}.json"
+            ))),
+            Some(BlockQuery::block("testworld-2-0", 42, STATE_HASH))
+        );
+        assert_eq!(redownload_query(Path::new("mainnet-x-3N.json")), None);
+    }
+}
@@ -0,0 +1,87 @@
+//! Block support in a deep `WeightedTree`, cached vs walking the subtree
+//!
+//! The tree is a binary crate, so its modules are compiled in directly
+//!
+//! ```sh
+//! cargo bench --bench support
+//! ```
+
+#![allow(dead_code)]
+
+#[path = "../src/ledger/mod.rs"]
+mod ledger;
+#[path = "../src/merge.rs"]
+mod merge;
+#[path = "../src/weighted/mod.rs"]
+mod weighted;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use id_tree::NodeId;
+use ledger::{block::Block, LedgerDiff};
+use weighted::weighted_tree::WeightedTree;
+
+const DEPTHS: [usize; 3] = [100, 1_000, 10_000];
+
+/// number of distinct block producers
+const NUM_PKS: usize = 50;
+
+/// every `FORK_FREQ` blocks there is a competing leaf
+const FORK_FREQ: usize = 10;
+
+/// A chain of `depth` blocks with a fork every `FORK_FREQ` blocks, and the
+/// chain's ids from the root
+fn deep_tree(depth: usize) -> (WeightedTree, Vec<NodeId>) {
+    let block = |n: usize| {
+        Block::new(
+            &format!("B62q{}", n % NUM_PKS),
+            (n % 97) as u32,
+            LedgerDiff::new(),
+        )
+    };
+    let mut tree = WeightedTree::new();
+    let mut chain = vec![tree.insert(block(0), None)];
+    for n in 1..depth {
+        let parent = chain.last().unwrap().clone();
+        if n % FORK_FREQ == 0 {
+            tree.insert(block(n + depth), Some(&parent));
+        }
+        chain.push(tree.insert(block(n), Some(&parent)));
+    }
+    (tree, chain)
+}
+
+fn support(c: &mut Criterion) {
+    let mut group = c.benchmark_group("support");
+    for depth in DEPTHS {
+        let (tree, chain) = deep_tree(depth);
+        // fork choice asks for the support of each block along the branch
+        group.bench_with_input(BenchmarkId::new("cached", depth), &chain, |b, chain| {
+            b.iter(|| chain.iter().map(|id| tree.support(id).unwrap()).max())
+        });
+        if depth <= 1_000 {
+            group.bench_with_input(BenchmarkId::new("walk", depth), &chain, |b, chain| {
+                b.iter(|| {
+                    chain
+                        .iter()
+                        .map(|id| tree.subtree_support(id).unwrap())
+                        .max()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+    for depth in DEPTHS {
+        group.bench_function(BenchmarkId::from_parameter(depth), |b| {
+            b.iter(|| deep_tree(depth))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, support, insert);
+criterion_main!(benches);
@@ -1,5 +1,3 @@
-use crate::ledger::Diff;
-
 #[allow(dead_code)]
 #[derive(Debug, Clone, PartialEq)]
 pub struct Account {
@@ -9,12 +7,6 @@ pub struct Account {
     pub delegations: u64,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
-pub struct AccountUpdate {
-    pub diff: Diff,
-}
-
 #[allow(dead_code)]
 impl Account {
     pub fn new(pk: String, balance: u64, delegations: u64) -> Self {
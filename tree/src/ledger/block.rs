@@ -5,6 +5,8 @@ pub struct Block {
     pub pk: String,
     pub diff: LedgerDiff,
     pub weight: u32,
+    /// state hash, empty unless given, breaks fork choice ties
+    pub hash: String,
 }
 
 #[allow(dead_code)]
@@ -14,6 +16,14 @@ impl Block {
             pk: pk.to_string(),
             diff,
             weight,
+            hash: String::new(),
+        }
+    }
+
+    pub fn with_hash(self, hash: &str) -> Self {
+        Self {
+            hash: hash.to_string(),
+            ..self
         }
     }
 }
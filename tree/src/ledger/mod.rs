@@ -1,8 +1,8 @@
 pub(crate) mod account;
 pub(crate) mod block;
 
-use crate::ledger::account::{Account, AccountUpdate};
-use std::collections::HashMap;
+use crate::ledger::account::Account;
+use std::collections::{BTreeMap, HashMap};
 
 // types
 
@@ -22,18 +22,43 @@ pub enum Diff {
     Transfer(String, String, u64),
 }
 
+/// Diffs of a block, applied in order
 #[derive(Clone, PartialEq, Eq)]
-pub struct LedgerDiff(HashMap<(String, String), Diff>);
+pub struct LedgerDiff(Vec<Diff>);
 
+/// How an account differs between two ledgers
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountDiff {
+    Added(Account),
+    Removed(Account),
+    Changed { before: Account, after: Account },
+}
+
+/// A diff which could not be applied, at `index` in its `LedgerDiff`
 #[derive(Debug, Clone, PartialEq)]
-pub enum Error {
+pub struct Error {
+    pub index: usize,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
     InvalidCreation(String),
     InvalidAbsentFromLedger(String),
-    InvalidTransfer,
     DelegateAbsentFromLedger(String),
     InsufficientFunds(String, u64, u64),
     TransferAbsentFromLedger(String),
-    InvalidUpdate,
+}
+
+/// Prior states of the accounts changed by an apply, in order of change
+///
+/// Reverting it restores balances, delegations and created accounts exactly,
+/// as long as later applies are reverted first
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndoLog {
+    total: u64,
+    accounts: Vec<(String, Option<Account>)>,
 }
 
 // impls
@@ -47,206 +72,205 @@ impl Ledger {
         }
     }
 
-    pub fn apply(&mut self, delta: LedgerDiff) -> Result<(), Error> {
-        // iterate over diffs to make account updates
-        for ((from, to), diff) in delta.0 {
-            let mut updates = Vec::new();
-            match self.map.get(&from) {
-                // if not in the ledger, must be creation
+    pub fn from_accounts(accounts: impl IntoIterator<Item = Account>) -> Self {
+        let mut ledger = Self::new();
+        for account in accounts {
+            ledger.total += account.balance;
+            ledger.map.insert(account.pk.clone(), account);
+        }
+        ledger
+    }
+
+    pub fn get(&self, pk: &str) -> Option<&Account> {
+        self.map.get(pk)
+    }
+
+    pub fn balance(&self, pk: &str) -> Option<u64> {
+        self.get(pk).map(|account| account.balance)
+    }
+
+    /// accounts which differ from `self` to `other`, by pk
+    pub fn diff(&self, other: &Self) -> BTreeMap<String, AccountDiff> {
+        let mut diffs = BTreeMap::new();
+        for (pk, before) in &self.map {
+            match other.map.get(pk) {
                 None => {
-                    if let Diff::Creation(pk) = diff {
-                        updates.push(AccountUpdate::new(None, &pk, false, None, None));
-                    } else {
-                        return Err(Error::InvalidAbsentFromLedger(from));
-                    }
+                    diffs.insert(pk.clone(), AccountDiff::Removed(before.clone()));
                 }
-                // non-creation
-                Some(Account {
-                    pk: _,
-                    balance,
-                    delegate: _,
-                    delegations: _,
-                }) => match diff {
-                    Diff::Creation(pk) => {
-                        return Err(Error::InvalidCreation(pk));
-                    }
-                    Diff::Coinbase(pk, amount) => {
-                        if from == to {
-                            updates.push(AccountUpdate::new(None, &pk, true, Some(amount), None));
-                        }
-                    }
-                    // delegation
-                    Diff::Delegation(from, delegate) => match self.map.get(&delegate) {
-                        None => {
-                            return Err(Error::DelegateAbsentFromLedger(to));
-                        }
-                        Some(_) => {
-                            updates.push(AccountUpdate::new(
-                                Some(&from),
-                                &delegate,
-                                false,
-                                None,
-                                Some(delegate.clone()),
-                            ));
-                        }
-                    },
-                    Diff::Transfer(from, to, amount) => {
-                        if *balance < amount {
-                            return Err(Error::InsufficientFunds(from, *balance, amount));
-                        } else {
-                            match self.map.get(&to) {
-                                None => {
-                                    return Err(Error::TransferAbsentFromLedger(to.clone()));
-                                }
-                                Some(_) => {
-                                    updates.push(AccountUpdate::new(
-                                        Some(&from),
-                                        &to,
-                                        false,
-                                        Some(amount),
-                                        None,
-                                    ));
-                                }
-                            }
-                        }
-                    }
-                },
-            }
-            // apply all updates for the diff
-            for update in updates {
-                match update {
-                    Err(err) => {
-                        println!("{:?}", err);
-                        return Err(Error::InvalidUpdate);
-                    }
-                    Ok(AccountUpdate { diff }) => {
-                        match diff {
-                            Diff::Coinbase(pk, amount) => {
-                                if let Some(account) = self.map.get_mut(&pk) {
-                                    account.balance += amount;
-                                    self.total += amount;
-                                } else {
-                                    return Err(Error::InvalidAbsentFromLedger(pk.clone()));
-                                }
-                            }
-                            Diff::Creation(pk) => {
-                                self.map.insert(pk.clone(), Account::new(to.clone(), 0, 0));
-                            }
-                            Diff::Delegation(from, to) => {
-                                self.map.get_mut(&from).unwrap().delegate = Some(to.clone());
-                                self.map.get_mut(&to).unwrap().delegations +=
-                                    self.map.get(&from).unwrap().balance;
-                            }
-                            Diff::Transfer(from, to, amount) => {
-                                let from_account = self.map.get_mut(&from).unwrap();
-                                println!("{}", from_account.balance);
-                                if from_account.balance >= amount {
-                                    from_account.balance -= amount;
-                                    self.map.get_mut(&to).unwrap().balance += amount;
-                                } else {
-                                    // println!("{:?}, {:?}, {:?}, {:?}", from, to, from_account.balance, amount);
-                                    return Err(Error::InvalidTransfer);
-                                }
-                            }
-                        }
-                    }
+                Some(after) if after != before => {
+                    diffs.insert(
+                        pk.clone(),
+                        AccountDiff::Changed {
+                            before: before.clone(),
+                            after: after.clone(),
+                        },
+                    );
                 }
+                Some(_) => (),
             }
         }
-        Ok(())
+        for (pk, after) in &other.map {
+            if !self.map.contains_key(pk) {
+                diffs.insert(pk.clone(), AccountDiff::Added(after.clone()));
+            }
+        }
+        diffs
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct UpdateError {
-    pub from: Option<String>,
-    pub to: String,
-    pub coinbase: bool,
-    pub amount: Option<u64>,
-    pub delegate: Option<String>,
-}
+    /// Apply the diffs in order, all or nothing
+    ///
+    /// Each account is journaled before it's changed, so if a diff is invalid
+    /// the ledger is restored and the error reports the diff's index. Else
+    /// the journal is returned to `revert` the diffs.
+    pub fn apply(&mut self, delta: LedgerDiff) -> Result<UndoLog, Error> {
+        let mut journal = UndoLog {
+            total: self.total,
+            accounts: Vec::new(),
+        };
+        for (index, diff) in delta.0.into_iter().enumerate() {
+            if let Err(kind) = self.apply_diff(diff, &mut journal) {
+                self.revert(journal);
+                return Err(Error { index, kind });
+            }
+        }
+        Ok(journal)
+    }
 
-impl AccountUpdate {
-    fn new(
-        from: Option<&str>,
-        to: &str,
-        coinbase: bool,
-        amount: Option<u64>,
-        delegate: Option<String>,
-    ) -> Result<Self, UpdateError> {
-        match (coinbase, amount, delegate.clone(), from) {
-            // coinbase (coinbase = true)
-            (true, _, _, None) => {
-                // validate positive amount
-                if delegate.is_none() {
-                    Ok(Self {
-                        diff: Diff::Coinbase(to.to_string(), amount.unwrap()),
-                    })
-                } else {
-                    Err(UpdateError {
-                        from: from.map(|x| x.to_string()),
-                        to: to.to_string(),
-                        coinbase,
-                        amount,
-                        delegate,
-                    })
+    /// Undo an apply, which must be the last one not reverted yet
+    pub fn revert(&mut self, undo: UndoLog) {
+        for (pk, account) in undo.accounts.into_iter().rev() {
+            match account {
+                Some(account) => self.map.insert(pk, account),
+                None => self.map.remove(&pk),
+            };
+        }
+        self.total = undo.total;
+    }
+
+    fn apply_diff(&mut self, diff: Diff, journal: &mut UndoLog) -> Result<(), ErrorKind> {
+        match diff {
+            Diff::Creation(pk) => {
+                if self.map.contains_key(&pk) {
+                    return Err(ErrorKind::InvalidCreation(pk));
+                }
+                journal.accounts.push((pk.clone(), None));
+                self.map.insert(pk.clone(), Account::new(pk, 0, 0));
+            }
+            Diff::Coinbase(pk, amount) => {
+                let Some(account) = self.journaled(&pk, journal) else {
+                    return Err(ErrorKind::InvalidAbsentFromLedger(pk));
+                };
+                account.balance += amount;
+                self.total += amount;
+            }
+            Diff::Delegation(from, delegate) => {
+                if !self.map.contains_key(&from) {
+                    return Err(ErrorKind::InvalidAbsentFromLedger(from));
+                }
+                if !self.map.contains_key(&delegate) {
+                    return Err(ErrorKind::DelegateAbsentFromLedger(delegate));
                 }
+                let delegator = self.journaled(&from, journal).unwrap();
+                delegator.delegate = Some(delegate.clone());
+                let balance = delegator.balance;
+                self.journaled(&delegate, journal).unwrap().delegations += balance;
             }
-            // creation (coinbase = false && everything None)
-            (_, None, None, _) => Ok(Self {
-                diff: Diff::Creation(to.to_string()),
-            }),
-            // delegation (coinbase = false && amount = None)
-            (_, None, Some(delegate), Some(from)) => Ok(Self {
-                diff: Diff::Delegation(from.to_string(), delegate),
-            }),
-            // transfer (amount.is_some())
-            (_, Some(amount), None, Some(from)) => Ok(Self {
-                diff: Diff::Transfer(from.to_string(), to.to_string(), amount),
-            }),
-            _ => {
-                let from = from.map(|x| x.to_string());
-                Err(UpdateError {
-                    from,
-                    to: to.to_string(),
-                    coinbase,
-                    amount,
-                    delegate,
-                })
+            Diff::Transfer(from, to, amount) => {
+                let Some(balance) = self.balance(&from) else {
+                    return Err(ErrorKind::InvalidAbsentFromLedger(from));
+                };
+                if balance < amount {
+                    return Err(ErrorKind::InsufficientFunds(from, balance, amount));
+                }
+                if !self.map.contains_key(&to) {
+                    return Err(ErrorKind::TransferAbsentFromLedger(to));
+                }
+                self.journaled(&from, journal).unwrap().balance -= amount;
+                self.journaled(&to, journal).unwrap().balance += amount;
             }
         }
+        Ok(())
+    }
+
+    /// journal the account, then borrow it to change it
+    fn journaled(&mut self, pk: &str, journal: &mut UndoLog) -> Option<&mut Account> {
+        let account = self.map.get_mut(pk)?;
+        journal
+            .accounts
+            .push((pk.to_string(), Some(account.clone())));
+        Some(account)
     }
 }
 
 #[allow(dead_code)]
 impl LedgerDiff {
     pub fn new() -> Self {
-        Self(HashMap::new())
+        Self(Vec::new())
     }
 
     pub fn from(diffs: &[Diff]) -> Self {
-        let mut delta = LedgerDiff::new();
-        for diff in diffs.iter().cloned() {
-            match diff.clone() {
-                Diff::Coinbase(pk, _) => {
-                    delta.0.insert((pk.clone(), pk), diff);
-                }
-                Diff::Creation(pk) => {
-                    delta.0.insert((pk.clone(), pk), diff);
+        Self(diffs.to_vec())
+    }
+
+    pub fn push(&mut self, diff: Diff) {
+        self.0.push(diff);
+    }
+
+    /// `pk`'s balance after the diffs, given its balance before them
+    ///
+    /// only the diffs involving `pk` are replayed, and checked as `apply`
+    /// would from `pk`'s side, e.g. a transfer to `pk` isn't checked against
+    /// the sender's balance
+    pub fn balance_after(&self, pk: &str, mut balance: Option<u64>) -> Result<Option<u64>, Error> {
+        for (index, diff) in self.0.iter().enumerate() {
+            let error = |kind| Err(Error { index, kind });
+            match diff {
+                Diff::Creation(created) if created == pk => {
+                    if balance.is_some() {
+                        return error(ErrorKind::InvalidCreation(pk.to_string()));
+                    }
+                    balance = Some(0);
                 }
-                Diff::Delegation(from, to) => {
-                    delta.0.insert((from, to), diff);
+                Diff::Coinbase(to, amount) if to == pk => match &mut balance {
+                    Some(balance) => *balance += amount,
+                    None => return error(ErrorKind::InvalidAbsentFromLedger(pk.to_string())),
+                },
+                Diff::Delegation(from, delegate) if balance.is_none() => {
+                    if from == pk {
+                        return error(ErrorKind::InvalidAbsentFromLedger(pk.to_string()));
+                    }
+                    if delegate == pk {
+                        return error(ErrorKind::DelegateAbsentFromLedger(pk.to_string()));
+                    }
                 }
-                Diff::Transfer(from, to, _) => {
-                    delta.0.insert((from, to), diff);
+                Diff::Transfer(from, to, amount) if from == pk || to == pk => {
+                    let Some(current) = balance else {
+                        let kind = if from == pk {
+                            ErrorKind::InvalidAbsentFromLedger(pk.to_string())
+                        } else {
+                            ErrorKind::TransferAbsentFromLedger(pk.to_string())
+                        };
+                        return error(kind);
+                    };
+                    if from == pk && current < *amount {
+                        return error(ErrorKind::InsufficientFunds(
+                            pk.to_string(),
+                            current,
+                            *amount,
+                        ));
+                    }
+                    if from != to {
+                        balance = Some(if from == pk {
+                            current - amount
+                        } else {
+                            current + amount
+                        });
+                    }
                 }
+                _ => (),
             }
         }
-        delta
-    }
-
-    pub fn insert(&mut self, from: &str, to: &str, diff: Diff) {
-        self.0.insert((from.to_string(), to.to_string()), diff);
+        Ok(balance)
     }
 }
 
@@ -265,8 +289,8 @@ impl std::fmt::Debug for Ledger {
 impl std::fmt::Debug for LedgerDiff {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "[ ").unwrap();
-        for ((from, to), amount) in self.0.clone() {
-            write!(f, "{:?} ", (from, to, amount)).unwrap()
+        for diff in &self.0 {
+            write!(f, "{:?} ", diff).unwrap()
         }
         write!(f, "]")
     }
@@ -330,12 +354,12 @@ pub fn ledger_example() {
     ledger.apply(diff1.clone()).expect("coinbase apply is ok");
 
     // total increase
-    match diff1.0.get(&(a.clone(), a.clone())).unwrap() {
+    match &diff1.0[0] {
         Diff::Coinbase(_, amount) => {
             assert_eq!(ledger.total, old_ledger.total + amount);
         }
         _ => {
-            assert!(false);
+            unreachable!();
         }
     }
 
@@ -407,7 +431,10 @@ pub fn ledger_example() {
     let old_ledger = ledger.clone();
     assert_eq!(
         ledger.apply(diff4),
-        Err(Error::InvalidAbsentFromLedger(e.clone()))
+        Err(Error {
+            index: 0,
+            kind: ErrorKind::InvalidAbsentFromLedger(e.clone())
+        })
     );
     assert_eq!(old_ledger, ledger);
 
@@ -416,7 +443,10 @@ pub fn ledger_example() {
     let old_ledger = ledger.clone();
     assert_eq!(
         ledger.apply(diff5),
-        Err(Error::InvalidAbsentFromLedger(e.clone()))
+        Err(Error {
+            index: 0,
+            kind: ErrorKind::InvalidAbsentFromLedger(e.clone())
+        })
     );
     assert_eq!(old_ledger, ledger);
 
@@ -425,14 +455,23 @@ pub fn ledger_example() {
     let old_ledger = ledger.clone();
     assert_eq!(
         ledger.apply(diff6),
-        Err(Error::InvalidAbsentFromLedger(e.clone()))
+        Err(Error {
+            index: 0,
+            kind: ErrorKind::InvalidAbsentFromLedger(e.clone())
+        })
     );
     assert_eq!(old_ledger, ledger);
 
     // receiver doesn't exist
     let diff7 = LedgerDiff::from(&[Diff::Transfer(c, e.clone(), 5)]);
     let old_ledger = ledger.clone();
-    assert_eq!(ledger.apply(diff7), Err(Error::TransferAbsentFromLedger(e)));
+    assert_eq!(
+        ledger.apply(diff7),
+        Err(Error {
+            index: 0,
+            kind: ErrorKind::TransferAbsentFromLedger(e)
+        })
+    );
     assert_eq!(old_ledger, ledger);
 
     // insufficient funds error
@@ -440,7 +479,10 @@ pub fn ledger_example() {
     let old_ledger = ledger.clone();
     assert_eq!(
         ledger.apply(diff8),
-        Err(Error::InsufficientFunds(a, 85, 500))
+        Err(Error {
+            index: 0,
+            kind: ErrorKind::InsufficientFunds(a, 85, 500)
+        })
     );
     assert_eq!(old_ledger, ledger);
 
@@ -449,3 +491,94 @@ pub fn ledger_example() {
 
     // assert!(false); // uncomment to see stdout
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn pk() -> impl Strategy<Value = String> {
+        // E is never in the genesis ledger
+        prop::sample::select(vec!["A", "B", "C", "D", "E"]).prop_map(String::from)
+    }
+
+    fn ledger() -> impl Strategy<Value = Ledger> {
+        prop::collection::btree_map(
+            prop::sample::select(vec!["A", "B", "C", "D"]),
+            0u64..1000,
+            0..4,
+        )
+        .prop_map(|balances| {
+            Ledger::from_accounts(
+                balances
+                    .into_iter()
+                    .map(|(pk, balance)| Account::new(pk.to_string(), balance, 0)),
+            )
+        })
+    }
+
+    fn diffs() -> impl Strategy<Value = Vec<Diff>> {
+        let diff = prop_oneof![
+            pk().prop_map(Diff::Creation),
+            (pk(), 0u64..100).prop_map(|(pk, amount)| Diff::Coinbase(pk, amount)),
+            (pk(), pk()).prop_map(|(from, to)| Diff::Delegation(from, to)),
+            (pk(), pk(), 0u64..500).prop_map(|(from, to, amount)| Diff::Transfer(from, to, amount)),
+        ];
+        prop::collection::vec(diff, 0..8)
+    }
+
+    proptest! {
+        #[test]
+        fn failed_apply_leaves_ledger_unchanged(genesis in ledger(), diffs in diffs()) {
+            let mut ledger = genesis.clone();
+            match ledger.apply(LedgerDiff::from(&diffs)) {
+                Err(Error { index, .. }) => {
+                    prop_assert_eq!(&ledger, &genesis);
+
+                    // the diffs before the reported one apply, it doesn't
+                    prop_assert!(index < diffs.len());
+                    prop_assert!(ledger.apply(LedgerDiff::from(&diffs[..index])).is_ok());
+                    let before = ledger.clone();
+                    prop_assert!(ledger.apply(LedgerDiff::from(&diffs[index..=index])).is_err());
+                    prop_assert_eq!(ledger, before);
+                }
+                Ok(_) => {
+                    let balances: u64 = ledger.map.values().map(|a| a.balance).sum();
+                    prop_assert_eq!(ledger.total, balances);
+                }
+            }
+        }
+
+        #[test]
+        fn revert_restores_ledger(genesis in ledger(), blocks in prop::collection::vec(diffs(), 0..4)) {
+            let mut ledger = genesis.clone();
+            let mut undos = vec![];
+            for diffs in &blocks {
+                if let Ok(undo) = ledger.apply(LedgerDiff::from(diffs)) {
+                    undos.push((ledger.clone(), undo));
+                }
+            }
+            while let Some((applied, undo)) = undos.pop() {
+                prop_assert_eq!(&ledger, &applied);
+                ledger.revert(undo);
+            }
+            prop_assert_eq!(ledger, genesis);
+        }
+
+        #[test]
+        fn apply_is_deterministic(genesis in ledger(), diffs in diffs(), split in 0usize..8) {
+            let mut whole = genesis.clone();
+            let whole_result = whole.apply(LedgerDiff::from(&diffs));
+
+            let split = split.min(diffs.len());
+            let mut parts = genesis;
+            let parts_result = parts
+                .apply(LedgerDiff::from(&diffs[..split]))
+                .and_then(|_| parts.apply(LedgerDiff::from(&diffs[split..])));
+            if whole_result.is_ok() {
+                prop_assert!(parts_result.is_ok());
+                prop_assert_eq!(whole, parts);
+            }
+        }
+    }
+}
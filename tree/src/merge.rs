@@ -1,31 +1,164 @@
-use id_tree::{InsertBehavior::*, Node, NodeId, Tree};
+use id_tree::{InsertBehavior::*, Node, NodeId, RemoveBehavior::*, Tree};
 use std::collections::HashMap;
 
-#[allow(dead_code)]
-fn merge(base_tree: &mut Tree<i32>, incoming_tree: &mut Tree<i32>, junction_node_id: &NodeId) {
+/// Insert the incoming tree under the junction node in level order, via
+/// `insert(data, new_parent_id)`, returning each incoming node id's new id
+pub(crate) fn merge_with<T>(
+    incoming_tree: &Tree<T>,
+    junction_node_id: &NodeId,
+    insert: impl FnMut(&T, &NodeId) -> NodeId,
+) -> HashMap<NodeId, NodeId> {
+    merge_from(incoming_tree, junction_node_id, false, insert)
+}
+
+/// [`merge_with`], where a `duplicate_root` is the junction node, so only its
+/// children are inserted
+fn merge_from<T>(
+    incoming_tree: &Tree<T>,
+    junction_node_id: &NodeId,
+    duplicate_root: bool,
+    mut insert: impl FnMut(&T, &NodeId) -> NodeId,
+) -> HashMap<NodeId, NodeId> {
     let mut merge_id_map = HashMap::new();
+    let incoming_root_id = match incoming_tree.root_node_id() {
+        None => return merge_id_map,
+        Some(id) => id,
+    };
     // associate the incoming tree's root node id with it's new id in the base tree
-    let incoming_root_id = incoming_tree.root_node_id().unwrap();
-    let node_data = incoming_tree.get(incoming_root_id).unwrap().data();
-    let new_node_id = base_tree
-        .insert(Node::new(*node_data), UnderNode(junction_node_id))
-        .unwrap();
-    merge_id_map.insert(incoming_root_id, new_node_id);
+    let new_root_id = if duplicate_root {
+        junction_node_id.clone()
+    } else {
+        let node_data = incoming_tree.get(incoming_root_id).unwrap().data();
+        insert(node_data, junction_node_id)
+    };
+    merge_id_map.insert(incoming_root_id.clone(), new_root_id);
     let traversal_ids = incoming_tree
         .traverse_level_order_ids(incoming_root_id)
         .unwrap();
     for old_node_id in traversal_ids {
-        let mim = merge_id_map.clone();
-        let under_node_id = mim.get(&old_node_id).unwrap();
-        let children_ids = incoming_tree.children_ids(&old_node_id).unwrap();
-        for child_id in children_ids {
+        let under_node_id = merge_id_map[&old_node_id].clone();
+        for child_id in incoming_tree.children_ids(&old_node_id).unwrap() {
             let child_node_data = incoming_tree.get(child_id).unwrap().data();
-            let new_child_id = base_tree
-                .insert(Node::new(*child_node_data), UnderNode(under_node_id))
-                .unwrap();
-            merge_id_map.insert(child_id, new_child_id);
+            merge_id_map.insert(child_id.clone(), insert(child_node_data, &under_node_id));
         }
     }
+    merge_id_map
+}
+
+/// Move the incoming tree's nodes under the junction node in level order,
+/// returning each incoming node id's new id
+fn merge_owned_from<T>(
+    base_tree: &mut Tree<T>,
+    mut incoming_tree: Tree<T>,
+    junction_node_id: &NodeId,
+    duplicate_root: bool,
+) -> HashMap<NodeId, NodeId> {
+    let mut merge_id_map = HashMap::new();
+    let incoming_root_id = match incoming_tree.root_node_id() {
+        None => return merge_id_map,
+        Some(id) => id.clone(),
+    };
+    // parents are cleared as nodes are removed, so record them first
+    let traversal_ids: Vec<(NodeId, Option<NodeId>)> = incoming_tree
+        .traverse_level_order_ids(&incoming_root_id)
+        .unwrap()
+        .map(|id| {
+            let parent_id = incoming_tree.get(&id).unwrap().parent().cloned();
+            (id, parent_id)
+        })
+        .collect();
+    for (old_node_id, old_parent_id) in traversal_ids {
+        let node = incoming_tree
+            .remove_node(old_node_id.clone(), OrphanChildren)
+            .unwrap();
+        let new_node_id = match old_parent_id {
+            None if duplicate_root => junction_node_id.clone(),
+            None => base_tree.insert(node, UnderNode(junction_node_id)).unwrap(),
+            Some(old_parent_id) => base_tree
+                .insert(node, UnderNode(&merge_id_map[&old_parent_id]))
+                .unwrap(),
+        };
+        merge_id_map.insert(old_node_id, new_node_id);
+    }
+    merge_id_map
+}
+
+/// Whether the incoming tree's root has the same key as the junction node
+fn duplicates_junction<T, K: PartialEq>(
+    base_tree: &Tree<T>,
+    incoming_tree: &Tree<T>,
+    junction_node_id: &NodeId,
+    key: impl Fn(&T) -> K,
+) -> bool {
+    incoming_tree.root_node_id().is_some_and(|root_id| {
+        let junction = base_tree.get(junction_node_id).unwrap().data();
+        key(incoming_tree.get(root_id).unwrap().data()) == key(junction)
+    })
+}
+
+/// Copy the incoming tree under the junction node, returning each incoming
+/// node id's new id
+#[allow(dead_code)]
+pub(crate) fn merge<T: Clone>(
+    base_tree: &mut Tree<T>,
+    incoming_tree: &Tree<T>,
+    junction_node_id: &NodeId,
+) -> HashMap<NodeId, NodeId> {
+    merge_from(
+        incoming_tree,
+        junction_node_id,
+        false,
+        |node_data, under_node_id| {
+            base_tree
+                .insert(Node::new(node_data.clone()), UnderNode(under_node_id))
+                .unwrap()
+        },
+    )
+}
+
+/// [`merge`], except an incoming root with the same key as the junction node
+/// (e.g. a block's hash) is the junction node, so only its children are copied
+#[allow(dead_code)]
+pub(crate) fn merge_by_key<T: Clone, K: PartialEq>(
+    base_tree: &mut Tree<T>,
+    incoming_tree: &Tree<T>,
+    junction_node_id: &NodeId,
+    key: impl Fn(&T) -> K,
+) -> HashMap<NodeId, NodeId> {
+    let duplicate_root = duplicates_junction(base_tree, incoming_tree, junction_node_id, key);
+    merge_from(
+        incoming_tree,
+        junction_node_id,
+        duplicate_root,
+        |node_data, under_node_id| {
+            base_tree
+                .insert(Node::new(node_data.clone()), UnderNode(under_node_id))
+                .unwrap()
+        },
+    )
+}
+
+/// [`merge`], moving the incoming tree's nodes instead of cloning them
+pub(crate) fn merge_owned<T>(
+    base_tree: &mut Tree<T>,
+    incoming_tree: Tree<T>,
+    junction_node_id: &NodeId,
+) -> HashMap<NodeId, NodeId> {
+    merge_owned_from(base_tree, incoming_tree, junction_node_id, false)
+}
+
+/// [`merge_by_key`], moving the incoming tree's nodes instead of cloning them
+///
+/// a duplicate root is dropped
+#[allow(dead_code)]
+pub(crate) fn merge_owned_by_key<T, K: PartialEq>(
+    base_tree: &mut Tree<T>,
+    incoming_tree: Tree<T>,
+    junction_node_id: &NodeId,
+    key: impl Fn(&T) -> K,
+) -> HashMap<NodeId, NodeId> {
+    let duplicate_root = duplicates_junction(base_tree, &incoming_tree, junction_node_id, key);
+    merge_owned_from(base_tree, incoming_tree, junction_node_id, duplicate_root)
 }
 
 #[test]
@@ -85,10 +218,107 @@ pub fn merge_example() {
     incoming_tree.write_formatted(&mut w).unwrap();
     println!("{}", w);
 
-    merge(&mut base_tree, &mut incoming_tree, &node_id1);
+    let merge_id_map = merge(&mut base_tree, &incoming_tree, &node_id1);
+    assert_eq!(merge_id_map.len(), 6);
+    let new_root = base_tree.get(&merge_id_map[&incoming_root_id]).unwrap();
+    assert_eq!(new_root.data(), &4);
+    assert_eq!(new_root.parent(), Some(&node_id1));
 
     println!("=== Tree after merge ===");
     let mut w = String::new();
     base_tree.write_formatted(&mut w).unwrap();
     println!("{}", w);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// blocks, (hash, data)
+    type Block = (&'static str, String);
+
+    fn block(hash: &'static str) -> Block {
+        (hash, format!("block {hash}"))
+    }
+
+    /// base: a -> b, incoming: b -> {c -> e, d}
+    fn trees() -> (Tree<Block>, NodeId, Tree<Block>) {
+        let mut base = Tree::new();
+        let a = base.insert(Node::new(block("a")), AsRoot).unwrap();
+        let b = base.insert(Node::new(block("b")), UnderNode(&a)).unwrap();
+
+        let mut incoming = Tree::new();
+        let root = incoming.insert(Node::new(block("b")), AsRoot).unwrap();
+        let c = incoming
+            .insert(Node::new(block("c")), UnderNode(&root))
+            .unwrap();
+        incoming
+            .insert(Node::new(block("d")), UnderNode(&root))
+            .unwrap();
+        incoming
+            .insert(Node::new(block("e")), UnderNode(&c))
+            .unwrap();
+        (base, b, incoming)
+    }
+
+    /// each block's parent's hash
+    fn parents(tree: &Tree<Block>) -> Vec<(&str, Option<&str>)> {
+        let mut parents: Vec<_> = tree
+            .traverse_pre_order(tree.root_node_id().unwrap())
+            .unwrap()
+            .map(|node| {
+                let parent = node.parent().map(|id| tree.get(id).unwrap().data().0);
+                (node.data().0, parent)
+            })
+            .collect();
+        parents.sort();
+        parents
+    }
+
+    #[test]
+    fn merge_preserves_data() {
+        let (mut base, b, incoming) = trees();
+        let merge_id_map = merge(&mut base, &incoming, &b);
+        assert_eq!(merge_id_map.len(), 4);
+        for (old_id, new_id) in &merge_id_map {
+            assert_eq!(
+                incoming.get(old_id).unwrap().data(),
+                base.get(new_id).unwrap().data()
+            );
+        }
+
+        let (mut owned_base, b, incoming) = trees();
+        merge_owned(&mut owned_base, incoming, &b);
+        assert_eq!(parents(&owned_base), parents(&base));
+        assert_eq!(parents(&owned_base).len(), 6);
+    }
+
+    #[test]
+    fn merge_duplicate_junction() {
+        let expected = vec![
+            ("a", None),
+            ("b", Some("a")),
+            ("c", Some("b")),
+            ("d", Some("b")),
+            ("e", Some("c")),
+        ];
+
+        let (mut base, b, incoming) = trees();
+        let merge_id_map = merge_by_key(&mut base, &incoming, &b, |block| block.0);
+        assert_eq!(parents(&base), expected);
+        assert_eq!(merge_id_map[incoming.root_node_id().unwrap()], b);
+
+        let (mut base, b, incoming) = trees();
+        let root_id = incoming.root_node_id().unwrap().clone();
+        let merge_id_map = merge_owned_by_key(&mut base, incoming, &b, |block| block.0);
+        assert_eq!(parents(&base), expected);
+        assert_eq!(merge_id_map[&root_id], b);
+
+        // different keys merge as usual
+        let (mut base, _, incoming) = trees();
+        let a = base.root_node_id().unwrap().clone();
+        merge_by_key(&mut base, &incoming, &a, |block| block.0);
+        assert_eq!(base.children(&a).unwrap().count(), 2);
+        assert_eq!(parents(&base).len(), 6);
+    }
+}
@@ -4,10 +4,22 @@ use id_tree::NodeId;
 
 use crate::{ledger::block::Block, weighted::weighted_tree::WeightedTree};
 
+/// Trees of blocks, keyed by state hash, which arrive in any order
+///
+/// a block whose parent is unknown starts a new tree, which is merged under
+/// the parent when it arrives
 #[allow(dead_code)]
 struct WeightedForest {
-    indices: HashMap<usize, usize>,
-    trees: Vec<WeightedTree>,
+    trees: HashMap<usize, WeightedTree>,
+    /// index of the next new tree
+    next_tree: usize,
+    /// each block's tree index and node id
+    locations: HashMap<String, (usize, NodeId)>,
+    /// each node's block hash
+    hashes: HashMap<(usize, NodeId), String>,
+    /// trees whose root's parent hasn't arrived, by the parent's hash
+    dangling: HashMap<String, Vec<usize>>,
+    /// sum of the tree weights
     weight: u32,
 }
 
@@ -15,38 +27,110 @@ struct WeightedForest {
 impl WeightedForest {
     pub fn new() -> Self {
         Self {
-            indices: HashMap::new(),
-            trees: Vec::new(),
+            trees: HashMap::new(),
+            next_tree: 0,
+            locations: HashMap::new(),
+            hashes: HashMap::new(),
+            dangling: HashMap::new(),
             weight: 0,
         }
     }
 
-    pub fn insert(&mut self, block: Block, parent: Option<&NodeId>, idx: usize) -> NodeId {
-        self.weight += block.weight;
-        let id = match self.indices.get(&idx) {
+    /// tree index and node id of the block
+    pub fn location(&self, hash: &str) -> Option<&(usize, NodeId)> {
+        self.locations.get(hash)
+    }
+
+    /// the block's tree
+    pub fn tree_of(&self, hash: &str) -> Option<&WeightedTree> {
+        self.location(hash).map(|(idx, _)| &self.trees[idx])
+    }
+
+    /// hash of the block's parent, `None` for tree roots
+    pub fn parent_hash(&self, hash: &str) -> Option<&String> {
+        let (idx, node_id) = self.location(hash)?;
+        let parent = self.trees[idx].tree.get(node_id).unwrap().parent()?;
+        self.hashes.get(&(*idx, parent.clone()))
+    }
+
+    /// add the block under its parent, or as the root of a new tree if the
+    /// parent is unknown, then merge the trees waiting for the block under it
+    ///
+    /// a block with an unknown parent becomes the root of the biggest tree
+    /// waiting for it instead, so a chain arriving in reverse order is never
+    /// copied between trees
+    ///
+    /// returns the block's tree index and node id, `None` if it's already known
+    pub fn insert(
+        &mut self,
+        hash: &str,
+        parent_hash: &str,
+        block: Block,
+    ) -> Option<(usize, NodeId)> {
+        if self.locations.contains_key(hash) {
+            return None;
+        }
+        let block = block.with_hash(hash);
+        let mut waiting = self.dangling.remove(hash).unwrap_or_default();
+        let (idx, node_id) = match self.locations.get(parent_hash).cloned() {
+            Some((idx, parent)) => (idx, self.insert_into(idx, block, Some(&parent))),
             None => {
-                let mut tree = WeightedTree::new();
-                let id = tree.insert(block, None);
-                self.indices.insert(idx, self.trees.len());
-                self.trees.push(tree);
-                id
+                let biggest =
+                    (0..waiting.len()).max_by_key(|&n| self.trees[&waiting[n]].weights.len());
+                let idx = match biggest {
+                    Some(n) => waiting.swap_remove(n),
+                    None => {
+                        let idx = self.next_tree;
+                        self.next_tree += 1;
+                        self.trees.insert(idx, WeightedTree::new());
+                        idx
+                    }
+                };
+                self.dangling
+                    .entry(parent_hash.to_string())
+                    .or_default()
+                    .push(idx);
+                (idx, self.insert_into(idx, block, None))
             }
-            Some(i) => match self.trees.get_mut(*i) {
-                None => {
-                    let mut tree = WeightedTree::new();
-                    let id = tree.insert(block, None);
-                    self.trees.push(tree);
-                    id
-                }
-                Some(tree) => tree.insert(block, parent),
-            },
         };
-        id
+        self.locations
+            .insert(hash.to_string(), (idx, node_id.clone()));
+        self.hashes.insert((idx, node_id.clone()), hash.to_string());
+
+        for dangling in waiting {
+            self.join(dangling, idx, &node_id);
+        }
+        Some((idx, node_id))
+    }
+
+    /// insert the block into the tree, as its new root if there's no parent
+    fn insert_into(&mut self, idx: usize, block: Block, parent: Option<&NodeId>) -> NodeId {
+        let tree = self.trees.get_mut(&idx).unwrap();
+        self.weight -= tree.weight;
+        let node_id = tree.insert(block, parent);
+        self.weight += tree.weight;
+        node_id
+    }
+
+    /// move the incoming tree's blocks under the junction block of the base
+    /// tree
+    fn join(&mut self, incoming_idx: usize, base_idx: usize, junction: &NodeId) {
+        let incoming = self.trees.remove(&incoming_idx).unwrap();
+        let base = self.trees.get_mut(&base_idx).unwrap();
+        self.weight -= incoming.weight + base.weight;
+        let id_map = base.merge_owned(incoming, junction);
+        self.weight += base.weight;
+
+        for (old_id, new_id) in id_map {
+            let hash = self.hashes.remove(&(incoming_idx, old_id)).unwrap();
+            self.locations
+                .insert(hash.clone(), (base_idx, new_id.clone()));
+            self.hashes.insert((base_idx, new_id), hash);
+        }
     }
 }
 
 #[test]
-#[allow(unused_variables)]
 fn forest_example() {
     use crate::ledger::*;
 
@@ -54,74 +138,83 @@ fn forest_example() {
     println!("~~~~~ forest_example ~~~~~\n");
     println!("~~~~~~~~~~~~~~~~~~~~~~~~~~");
 
-    let mut forest = WeightedForest::new();
+    // Blocks, (hash, parent hash, pk, weight)
+    //
+    //      (0, _, A, 10)
+    //       /          \
+    // (1, 0, B, 15)  (2, 0, A, 2)
+    //       |
+    // (3, 1, C, 5)         (4, 9, B, 4)
+
     let a = "A".to_string();
     let b = "B".to_string();
     let c = "C".to_string();
-
-    // add to tree 0
-    let id0 = forest.insert(
-        Block::new(&a, 10, LedgerDiff::from(&[Diff::Coinbase(a.clone(), 2)])),
-        None,
-        0,
-    );
-    let id1 = forest.insert(
-        Block::new(
-            &b,
-            15,
-            LedgerDiff::from(&[Diff::Transfer(a.clone(), b.clone(), 3)]),
+    let blocks = [
+        (
+            "3",
+            "1",
+            Block::new(&c, 5, LedgerDiff::from(&[Diff::Coinbase(c.clone(), 2)])),
+        ),
+        (
+            "2",
+            "0",
+            Block::new(
+                &a,
+                2,
+                LedgerDiff::from(&[Diff::Transfer(a.clone(), b.clone(), 3)]),
+            ),
         ),
-        Some(&id0),
-        0,
-    );
-
-    // add to tree 2
-    let id6 = forest.insert(
-        Block::new(
-            &a,
-            3,
-            LedgerDiff::from(&[
-                Diff::Transfer(b.clone(), c.clone(), 2),
-                Diff::Coinbase(c.clone(), 3),
-            ]),
+        (
+            "4",
+            "9",
+            Block::new(
+                &b,
+                4,
+                LedgerDiff::from(&[Diff::Transfer(b.clone(), a.clone(), 2)]),
+            ),
         ),
-        None,
-        2,
-    );
-
-    // add to tree 0
-    let id2 = forest.insert(
-        Block::new(
-            &a,
-            2,
-            LedgerDiff::from(&[Diff::Transfer(a.clone(), b.clone(), 3)]),
+        (
+            "1",
+            "0",
+            Block::new(
+                &b,
+                15,
+                LedgerDiff::from(&[Diff::Transfer(a.clone(), b.clone(), 3)]),
+            ),
         ),
-        Some(&id0),
-        0,
-    );
-
-    // add to tree 1
-    let id4 = forest.insert(
-        Block::new(
-            &b,
-            4,
-            LedgerDiff::from(&[Diff::Transfer(b.clone(), a.clone(), 2)]),
+        (
+            "0",
+            "_",
+            Block::new(&a, 10, LedgerDiff::from(&[Diff::Coinbase(a.clone(), 2)])),
         ),
-        None,
-        1,
-    );
-    let id5 = forest.insert(
-        Block::new(&c, 1, LedgerDiff::from(&[Diff::Transfer(a.clone(), b, 2)])),
-        None,
-        1,
-    );
-
-    // add to tree 0
-    let id3 = forest.insert(
-        Block::new(&c, 5, LedgerDiff::from(&[Diff::Transfer(c.clone(), a, 2)])),
-        None,
-        0,
-    );
+    ];
+
+    let mut forest = WeightedForest::new();
+    for (hash, parent_hash, block) in blocks.clone() {
+        assert!(forest.insert(hash, parent_hash, block).is_some());
+    }
+    assert!(forest.insert("1", "0", blocks[3].2.clone()).is_none());
+
+    // the out of order blocks were joined under their parents
+    assert_eq!(forest.trees.len(), 2);
+    assert_eq!(forest.parent_hash("3"), Some(&"1".to_string()));
+    assert_eq!(forest.parent_hash("1"), Some(&"0".to_string()));
+    assert_eq!(forest.parent_hash("2"), Some(&"0".to_string()));
+    assert_eq!(forest.parent_hash("0"), None);
+    assert_eq!(forest.parent_hash("4"), None);
+
+    let tree = forest.tree_of("3").unwrap();
+    let (_, root) = forest.location("0").unwrap();
+    assert_eq!(tree.tree.root_node_id(), Some(root));
+    assert_eq!(tree.weight, 10 + 15 + 5);
+    assert_eq!(forest.weight, 10 + 15 + 5 + 4);
+    for (hash, _, block) in blocks {
+        let (idx, node_id) = forest.location(hash).unwrap();
+        assert_eq!(
+            forest.trees[idx].tree.get(node_id).unwrap().data(),
+            &block.with_hash(hash)
+        );
+    }
 
     // print final forest
     println!("{:?}", forest);
@@ -129,12 +222,84 @@ fn forest_example() {
     // assert!(false); // uncomment to see stdout
 }
 
+#[test]
+fn reverse_order_chain() {
+    use crate::ledger::*;
+
+    let pks = ["A", "B", "C"].map(String::from);
+    let n = 1000;
+    let mut forest = WeightedForest::new();
+    let mut first = None;
+    for i in (0..n).rev() {
+        let pk = &pks[i % pks.len()];
+        let block = Block::new(pk, 1 + i as u32 % 7, LedgerDiff::from(&[]));
+        let (idx, _) = forest
+            .insert(&i.to_string(), &(i as i64 - 1).to_string(), block)
+            .unwrap();
+
+        // each block roots the tree of its child, no tree is copied
+        assert_eq!(*first.get_or_insert(idx), idx);
+        assert_eq!(forest.trees.len(), 1);
+    }
+
+    let tree = forest.tree_of("0").unwrap();
+    let (_, root) = forest.location("0").unwrap();
+    assert_eq!(tree.tree.root_node_id(), Some(root));
+    assert_eq!(tree.tree.height(), n);
+    assert_eq!(forest.parent_hash("0"), None);
+    for i in 1..n {
+        assert_eq!(
+            forest.parent_hash(&i.to_string()),
+            Some(&(i - 1).to_string())
+        );
+    }
+    assert_eq!(forest.dangling.len(), 1);
+    assert_eq!(forest.dangling["-1"], vec![first.unwrap()]);
+    assert_eq!(forest.weight, tree.weight);
+    assert_eq!(tree.support(root).unwrap(), tree.weight);
+}
+
+#[test]
+fn chain_before_its_parent() {
+    use crate::ledger::*;
+
+    let pks = ["A", "B", "C"].map(String::from);
+    let block = |i: usize| Block::new(&pks[i % pks.len()], 1 + i as u32 % 7, LedgerDiff::from(&[]));
+    let n = 200;
+    let mut forest = WeightedForest::new();
+    forest.insert("0", "-1", block(0));
+    for i in 2..n {
+        forest.insert(&i.to_string(), &(i - 1).to_string(), block(i));
+    }
+    assert_eq!(forest.trees.len(), 2);
+
+    // the chain is moved under its parent once it arrives
+    forest.insert("1", "0", block(1));
+    assert_eq!(forest.trees.len(), 1);
+    let tree = forest.tree_of("0").unwrap();
+    let (_, root) = forest.location("0").unwrap();
+    assert_eq!(tree.tree.height(), n);
+    for i in 1..n {
+        assert_eq!(
+            forest.parent_hash(&i.to_string()),
+            Some(&(i - 1).to_string())
+        );
+        let (_, node_id) = forest.location(&i.to_string()).unwrap();
+        assert_eq!(
+            tree.support(node_id).unwrap(),
+            tree.subtree_support(node_id).unwrap()
+        );
+    }
+    assert_eq!(tree.support(root).unwrap(), tree.weight);
+    assert_eq!(forest.weight, tree.weight);
+}
+
 impl std::fmt::Debug for WeightedForest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut res = Ok(());
         writeln!(f, "=== Weighted Forest ===\n").unwrap();
         writeln!(f, "Forest weight: {}\n", self.weight).unwrap();
-        let mut trees = self.trees.clone();
+        let mut trees: Vec<&WeightedTree> = self.trees.values().collect();
         trees.sort();
         for (n, tree) in trees.iter().enumerate() {
             writeln!(f, "**********").unwrap();
@@ -1,8 +1,17 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    iter::once,
+};
 
 use id_tree::{InsertBehavior::*, Node, NodeId, NodeIdError, Tree};
 
-use crate::ledger::block::Block;
+use crate::{
+    ledger::{self, block::Block, AccountDiff, Ledger, UndoLog},
+    merge::{merge_owned, merge_with},
+};
+
+/// default number of blocks between ledger snapshots
+pub const SNAPSHOT_INTERVAL: usize = 100;
 
 #[derive(Clone)]
 pub struct WeightedTree {
@@ -15,6 +24,60 @@ pub struct WeightedTree {
     pub pk_weights: HashMap<String, u32>,
     /// disjoint sets of each BP's blocks
     pub pk_blocks: HashMap<String, HashSet<NodeId>>,
+    /// each block's supporters, i.e. the max weight of each BP in its subtree
+    supporters: HashMap<NodeId, HashMap<String, u32>>,
+    /// each block's support, i.e. the sum of its supporters' weights
+    supports: HashMap<NodeId, u32>,
+    /// number of blocks between ledger snapshots
+    pub snapshot_interval: usize,
+    /// memoized ledgers of every `snapshot_interval`th block of each branch
+    snapshots: HashMap<NodeId, Ledger>,
+    /// ledger before the root block, which the snapshots are computed from
+    genesis: Option<Ledger>,
+    tracked: Option<TrackedLedger>,
+    /// best tip as of the last `update_head`
+    head: Option<NodeId>,
+}
+
+/// How the best tip is picked
+///
+/// ties are broken in favor of the block with the lowest hash, then pk, so
+/// the head doesn't depend on the order the blocks arrived in. Blocks with
+/// the same hash and pk, e.g. without hashes, tie in favor of the first in a
+/// pre-order traversal
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForkChoiceRule {
+    /// deepest leaf
+    LongestChain,
+    /// GHOST, i.e. from the root, repeatedly step into the child with the
+    /// most support
+    HeaviestSubtree,
+    /// leaf with the heaviest branch, i.e. the sum of the block weights from
+    /// the root to the leaf
+    HeaviestBranch,
+}
+
+/// The best tip moved
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForkChoiceUpdate {
+    /// `None` for the first head
+    pub old_tip: Option<NodeId>,
+    pub new_tip: NodeId,
+    /// last block shared by the old and new tips' branches
+    pub common_ancestor: Option<NodeId>,
+    /// number of blocks of the old branch above the common ancestor
+    pub reorg_depth: usize,
+}
+
+/// A ledger kept at a block, which moves between branches by undoing and
+/// applying diffs
+#[derive(Clone)]
+struct TrackedLedger {
+    ledger: Ledger,
+    /// blocks applied to the genesis ledger, from the root, with their undo logs
+    applied: Vec<(NodeId, UndoLog)>,
 }
 
 #[allow(dead_code)]
@@ -23,11 +86,18 @@ pub enum WeightError {
     WeightCalculationError,
 }
 
-#[allow(dead_code)]
+#[allow(dead_code, clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum TreeError {
     NodeIdError(NodeIdError),
     WeightError(WeightError),
+    LedgerError(ledger::Error),
+    /// `track_ledger` was never called
+    LedgerNotTracked,
+    /// the node isn't a block of the tracked ledger's branch
+    NotAnAncestor(NodeId),
+    /// `set_genesis` was never called
+    GenesisNotSet,
 }
 
 impl WeightedTree {
@@ -38,6 +108,21 @@ impl WeightedTree {
             weights: HashMap::new(),
             pk_weights: HashMap::new(),
             pk_blocks: HashMap::new(),
+            supporters: HashMap::new(),
+            supports: HashMap::new(),
+            snapshot_interval: SNAPSHOT_INTERVAL,
+            snapshots: HashMap::new(),
+            genesis: None,
+            tracked: None,
+            head: None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_snapshot_interval(snapshot_interval: usize) -> Self {
+        Self {
+            snapshot_interval: snapshot_interval.max(1),
+            ..Self::new()
         }
     }
 
@@ -53,88 +138,470 @@ impl WeightedTree {
         }
     }
 
-    // TODO fn add_block_weight
-    // TODO fn add_tree_weight
+    /// record the block's weight and the BP's new block
+    fn add_block_weight(&mut self, pk: &str, weight: u32, node_id: &NodeId) {
+        self.weights.insert(node_id.clone(), weight);
+        self.add_bp_block_id(pk, node_id);
+    }
+
+    /// a BP counts once in the tree weight, at their max block weight
+    fn add_tree_weight(&mut self, pk: &str, weight: u32) {
+        let max = self.pk_weights.entry(pk.to_string()).or_insert(0);
+        if weight > *max {
+            self.weight += weight - *max;
+            *max = weight;
+        }
+    }
+
+    /// raise the BP's weight in the supporters of the node and its ancestors,
+    /// stopping at the first one already supported by the BP at that weight
+    fn add_support(&mut self, pk: &str, weight: u32, node_id: &NodeId) {
+        let ancestors = self.tree.ancestor_ids(node_id).unwrap().cloned();
+        for id in once(node_id.clone()).chain(ancestors) {
+            let supporters = self.supporters.entry(id.clone()).or_default();
+            let old = match supporters.get(pk) {
+                Some(&old) if old >= weight => break,
+                old => old.copied().unwrap_or(0),
+            };
+            supporters.insert(pk.to_string(), weight);
+            *self.supports.entry(id).or_insert(0) += weight - old;
+        }
+    }
 
     fn new_root(&mut self, block: Block) -> NodeId {
-        let weight = if !self.pk_weights.contains_key(&block.pk) {
-            self.weight += block.weight;
-            block.weight
-        } else {
-            let w = self.weight.max(block.weight);
-            self.weight += w - self.pk_weights.get(&block.pk).unwrap();
-            w
-        };
+        // the old root's ledgers now include the new root's diff
+        self.snapshots.clear();
+        if let Some(tracked) = &mut self.tracked {
+            for (_, undo) in tracked.applied.drain(..).rev() {
+                tracked.ledger.revert(undo);
+            }
+        }
+        let old_root = self.tree.root_node_id().cloned();
         let id = self.tree.insert(Node::new(block.clone()), AsRoot).unwrap();
-        self.pk_weights.insert(block.pk.clone(), weight);
-        self.add_bp_block_id(&block.pk, &id);
-        self.weights.insert(id.clone(), block.weight);
+        // the old root's supporters all support the new root
+        if let Some(old_root) = old_root {
+            self.supporters
+                .insert(id.clone(), self.supporters[&old_root].clone());
+            self.supports.insert(id.clone(), self.supports[&old_root]);
+        }
+        self.add_block_weight(&block.pk, block.weight, &id);
+        self.add_tree_weight(&block.pk, block.weight);
+        self.add_support(&block.pk, block.weight, &id);
         id
     }
 
     fn new_leaf(&mut self, block: Block, parent: &NodeId) -> NodeId {
-        if !self.pk_weights.contains_key(&block.pk) {
-            self.weight += block.weight;
-            block.weight
-        } else {
-            let w = self.weight.max(block.weight);
-            self.weight += w - self.weight.min(block.weight);
-            w
-        };
         let id = self
             .tree
-            .insert(Node::new(block), UnderNode(parent))
+            .insert(Node::new(block.clone()), UnderNode(parent))
             .unwrap();
+        self.add_block_weight(&block.pk, block.weight, &id);
+        self.add_tree_weight(&block.pk, block.weight);
+        self.add_support(&block.pk, block.weight, &id);
         id
     }
 
     pub fn insert(&mut self, block: Block, parent: Option<&NodeId>) -> NodeId {
-        let id = match parent {
-            None => self.new_root(block.clone()),
-            Some(parent) => self.new_leaf(block.clone(), parent),
-        };
-        self.weights.insert(id.clone(), block.weight);
-        id
+        match parent {
+            None => self.new_root(block),
+            Some(parent) => self.new_leaf(block, parent),
+        }
+    }
+
+    /// insert the incoming tree's blocks under the junction block, returning
+    /// each incoming node id's new id
+    #[allow(dead_code)]
+    pub fn merge(&mut self, incoming: &WeightedTree, junction: &NodeId) -> HashMap<NodeId, NodeId> {
+        merge_with(&incoming.tree, junction, |block, parent| {
+            self.insert(block.clone(), Some(parent))
+        })
     }
 
-    // block support => weight of subtree under the block
+    /// [`WeightedTree::merge`], moving the incoming tree's blocks with their
+    /// cached weights and supports instead of re-inserting them
+    ///
+    /// only the supports of the junction block and its ancestors change, so
+    /// they're raised once per BP of the incoming tree
+    pub fn merge_owned(
+        &mut self,
+        incoming: WeightedTree,
+        junction: &NodeId,
+    ) -> HashMap<NodeId, NodeId> {
+        let WeightedTree {
+            tree,
+            weights,
+            pk_weights,
+            pk_blocks,
+            mut supporters,
+            mut supports,
+            ..
+        } = incoming;
+        let root_supporters = tree
+            .root_node_id()
+            .and_then(|root| supporters.get(root))
+            .cloned()
+            .unwrap_or_default();
+
+        let id_map = merge_owned(&mut self.tree, tree, junction);
+        for (old_id, new_id) in &id_map {
+            if let Some(weight) = weights.get(old_id) {
+                self.weights.insert(new_id.clone(), *weight);
+            }
+            if let Some(block_supporters) = supporters.remove(old_id) {
+                self.supporters.insert(new_id.clone(), block_supporters);
+            }
+            if let Some(support) = supports.remove(old_id) {
+                self.supports.insert(new_id.clone(), support);
+            }
+        }
+        for (pk, ids) in pk_blocks {
+            for id in ids {
+                self.add_bp_block_id(&pk, &id_map[&id]);
+            }
+        }
+        for (pk, weight) in pk_weights {
+            self.add_tree_weight(&pk, weight);
+        }
+        for (pk, weight) in root_supporters {
+            self.add_support(&pk, weight, junction);
+        }
+        id_map
+    }
+
+    /// block support => weight of the subtree under the block, each BP
+    /// counted once at their max weight in the subtree
+    ///
+    /// cached on insert, so blocks must be added via [`WeightedTree::insert`]
     pub fn support(&self, node_id: &NodeId) -> Result<u32, TreeError> {
-        match self.tree.get(node_id) {
-            Ok(_) => {
-                let mut supporters = HashMap::new();
-                let res = Ok(self
-                    .tree
-                    .traverse_level_order_ids(node_id)
-                    .unwrap()
+        self.tree.get(node_id).map_err(TreeError::NodeIdError)?;
+        Ok(self.supports.get(node_id).copied().unwrap_or(0))
+    }
+
+    /// the node's child with the most support, the lowest by
+    /// [`WeightedTree::tie_key`] on ties
+    #[allow(dead_code)]
+    pub fn heaviest_child(&self, node_id: &NodeId) -> Result<Option<NodeId>, TreeError> {
+        let mut heaviest: Option<(&NodeId, u32)> = None;
+        for id in self
+            .tree
+            .children_ids(node_id)
+            .map_err(TreeError::NodeIdError)?
+        {
+            let support = self.support(id)?;
+            if heaviest.is_none_or(|(best, max)| {
+                support > max || support == max && self.tie_key(id) < self.tie_key(best)
+            }) {
+                heaviest = Some((id, support));
+            }
+        }
+        Ok(heaviest.map(|(id, _)| id.clone()))
+    }
+
+    /// [`WeightedTree::support`], computed by walking the whole subtree
+    #[allow(dead_code)]
+    pub fn subtree_support(&self, node_id: &NodeId) -> Result<u32, TreeError> {
+        let ids = self
+            .tree
+            .traverse_level_order_ids(node_id)
+            .map_err(TreeError::NodeIdError)?;
+        let mut supporters: HashMap<&str, u32> = HashMap::new();
+        for id in ids {
+            let block = self.tree.get(&id).unwrap().data();
+            let max = supporters.entry(&block.pk).or_insert(0);
+            *max = (*max).max(block.weight);
+        }
+        Ok(supporters.values().sum())
+    }
+
+    /// set the ledger before the root block, for `ledger_at` and `balance_at`
+    #[allow(dead_code)]
+    pub fn set_genesis(&mut self, genesis: Ledger) {
+        self.snapshots.clear();
+        self.genesis = Some(genesis);
+    }
+
+    /// the blocks since the closest snapshot, from the node down, and that
+    /// snapshot's ledger, which is the genesis ledger without one
+    fn since_snapshot(&self, node_id: &NodeId) -> Result<(Vec<NodeId>, &Ledger), TreeError> {
+        let genesis = self.genesis.as_ref().ok_or(TreeError::GenesisNotSet)?;
+        let mut branch = vec![node_id.clone()];
+        let mut ancestors = self
+            .tree
+            .ancestor_ids(node_id)
+            .map_err(TreeError::NodeIdError)?;
+        loop {
+            let last = branch.last().unwrap();
+            if let Some(snapshot) = self.snapshots.get(last) {
+                branch.pop();
+                return Ok((branch, snapshot));
+            }
+            match ancestors.next() {
+                Some(id) => branch.push(id.clone()),
+                None => return Ok((branch, genesis)),
+            }
+        }
+    }
+
+    /// ledger after applying the diffs of each block from the root to the node
+    ///
+    /// every `snapshot_interval`th ledger of the branch is memoized, so only
+    /// the diffs since the closest snapshot below the node are applied
+    #[allow(dead_code)]
+    pub fn ledger_at(&mut self, node_id: &NodeId) -> Result<Ledger, TreeError> {
+        let (branch, ledger) = self.since_snapshot(node_id)?;
+        let mut ledger = ledger.clone();
+
+        let depth = self.tree.ancestor_ids(node_id).unwrap().count();
+        let first_depth = depth + 1 - branch.len();
+        for (i, id) in branch.iter().rev().enumerate() {
+            let diff = self.tree.get(id).unwrap().data().diff.clone();
+            ledger.apply(diff).map_err(TreeError::LedgerError)?;
+            if (first_depth + i).is_multiple_of(self.snapshot_interval) {
+                self.snapshots.insert(id.clone(), ledger.clone());
+            }
+        }
+        Ok(ledger)
+    }
+
+    /// balance of `pk` in the ledger at the node
+    ///
+    /// read from the closest snapshot, then only the diffs involving `pk` are
+    /// replayed, so unlike `ledger_at` the other accounts' diffs aren't checked
+    #[allow(dead_code)]
+    pub fn balance_at(&self, node_id: &NodeId, pk: &str) -> Result<Option<u64>, TreeError> {
+        let (branch, ledger) = self.since_snapshot(node_id)?;
+        let mut balance = ledger.balance(pk);
+        for id in branch.iter().rev() {
+            let diff = &self.tree.get(id).unwrap().data().diff;
+            balance = diff
+                .balance_after(pk, balance)
+                .map_err(TreeError::LedgerError)?;
+        }
+        Ok(balance)
+    }
+
+    /// accounts which differ from the ledger at `a` to the ledger at `b`
+    #[allow(dead_code)]
+    pub fn fork_diff(
+        &mut self,
+        a: &NodeId,
+        b: &NodeId,
+    ) -> Result<BTreeMap<String, AccountDiff>, TreeError> {
+        let ledger_a = self.ledger_at(a)?;
+        let ledger_b = self.ledger_at(b)?;
+        Ok(ledger_a.diff(&ledger_b))
+    }
+
+    /// track a ledger, starting from `genesis` before the root block
+    ///
+    /// it's moved between blocks with `switch_ledger_to` and `rollback_to`,
+    /// only applying and reverting the diffs of the blocks in between. A new
+    /// root moves it back to `genesis`.
+    #[allow(dead_code)]
+    pub fn track_ledger(&mut self, genesis: Ledger) {
+        self.tracked = Some(TrackedLedger {
+            ledger: genesis,
+            applied: Vec::new(),
+        });
+    }
+
+    /// the tracked ledger and its block, `None` before the root block
+    #[allow(dead_code)]
+    pub fn tracked_ledger(&self) -> Option<(&Ledger, Option<&NodeId>)> {
+        self.tracked
+            .as_ref()
+            .map(|t| (&t.ledger, t.applied.last().map(|(id, _)| id)))
+    }
+
+    /// revert the tracked ledger's blocks above `ancestor`
+    #[allow(dead_code)]
+    pub fn rollback_to(&mut self, ancestor: &NodeId) -> Result<&Ledger, TreeError> {
+        let tracked = self.tracked.as_mut().ok_or(TreeError::LedgerNotTracked)?;
+        let position = tracked
+            .applied
+            .iter()
+            .position(|(id, _)| id == ancestor)
+            .ok_or_else(|| TreeError::NotAnAncestor(ancestor.clone()))?;
+        for (_, undo) in tracked.applied.drain(position + 1..).rev() {
+            tracked.ledger.revert(undo);
+        }
+        Ok(&tracked.ledger)
+    }
+
+    /// move the tracked ledger to the node, rolling back to the common
+    /// ancestor then applying the blocks of the node's branch
+    ///
+    /// if a block's diff doesn't apply, the ledger is moved back to where it
+    /// was
+    #[allow(dead_code)]
+    pub fn switch_ledger_to(&mut self, node_id: &NodeId) -> Result<&Ledger, TreeError> {
+        let tracked = self.tracked.as_mut().ok_or(TreeError::LedgerNotTracked)?;
+        let applied: HashMap<&NodeId, usize> = tracked
+            .applied
+            .iter()
+            .enumerate()
+            .map(|(i, (id, _))| (id, i + 1))
+            .collect();
+
+        // blocks of the new branch, from the node down to the common ancestor
+        let mut branch = vec![];
+        let mut common = 0;
+        let ancestors = self
+            .tree
+            .ancestor_ids(node_id)
+            .map_err(TreeError::NodeIdError)?;
+        for id in once(node_id).chain(ancestors) {
+            if let Some(len) = applied.get(id) {
+                common = *len;
+                break;
+            }
+            branch.push(id.clone());
+        }
+
+        let old_branch: Vec<NodeId> = tracked.applied[common..]
+            .iter()
+            .map(|(id, _)| id.clone())
+            .collect();
+        for (_, undo) in tracked.applied.drain(common..).rev() {
+            tracked.ledger.revert(undo);
+        }
+        for id in branch.into_iter().rev() {
+            let diff = self.tree.get(&id).unwrap().data().diff.clone();
+            match tracked.ledger.apply(diff) {
+                Ok(undo) => tracked.applied.push((id, undo)),
+                Err(err) => {
+                    for (_, undo) in tracked.applied.drain(common..).rev() {
+                        tracked.ledger.revert(undo);
+                    }
+                    for id in old_branch {
+                        let diff = self.tree.get(&id).unwrap().data().diff.clone();
+                        let undo = tracked
+                            .ledger
+                            .apply(diff)
+                            .expect("the old branch applied before");
+                        tracked.applied.push((id, undo));
+                    }
+                    return Err(TreeError::LedgerError(err));
+                }
+            }
+        }
+        Ok(&tracked.ledger)
+    }
+
+    /// best tip by the rule, `None` for an empty tree
+    #[allow(dead_code)]
+    pub fn best_tip(&self, rule: ForkChoiceRule) -> Result<Option<NodeId>, TreeError> {
+        let root = match self.tree.root_node_id() {
+            None => return Ok(None),
+            Some(root) => root.clone(),
+        };
+        let leaves = || {
+            self.tree
+                .traverse_pre_order_ids(&root)
+                .unwrap()
+                .filter(|id| self.tree.children_ids(id).unwrap().next().is_none())
+        };
+        // leaf with the max score, the lowest by tie key on ties
+        let best = |scores: Vec<(NodeId, u32)>| {
+            let mut best: Option<(NodeId, u32)> = None;
+            for (id, score) in scores {
+                if best.as_ref().is_none_or(|(best, max)| {
+                    score > *max || score == *max && self.tie_key(&id) < self.tie_key(best)
+                }) {
+                    best = Some((id, score));
+                }
+            }
+            best.map(|(id, _)| id)
+        };
+
+        Ok(match rule {
+            ForkChoiceRule::LongestChain => best(
+                leaves()
                     .map(|id| {
-                        let pk = self.tree.get(&id).unwrap().data().pk.clone();
-                        let weight = self.tree.get(&id).unwrap().data().weight;
-                        match supporters.get(&pk) {
-                            None => {
-                                supporters.insert(pk, weight);
-                                weight
-                            }
-                            Some(&old_weight) => {
-                                let w = old_weight.max(weight);
-                                supporters.insert(pk, w);
-                                w - old_weight.min(weight)
-                            }
-                        }
+                        let depth = self.tree.ancestor_ids(&id).unwrap().count();
+                        (id, depth as u32)
                     })
-                    .reduce(|acc, w| acc + w)
-                    .unwrap());
+                    .collect(),
+            ),
+            ForkChoiceRule::HeaviestSubtree => {
+                let mut tip = root;
+                while let Some(child) = self.heaviest_child(&tip)? {
+                    tip = child;
+                }
+                Some(tip)
+            }
+            ForkChoiceRule::HeaviestBranch => best(
+                leaves()
+                    .map(|id| {
+                        let weight = self.weights[&id] + self.branch_support(&id)?;
+                        Ok((id, weight))
+                    })
+                    .collect::<Result<_, TreeError>>()?,
+            ),
+        })
+    }
 
-                res
+    /// the block's hash then pk, which break fork choice ties
+    fn tie_key(&self, node_id: &NodeId) -> (&str, &str) {
+        let block = self.tree.get(node_id).unwrap().data();
+        (&block.hash, &block.pk)
+    }
+
+    /// the best tip as of the last `update_head`
+    #[allow(dead_code)]
+    pub fn head(&self) -> Option<&NodeId> {
+        self.head.as_ref()
+    }
+
+    /// move the head to the best tip by the rule, returning the update if it
+    /// changed
+    #[allow(dead_code)]
+    pub fn update_head(
+        &mut self,
+        rule: ForkChoiceRule,
+    ) -> Result<Option<ForkChoiceUpdate>, TreeError> {
+        let new_tip = match self.best_tip(rule)? {
+            Some(tip) if Some(&tip) != self.head.as_ref() => tip,
+            _ => return Ok(None),
+        };
+        let old_tip = self.head.replace(new_tip.clone());
+        let (common_ancestor, reorg_depth) = match &old_tip {
+            None => (None, 0),
+            Some(old_tip) => {
+                let (common, depth) = self.common_ancestor(old_tip, &new_tip)?;
+                (Some(common), depth)
             }
-            Err(err) => Err(TreeError::NodeIdError(err)),
-        }
+        };
+        Ok(Some(ForkChoiceUpdate {
+            old_tip,
+            new_tip,
+            common_ancestor,
+            reorg_depth,
+        }))
+    }
+
+    /// last block on both nodes' branches, and its distance from `a`
+    fn common_ancestor(&self, a: &NodeId, b: &NodeId) -> Result<(NodeId, usize), TreeError> {
+        let branch_b: HashSet<&NodeId> = once(b)
+            .chain(self.tree.ancestor_ids(b).map_err(TreeError::NodeIdError)?)
+            .collect();
+        Ok(once(a)
+            .chain(self.tree.ancestor_ids(a).map_err(TreeError::NodeIdError)?)
+            .enumerate()
+            .find(|(_, id)| branch_b.contains(id))
+            .map(|(depth, id)| (id.clone(), depth))
+            .expect("blocks of a tree share the root"))
     }
 
     /// sums weights of ancestors of the node and records the value in the node
     #[allow(dead_code)]
     pub fn branch_support(&self, node_id: &NodeId) -> Result<u32, TreeError> {
         let mut sum = Ok(0);
-        for id in self.tree.ancestor_ids(node_id).unwrap() {
+        for id in self
+            .tree
+            .ancestor_ids(node_id)
+            .map_err(TreeError::NodeIdError)?
+        {
             match self.tree.get(id) {
                 Ok(node) => {
                     if let Ok(mut s) = sum {
@@ -161,13 +628,13 @@ impl Eq for WeightedTree {}
 
 impl PartialOrd for WeightedTree {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.weight.cmp(&other.weight))
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for WeightedTree {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        self.weight.cmp(&other.weight)
     }
 }
 
@@ -189,6 +656,188 @@ mod tests {
     #[allow(unused_imports)]
     use super::*;
 
+    #[test]
+    fn ledger_at() {
+        use crate::ledger::{account::Account, *};
+
+        // Tree, with snapshots every 2 blocks
+        //          (a, A -> B 10)
+        //           /          \
+        // (b, coinbase A 5)  (c, B -> A 3)
+        //          |
+        // (d, A -> B 1)
+        //          |
+        // (e, create C)
+
+        let (a, b) = ("A".to_string(), "B".to_string());
+        let genesis = Ledger::from_accounts([
+            Account::new(a.clone(), 100, 0),
+            Account::new(b.clone(), 50, 0),
+        ]);
+        let tree = &mut WeightedTree::with_snapshot_interval(2);
+        let root = tree.insert(
+            Block::new(
+                "a",
+                1,
+                LedgerDiff::from(&[Diff::Transfer(a.clone(), b.clone(), 10)]),
+            ),
+            None,
+        );
+        let node_b = tree.insert(
+            Block::new("b", 1, LedgerDiff::from(&[Diff::Coinbase(a.clone(), 5)])),
+            Some(&root),
+        );
+        let node_c = tree.insert(
+            Block::new(
+                "c",
+                1,
+                LedgerDiff::from(&[Diff::Transfer(b.clone(), a.clone(), 3)]),
+            ),
+            Some(&root),
+        );
+        let node_d = tree.insert(
+            Block::new(
+                "d",
+                1,
+                LedgerDiff::from(&[Diff::Transfer(a.clone(), b.clone(), 1)]),
+            ),
+            Some(&node_b),
+        );
+        let node_e = tree.insert(
+            Block::new("e", 1, LedgerDiff::from(&[Diff::Creation("C".into())])),
+            Some(&node_d),
+        );
+
+        assert!(matches!(
+            tree.ledger_at(&node_e),
+            Err(TreeError::GenesisNotSet)
+        ));
+        tree.set_genesis(genesis.clone());
+
+        let balances = |tree: &mut WeightedTree, node| {
+            let ledger = tree.ledger_at(node).unwrap();
+            (ledger.balance(&a), ledger.balance(&b), ledger.total)
+        };
+        assert_eq!(balances(tree, &node_e), (Some(94), Some(61), 155));
+        assert_eq!(tree.snapshots.len(), 2);
+        assert!(tree.snapshots.contains_key(&root) && tree.snapshots.contains_key(&node_d));
+        assert_eq!(balances(tree, &node_b), (Some(95), Some(60), 155));
+        assert_eq!(balances(tree, &node_c), (Some(93), Some(57), 150));
+
+        // balances replayed from a snapshot, or without any, match the ledgers
+        for clear in [false, true] {
+            for node in [&root, &node_b, &node_c, &node_d, &node_e] {
+                let ledger = tree.ledger_at(node).unwrap();
+                if clear {
+                    tree.snapshots.clear();
+                }
+                for pk in ["A", "B", "C", "D"] {
+                    assert_eq!(tree.balance_at(node, pk).unwrap(), ledger.balance(pk));
+                }
+            }
+        }
+
+        // from a snapshot, or without any
+        let from_snapshot = tree.ledger_at(&node_e).unwrap();
+        tree.snapshots.clear();
+        assert_eq!(tree.ledger_at(&node_e).unwrap(), from_snapshot);
+
+        let diff = tree.fork_diff(&node_c, &node_e).unwrap();
+        assert_eq!(diff.len(), 3);
+        assert_eq!(
+            diff[&a],
+            AccountDiff::Changed {
+                before: Account::new(a.clone(), 93, 0),
+                after: Account::new(a.clone(), 94, 0),
+            }
+        );
+        assert!(matches!(diff["C"], AccountDiff::Added(_)));
+        assert!(tree.fork_diff(&node_e, &node_e).unwrap().is_empty());
+
+        // a different genesis invalidates the snapshots
+        tree.set_genesis(Ledger::from_accounts([
+            Account::new(a.clone(), 10, 0),
+            Account::new(b.clone(), 0, 0),
+        ]));
+        assert!(tree.snapshots.is_empty());
+        assert_eq!(tree.balance_at(&node_b, &a).unwrap(), Some(5));
+        assert_eq!(tree.balance_at(&node_d, &a).unwrap(), Some(4));
+        tree.set_genesis(Ledger::new());
+        assert!(matches!(
+            tree.ledger_at(&node_b),
+            Err(TreeError::LedgerError(_))
+        ));
+        assert!(matches!(
+            tree.balance_at(&node_b, &a),
+            Err(TreeError::LedgerError(_))
+        ));
+    }
+
+    #[test]
+    fn switch_ledger_between_forks() {
+        use crate::ledger::{account::Account, *};
+
+        // Tree
+        //            (a, A -> B 10)
+        //             /          \
+        // (b, A -> B 80)      (c, create C)
+        //         |                |
+        // (d, B -> A 5)     (e, A -> C 50)
+        //                          |
+        //                   (f, A -> C 50)
+
+        let (a, b, c) = ("A".to_string(), "B".to_string(), "C".to_string());
+        let genesis = Ledger::from_accounts([
+            Account::new(a.clone(), 100, 0),
+            Account::new(b.clone(), 0, 0),
+        ]);
+        let transfer = |from: &str, to: &str, amount| {
+            LedgerDiff::from(&[Diff::Transfer(from.into(), to.into(), amount)])
+        };
+        let tree = &mut WeightedTree::new();
+        let root = tree.insert(Block::new("a", 1, transfer(&a, &b, 10)), None);
+        assert!(matches!(
+            tree.switch_ledger_to(&root),
+            Err(TreeError::LedgerNotTracked)
+        ));
+        let node_b = tree.insert(Block::new("b", 1, transfer(&a, &b, 80)), Some(&root));
+        let node_c = tree.insert(
+            Block::new("c", 1, LedgerDiff::from(&[Diff::Creation(c.clone())])),
+            Some(&root),
+        );
+        let node_d = tree.insert(Block::new("d", 1, transfer(&b, &a, 5)), Some(&node_b));
+        let node_e = tree.insert(Block::new("e", 1, transfer(&a, &c, 50)), Some(&node_c));
+        let node_f = tree.insert(Block::new("f", 1, transfer(&a, &c, 50)), Some(&node_e));
+
+        tree.track_ledger(genesis.clone());
+        tree.set_genesis(genesis.clone());
+        for node in [&node_d, &node_e, &root, &node_b, &node_e, &node_d] {
+            let expected = tree.ledger_at(node).unwrap();
+            assert_eq!(tree.switch_ledger_to(node).unwrap(), &expected);
+            assert_eq!(tree.tracked_ledger().unwrap().1, Some(node));
+        }
+
+        // only to blocks of the tracked branch
+        assert!(matches!(
+            tree.rollback_to(&node_c),
+            Err(TreeError::NotAnAncestor(_))
+        ));
+        let at_root = tree.ledger_at(&root).unwrap();
+        assert_eq!(tree.rollback_to(&root).unwrap(), &at_root);
+
+        // A can't afford f, the ledger stays at d
+        let at_d = tree.switch_ledger_to(&node_d).unwrap().clone();
+        assert!(matches!(
+            tree.switch_ledger_to(&node_f),
+            Err(TreeError::LedgerError(_))
+        ));
+        assert_eq!(tree.tracked_ledger().unwrap(), (&at_d, Some(&node_d)));
+
+        // a new root moves it back to genesis
+        tree.insert(Block::new("g", 1, LedgerDiff::new()), None);
+        assert_eq!(tree.tracked_ledger().unwrap(), (&genesis, None));
+    }
+
     #[test]
     pub fn insert_weighted_block() {
         use crate::ledger::*;
@@ -340,7 +989,7 @@ mod tests {
         let mut leaves = HashMap::new();
         for id in tree
             .tree
-            .traverse_level_order_ids(&tree.tree.root_node_id().unwrap())
+            .traverse_level_order_ids(tree.tree.root_node_id().unwrap())
             .unwrap()
         {
             if tree.tree.children(&id).unwrap().next().is_none() {
@@ -356,4 +1005,238 @@ mod tests {
         );
         // panic!(); // uncomment to see stdout
     }
+
+    #[test]
+    fn fork_choice() {
+        use crate::ledger::LedgerDiff;
+
+        // Tree, (pk, weight)
+        //                 (R, 1)
+        //        /          |         \
+        //    (X, 1)      (Y, 8)     (Z, 0)
+        //    /    \        |          |
+        // (P, 5) (Q, 5)  (Y, 8)     (Z, 0)
+        //                              |
+        //                           (Z, 0)
+
+        let mut tree = WeightedTree::new();
+        let mut insert = |pk: &str, weight, parent: Option<&NodeId>| {
+            tree.insert(Block::new(pk, weight, LedgerDiff::new()), parent)
+        };
+        let root = insert("R", 1, None);
+        let x = insert("X", 1, Some(&root));
+        let y = insert("Y", 8, Some(&root));
+        let z = insert("Z", 0, Some(&root));
+        let p = insert("P", 5, Some(&x));
+        insert("Q", 5, Some(&x));
+        let y1 = insert("Y", 8, Some(&y));
+        let z1 = insert("Z", 0, Some(&z));
+        let z2 = insert("Z", 0, Some(&z1));
+
+        // support(X) = 11 > support(Y) = 8, P and Q tie
+        assert_eq!(
+            tree.best_tip(ForkChoiceRule::HeaviestSubtree).unwrap(),
+            Some(p.clone())
+        );
+        assert_eq!(
+            tree.best_tip(ForkChoiceRule::HeaviestBranch).unwrap(),
+            Some(y1)
+        );
+        assert_eq!(
+            tree.best_tip(ForkChoiceRule::LongestChain).unwrap(),
+            Some(z2.clone())
+        );
+
+        assert_eq!(
+            tree.update_head(ForkChoiceRule::LongestChain).unwrap(),
+            Some(ForkChoiceUpdate {
+                old_tip: None,
+                new_tip: z2.clone(),
+                common_ancestor: None,
+                reorg_depth: 0,
+            })
+        );
+        assert_eq!(
+            tree.update_head(ForkChoiceRule::HeaviestSubtree).unwrap(),
+            Some(ForkChoiceUpdate {
+                old_tip: Some(z2),
+                new_tip: p.clone(),
+                common_ancestor: Some(root),
+                reorg_depth: 3,
+            })
+        );
+        assert_eq!(
+            tree.update_head(ForkChoiceRule::HeaviestSubtree).unwrap(),
+            None
+        );
+
+        // extending the head isn't a reorg
+        let p1 = tree.insert(Block::new("P", 1, LedgerDiff::new()), Some(&p));
+        assert_eq!(
+            tree.update_head(ForkChoiceRule::HeaviestSubtree).unwrap(),
+            Some(ForkChoiceUpdate {
+                old_tip: Some(p.clone()),
+                new_tip: p1.clone(),
+                common_ancestor: Some(p),
+                reorg_depth: 0,
+            })
+        );
+        assert_eq!(tree.head(), Some(&p1));
+    }
+
+    #[test]
+    fn fork_choice_ties() {
+        use crate::ledger::LedgerDiff;
+
+        // Tree, (pk, hash)
+        //        (R, r)
+        //       /      \
+        //   (X, x)    (X, w)
+        //     |         |
+        //   (A, b)    (A, a)
+        //
+        // the right leaf is inserted first, the left one is first in pre-order
+        let block = |pk: &str, hash: &str| Block::new(pk, 1, LedgerDiff::new()).with_hash(hash);
+        let build = |left_first: bool| {
+            let mut tree = WeightedTree::new();
+            let root = tree.insert(block("R", "r"), None);
+            let (left, right) = if left_first {
+                let left = tree.insert(block("X", "x"), Some(&root));
+                (left, tree.insert(block("X", "w"), Some(&root)))
+            } else {
+                let right = tree.insert(block("X", "w"), Some(&root));
+                (tree.insert(block("X", "x"), Some(&root)), right)
+            };
+            let right_leaf = tree.insert(block("A", "a"), Some(&right));
+            tree.insert(block("A", "b"), Some(&left));
+            (tree, right_leaf)
+        };
+
+        for left_first in [true, false] {
+            let (tree, right_leaf) = build(left_first);
+            for rule in [
+                ForkChoiceRule::LongestChain,
+                ForkChoiceRule::HeaviestSubtree,
+                ForkChoiceRule::HeaviestBranch,
+            ] {
+                assert_eq!(
+                    tree.best_tip(rule).unwrap().as_ref(),
+                    Some(&right_leaf),
+                    "{rule:?}"
+                );
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod weights {
+        use super::*;
+        use crate::ledger::LedgerDiff;
+        use proptest::prelude::*;
+
+        /// blocks, each under a random earlier block, or a new root if `None`
+        fn blocks() -> impl Strategy<Value = Vec<(Option<usize>, &'static str, u32)>> {
+            prop::collection::vec(
+                (
+                    prop::option::weighted(0.9, any::<usize>()),
+                    prop::sample::select(vec!["A", "B", "C", "D"]),
+                    0u32..100,
+                ),
+                1..40,
+            )
+        }
+
+        fn build(blocks: &[(Option<usize>, &str, u32)]) -> (WeightedTree, Vec<NodeId>) {
+            let mut tree = WeightedTree::new();
+            let mut ids: Vec<NodeId> = vec![];
+            for (parent, pk, weight) in blocks {
+                let parent = parent
+                    .filter(|_| !ids.is_empty())
+                    .map(|n| ids[n % ids.len()].clone());
+                let block = Block::new(pk, *weight, LedgerDiff::new());
+                ids.push(tree.insert(block, parent.as_ref()));
+            }
+            (tree, ids)
+        }
+
+        proptest! {
+            #[test]
+            fn root_support_is_tree_weight(blocks in blocks()) {
+                let (tree, ids) = build(&blocks);
+                let root = tree.tree.root_node_id().unwrap();
+                prop_assert_eq!(tree.support(root).unwrap(), tree.weight);
+                for id in &ids {
+                    prop_assert_eq!(tree.support(id).unwrap(), tree.subtree_support(id).unwrap());
+                }
+                prop_assert_eq!(tree.pk_weights.values().sum::<u32>(), tree.weight);
+                prop_assert_eq!(tree.weights.len(), ids.len());
+                prop_assert_eq!(tree.pk_blocks.values().map(HashSet::len).sum::<usize>(), ids.len());
+                for (pk, max) in &tree.pk_weights {
+                    let weights = tree.pk_blocks[pk].iter().map(|id| tree.weights[id]);
+                    prop_assert_eq!(weights.max(), Some(*max));
+                }
+            }
+
+            #[test]
+            fn owned_merge_matches_copy(
+                base in blocks(),
+                incoming in blocks(),
+                junction in any::<usize>(),
+            ) {
+                let (mut copied, base_ids) = build(&base);
+                let (mut owned, owned_base_ids) = build(&base);
+                let (incoming, incoming_ids) = build(&incoming);
+                let junction = junction % base_ids.len();
+                let copied_ids = copied.merge(&incoming, &base_ids[junction]);
+                let owned_ids = owned.merge_owned(incoming, &owned_base_ids[junction]);
+
+                prop_assert_eq!(owned.weight, copied.weight);
+                prop_assert_eq!(&owned.pk_weights, &copied.pk_weights);
+                prop_assert_eq!(owned.weights.len(), base_ids.len() + incoming_ids.len());
+                for (owned_id, copied_id) in owned_base_ids.iter().zip(&base_ids) {
+                    prop_assert_eq!(owned.support(owned_id).unwrap(), copied.support(copied_id).unwrap());
+                }
+                for id in &incoming_ids {
+                    let (owned_id, copied_id) = (&owned_ids[id], &copied_ids[id]);
+                    prop_assert_eq!(owned.support(owned_id).unwrap(), copied.support(copied_id).unwrap());
+                    prop_assert_eq!(owned.weights[owned_id], copied.weights[copied_id]);
+                    prop_assert_eq!(
+                        owned.tree.get(owned_id).unwrap().data(),
+                        copied.tree.get(copied_id).unwrap().data()
+                    );
+                }
+                for (pk, ids) in &owned.pk_blocks {
+                    prop_assert_eq!(ids.len(), copied.pk_blocks[pk].len());
+                }
+            }
+
+            #[test]
+            fn support_is_monotone_up_the_tree(blocks in blocks()) {
+                let (tree, ids) = build(&blocks);
+                for id in &ids {
+                    if let Some(parent) = tree.tree.get(id).unwrap().parent() {
+                        prop_assert!(tree.support(parent).unwrap() >= tree.support(id).unwrap());
+                    }
+                }
+            }
+
+            #[test]
+            fn heaviest_child_has_max_support(blocks in blocks()) {
+                let (tree, ids) = build(&blocks);
+                for id in &ids {
+                    let supports: Vec<u32> = tree
+                        .tree
+                        .children_ids(id)
+                        .unwrap()
+                        .map(|child| tree.support(child).unwrap())
+                        .collect();
+                    let heaviest = tree.heaviest_child(id).unwrap();
+                    prop_assert_eq!(
+                        heaviest.map(|child| tree.support(&child).unwrap()),
+                        supports.into_iter().max()
+                    );
+                }
+            }
+        }
+    }
 }